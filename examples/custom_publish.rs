@@ -23,7 +23,7 @@ fn main() {
                         InputKind::Counter,
                         name.append("customized_add_prefix")
                             .append(format!("{}_and_a_suffix", last)),
-                        count,
+                        count as MetricValue,
                     ))
                 } else {
                     None
@@ -31,10 +31,10 @@ fn main() {
             },
 
             // scaling the score value and appending unit to name
-            (kind, ScoreType::Sum(sum)) => Some((kind, name.append("per_thousand"), sum / 1000)),
+            (kind, ScoreType::Sum(sum)) => Some((kind, name.append("per_thousand"), sum / 1000.0)),
 
             // using the unmodified metric name
-            (kind, ScoreType::Mean(avg)) => Some((kind, name, avg.round() as MetricValue)),
+            (kind, ScoreType::Mean(avg)) => Some((kind, name, avg.round())),
 
             // do not export min and max
             _ => None,
@@ -0,0 +1,24 @@
+//! A sample application sending ad-hoc labeled metrics to an InfluxDB server.
+
+extern crate dipstick;
+
+use dipstick::*;
+use std::time::Duration;
+
+fn main() {
+    AppLabel::set("host", "my_host");
+
+    let metrics =
+        Influx::write_http_to("localhost:8086", "my_db")
+            .expect("InfluxDB Address")
+            .buffered(Buffering::Unlimited)
+            .add_prefix("my_app")
+            .input();
+
+    loop {
+        metrics.counter("counter_a").count_with(123, labels!{ "route" => "/home" });
+        metrics.timer("timer_a").interval_us(2000000);
+        metrics.flush().expect("Sent to InfluxDB");
+        std::thread::sleep(Duration::from_millis(40));
+    }
+}
@@ -20,8 +20,8 @@ fn main() {
         .add_prefix("out_both").input();
 
     loop {
-        different_type_metrics.new_metric("counter_a".into(), InputKind::Counter).write(123, labels![]);
-        same_type_metrics.new_metric("timer_a".into(), InputKind::Timer).write(6677, labels![]);
+        different_type_metrics.new_metric("counter_a".into(), InputKind::Counter).write(123.0, labels![]);
+        same_type_metrics.new_metric("timer_a".into(), InputKind::Timer).write(6677.0, labels![]);
         std::thread::sleep(Duration::from_millis(400));
     }
 }
@@ -7,8 +7,8 @@ use std::time::Duration;
 
 fn main() {
     let metrics =
-        Prometheus::send_json_to("localhost:2003")
-            .expect("Prometheus Socket")
+        Prometheus::push_to("localhost:9091", "my_app")
+            .expect("Prometheus Pushgateway")
             .add_prefix("my_app")
             .input();
 
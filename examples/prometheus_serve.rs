@@ -0,0 +1,20 @@
+//! A sample application exposing aggregated metrics to Prometheus scrapers.
+
+extern crate dipstick;
+
+use dipstick::*;
+use std::time::Duration;
+
+fn main() {
+    let metrics = AtomicBucket::new().add_prefix("my_app");
+
+    let server = PrometheusServer::serve_bucket("0.0.0.0:9102", metrics.clone())
+        .expect("Prometheus Server");
+    println!("Serving metrics on http://{}/metrics", server.server_addr());
+
+    loop {
+        metrics.counter("counter_a").count(123);
+        metrics.timer("timer_a").interval_us(2000000);
+        std::thread::sleep(Duration::from_millis(40));
+    }
+}
@@ -19,5 +19,5 @@ pub fn raw_write() {
         "count_a".into(),
         dipstick::InputKind::Counter,
     );
-    counter.write(1, labels![]);
+    counter.write(1.0, labels![]);
 }
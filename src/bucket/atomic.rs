@@ -1,23 +1,29 @@
 //! Maintain aggregated metrics for deferred reporting,
 
-use core::attributes::{Attributes, WithAttributes, Prefixed};
+use core::attributes::{Attributes, WithAttributes, Prefixed, Sampled};
 use core::name::{MetricName};
+use core::metadata::Unit;
 use core::input::{InputKind, InputScope, InputMetric};
 use core::output::{OutputDyn, OutputScope, OutputMetric, Output, output_none};
-use core::clock::TimeHandle;
+use core::clock::{TimeHandle, Clock, SystemClock};
 use core::{MetricValue, Flush};
+use core::label::Labels;
+use core::metrics;
+use core::pcg32;
+use core::sampler::Sampler;
+use core::conflict::{OnKindConflict, KindConflict, KindConflicts, Definition, suffix_kind};
 use bucket::{ScoreType, stats_summary};
 use bucket::ScoreType::*;
+use bucket::histogram::AtomicHistogram;
 use core::error;
 
-use std::mem;
-use std::isize;
-use std::collections::BTreeMap;
-use std::sync::atomic::AtomicIsize;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicIsize, AtomicU64};
 use std::sync::atomic::Ordering::*;
 use std::sync::{Arc, RwLock};
 use std::fmt;
 use std::borrow::Borrow;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 /// A function type to transform aggregated scores into publishable statistics.
 pub type StatsFn = Fn(InputKind, MetricName, ScoreType) -> Option<(InputKind, MetricName, MetricValue)> + Send + Sync + 'static;
@@ -30,6 +36,9 @@ fn initial_output() -> Arc<OutputDyn + Send + Sync> {
     Arc::new(output_none())
 }
 
+/// Default maximum number of label sets aggregated separately for each metric.
+const DEFAULT_MAX_LABEL_SETS: usize = 1000;
+
 lazy_static! {
     static ref DEFAULT_AGGREGATE_STATS: RwLock<Arc<StatsFn>> = RwLock::new(Arc::new(initial_stats()));
 
@@ -42,11 +51,17 @@ lazy_static! {
 pub struct AtomicBucket {
     attributes: Attributes,
     inner: Arc<RwLock<InnerAtomicBucket>>,
+    percentiles: Option<Arc<[f64]>>,
+    label_keys: Arc<[String]>,
+    max_label_sets: usize,
 }
 
 struct InnerAtomicBucket {
-    metrics: BTreeMap<MetricName, Arc<AtomicScores>>,
+    // metrics of different kinds may share a name, depending on the kind conflict policy
+    metrics: BTreeMap<(MetricName, InputKind), Arc<AtomicSeries>>,
+    kind_conflicts: KindConflicts,
     period_start: TimeHandle,
+    clock: Arc<dyn Clock>,
     stats: Option<Arc<Fn(InputKind, MetricName, ScoreType)
         -> Option<(InputKind, MetricName, MetricValue)> + Send + Sync + 'static>>,
     output: Option<Arc<OutputDyn + Send + Sync + 'static>>,
@@ -61,11 +76,27 @@ impl fmt::Debug for InnerAtomicBucket {
 }
 
 lazy_static! {
-    static ref PERIOD_LENGTH: MetricName = "_period_length".into();
+    static ref PERIOD_LENGTH: MetricName = MetricName::from("_period_length").unit(Unit::Milliseconds);
+}
+
+/// The end of the current period, truncated to the second.
+/// All stats of a flush are written with this same timestamp.
+fn period_timestamp() -> SystemTime {
+    let now = SystemTime::now();
+    match now.duration_since(UNIX_EPOCH) {
+        Ok(since_epoch) => UNIX_EPOCH + Duration::from_secs(since_epoch.as_secs()),
+        Err(_) => now,
+    }
 }
 
 impl InnerAtomicBucket {
 
+    fn defined_kinds(&self, name: &MetricName) -> Vec<InputKind> {
+        self.metrics.range((name.clone(), InputKind::Marker)..=(name.clone(), InputKind::Timer))
+            .map(|(&(_, kind), _)| kind)
+            .collect()
+    }
+
     pub fn flush(&mut self) -> error::Result<()> {
         let stats_fn = match self.stats {
             Some(ref stats_fn) => stats_fn.clone(),
@@ -84,7 +115,7 @@ impl InnerAtomicBucket {
         // TODO parameterize whether to keep ad-hoc metrics after publish
         let mut purged = self.metrics.clone();
         self.metrics.iter()
-            .filter(|&(_k, v)| Arc::strong_count(v) == 1)
+            .filter(|&(_k, v)| Arc::strong_count(v) == 1 && Arc::strong_count(&v.unlabeled) == 1)
             .map(|(k, _v)| k)
             .for_each(|k| {purged.remove(k);});
         self.metrics = purged;
@@ -97,17 +128,14 @@ impl InnerAtomicBucket {
     /// Write stats to assigned or default output.
     pub fn flush_to(&mut self, target: &OutputScope, stats: &StatsFn) -> error::Result<()> {
 
-        let now = TimeHandle::now();
-        let duration_seconds = self.period_start.elapsed_us() as f64 / 1_000_000.0;
+        let now = TimeHandle::now_from(self.clock.as_ref());
+        let duration_seconds = self.period_start.elapsed_us_from(self.clock.as_ref()) / 1_000_000.0;
         self.period_start = now;
 
-        let mut snapshot: Vec<(&MetricName, InputKind, Vec<ScoreType>)> = self.metrics.iter()
-            .flat_map(|(name, scores)| if let Some(values) = scores.reset(duration_seconds) {
-                Some((name, scores.metric_kind(), values))
-            } else {
-                None
-            })
-            .collect();
+        let mut snapshot: Vec<Snapshot> = vec![];
+        for (&(ref name, _kind), series) in &self.metrics {
+            series.reset(name, duration_seconds, &mut snapshot);
+        }
 
         if snapshot.is_empty() {
             // no data was collected for this period
@@ -117,15 +145,16 @@ impl InnerAtomicBucket {
         } else {
             // TODO add switch for metadata such as PERIOD_LENGTH
             if self.publish_metadata {
-                snapshot.push((&PERIOD_LENGTH, InputKind::Timer, vec![Sum((duration_seconds * 1000.0) as isize)]));
+                snapshot.push((&PERIOD_LENGTH, labels![], InputKind::Timer, vec![Sum(duration_seconds * 1000.0)]));
             }
-            for metric in snapshot {
-                for score in metric.2 {
-                    let filtered = stats(metric.1, metric.0.clone(), score);
-                    if let Some((kind, name, value)) = filtered {
-                        let metric: OutputMetric = target.new_metric(name, kind);
-                        // TODO provide some bucket context through labels?
-                        metric.write(value, labels![])
+            let timestamp = Some(period_timestamp());
+            for (name, labels, kind, scores) in snapshot {
+                for score in scores {
+                    let filtered = stats(kind, name.clone(), score);
+                    if let Some((stat_kind, stat_name, value)) = filtered {
+                        let stat_name = inherit_metadata(stat_name, name, kind, score);
+                        let metric: OutputMetric = target.new_metric(stat_name, stat_kind);
+                        metric.write_timed(value, labels.clone(), timestamp)
                     }
                 }
             }
@@ -135,6 +164,20 @@ impl InnerAtomicBucket {
 
 }
 
+/// Give a stat named by the stats function the description of its metric,
+/// and its unit if the stat is in the unit of the metric's values.
+fn inherit_metadata(stat_name: MetricName, name: &MetricName, kind: InputKind, score: ScoreType) -> MetricName {
+    if !stat_name.metadata().is_empty() {
+        return stat_name
+    }
+    let mut metadata = name.metadata().clone();
+    match score {
+        Sum(_) | Max(_) | Min(_) | Mean(_) | Percentile(..) => metadata.unit = metadata.effective_unit(kind),
+        Count(_) | Rate(_) => metadata.unit = None,
+    }
+    stat_name.with_metadata(metadata)
+}
+
 impl<S: AsRef<str>> From<S> for AtomicBucket {
     fn from(name: S) -> AtomicBucket {
         AtomicBucket::new().add_prefix(name.as_ref())
@@ -148,15 +191,54 @@ impl AtomicBucket {
             attributes: Attributes::default(),
             inner: Arc::new(RwLock::new(InnerAtomicBucket {
                 metrics: BTreeMap::new(),
+                kind_conflicts: KindConflicts::default(),
                 period_start: TimeHandle::now(),
+                clock: Arc::new(SystemClock),
                 stats: None,
                 output: None,
                 // TODO add API toggle for metadata publish
                 publish_metadata: false,
-            }))
+            })),
+            percentiles: None,
+            label_keys: Arc::new([]),
+            max_label_sets: DEFAULT_MAX_LABEL_SETS,
         }
     }
 
+    /// Return a clone of this bucket that also tracks the specified percentiles
+    /// for the Counter, Gauge and Timer metrics defined through it.
+    /// Percentiles are expressed in percent, e.g. `&[50.0, 99.0, 99.9]`,
+    /// and are published as `ScoreType::Percentile` scores.
+    /// Metrics already defined in the bucket under the same name keep their current scores.
+    pub fn percentiles(&self, percentiles: &[f64]) -> Self {
+        for p in percentiles {
+            assert!(*p > 0.0 && *p <= 100.0, "Percentile {} out of range", p);
+        }
+        let mut cloned = self.clone();
+        cloned.percentiles = Some(percentiles.into());
+        cloned
+    }
+
+    /// Return a clone of this bucket that aggregates values separately for each distinct set
+    /// of values of the specified label keys, for the metrics defined through it.
+    /// Label values are looked up in each written value's labels, then in `ThreadLabel` and `AppLabel`.
+    /// Other labels are ignored. Aggregated stats are published with their set of labels.
+    /// Metrics already defined in the bucket under the same name keep their current label keys.
+    pub fn aggregate_labels<S: AsRef<str>>(&self, keys: &[S]) -> Self {
+        let mut cloned = self.clone();
+        cloned.label_keys = keys.iter().map(|key| key.as_ref().to_string()).collect::<Vec<_>>().into();
+        cloned
+    }
+
+    /// Return a clone of this bucket that aggregates at most this many label sets per metric.
+    /// Values written with any additional label set are aggregated without labels.
+    /// Label sets without values are dropped at the end of every period.
+    pub fn max_label_sets(&self, max_label_sets: usize) -> Self {
+        let mut cloned = self.clone();
+        cloned.max_label_sets = max_label_sets;
+        cloned
+    }
+
     /// Set the default aggregated metrics statistics generator.
     pub fn set_default_stats<F>(func: F)
         where
@@ -203,6 +285,29 @@ impl AtomicBucket {
         self.inner.write().expect("Aggregator").output = None
     }
 
+    /// Measure the length of aggregation periods with this clock instead of the system's.
+    /// The current period restarts from the clock's current time.
+    pub fn set_clock<C: Clock + 'static>(&self, clock: C) {
+        let mut inner = self.inner.write().expect("Aggregator");
+        inner.period_start = TimeHandle::now_from(&clock);
+        inner.clock = Arc::new(clock);
+    }
+
+    /// Revert to measuring the length of aggregation periods with the system clock.
+    pub fn unset_clock(&self) {
+        self.set_clock(SystemClock)
+    }
+
+    /// Set the policy applied when a metric is defined again with a different kind.
+    pub fn set_kind_conflict(&self, policy: OnKindConflict) {
+        self.inner.write().expect("Aggregator").kind_conflicts.set_policy(policy)
+    }
+
+    /// The distinct kind conflicts met by this bucket.
+    pub fn kind_conflicts(&self) -> Vec<KindConflict> {
+        self.inner.read().expect("Aggregator").kind_conflicts.list()
+    }
+
     /// Immediately flush the bucket's metrics to the specified scope and stats.
     pub fn flush_now_to(&self, publish_scope: &OutputScope, stats_fn: &StatsFn) -> error::Result<()> {
         let mut inner = self.inner.write().expect("Aggregator");
@@ -214,14 +319,53 @@ impl AtomicBucket {
 impl InputScope for AtomicBucket {
     /// Lookup or create scores for the requested metric.
     fn new_metric(&self, name: MetricName, kind: InputKind) -> InputMetric {
-        let scores = self.inner
-            .write()
-            .expect("Aggregator")
+        let mut name = self.prefix_append(name);
+        let mut inner = self.inner.write().expect("Aggregator");
+        let existing = inner.defined_kinds(&name);
+        let mut definition = inner.kind_conflicts.check(&name, kind, &existing);
+        if definition == Definition::Renamed {
+            suffix_kind(&mut name, kind);
+            let existing = inner.defined_kinds(&name);
+            definition = inner.kind_conflicts.check_renamed(&name, kind, &existing);
+        }
+        match definition {
+            Definition::Named | Definition::Separated | Definition::Renamed => {},
+            Definition::Rejected => {
+                drop(inner);
+                metrics::BUCKET_KIND_CONFLICT.mark();
+                return InputMetric::new(|_value, _labels| {})
+            }
+        }
+        let series = inner
             .metrics
-            .entry(self.prefix_append(name))
-            .or_insert_with(|| Arc::new(AtomicScores::new(kind)))
+            .entry((name, kind))
+            .or_insert_with(|| Arc::new(AtomicSeries {
+                unlabeled: Arc::new(AtomicScores::with(kind, self.percentiles.clone())),
+                percentiles: self.percentiles.clone(),
+                label_keys: self.label_keys.clone(),
+                max_label_sets: self.max_label_sets,
+                labeled: RwLock::new(BTreeMap::new()),
+            }))
             .clone();
-        InputMetric::new(move |value, _labels| scores.update(value))
+        drop(inner);
+        if definition != Definition::Named {
+            metrics::BUCKET_KIND_CONFLICT.mark();
+        }
+        match (self.get_sampling(), series.label_keys.is_empty()) {
+            (None, true) => {
+                let scores = series.unlabeled.clone();
+                InputMetric::new_sampled(move |value, _labels, rate| scores.update_sampled(value, rate))
+            }
+            (None, false) => InputMetric::new_sampled(move |value, labels, rate| series.update(value, rate, &labels)),
+            (Some(sampling), _) => {
+                let sampler = Sampler::new(&sampling);
+                InputMetric::new_sampled(move |value, labels, rate| {
+                    if let Some(sampled) = sampler.sample(&labels) {
+                        series.update(value, rate * sampled, &labels)
+                    }
+                })
+            }
+        }
     }
 }
 
@@ -239,22 +383,113 @@ impl WithAttributes for AtomicBucket {
     fn mut_attributes(&mut self) -> &mut Attributes { &mut self.attributes }
 }
 
+/// Sampled values are scaled by the inverse of their rate,
+/// so that published counts, sums and rates estimate those of all values.
+impl Sampled for AtomicBucket {}
+
+/// Aggregated scores of a metric, with label names and values, ready for publication.
+type Snapshot<'a> = (&'a MetricName, Labels, InputKind, Vec<ScoreType>);
+
+/// The values of the aggregated label keys, in order. Missing labels have no value.
+type LabelSet = Vec<Option<Arc<String>>>;
+
+/// Aggregated scores of a metric, split by the values of its aggregated label keys, if any.
+#[derive(Debug)]
+struct AtomicSeries {
+    /// Scores of values written without any of the aggregated labels.
+    unlabeled: Arc<AtomicScores>,
+    percentiles: Option<Arc<[f64]>>,
+    label_keys: Arc<[String]>,
+    max_label_sets: usize,
+    labeled: RwLock<BTreeMap<LabelSet, AtomicScores>>,
+}
+
+impl AtomicSeries {
+    /// Update the scores of the label set of the value, sampled at the specified rate.
+    fn update(&self, value: MetricValue, rate: f64, labels: &Labels) {
+        let label_set: LabelSet = self.label_keys.iter().map(|key| labels.lookup(key)).collect();
+        if label_set.iter().all(Option::is_none) {
+            return self.unlabeled.update_sampled(value, rate)
+        }
+        if let Some(scores) = self.labeled.read().expect("Labeled Scores").get(&label_set) {
+            return scores.update_sampled(value, rate)
+        }
+        let mut labeled = self.labeled.write().expect("Labeled Scores");
+        if !labeled.contains_key(&label_set) && labeled.len() >= self.max_label_sets {
+            metrics::BUCKET_LABEL_OVERFLOW.mark();
+            return self.unlabeled.update_sampled(value, rate)
+        }
+        let kind = self.unlabeled.metric_kind();
+        let percentiles = self.percentiles.clone();
+        labeled.entry(label_set).or_insert_with(|| AtomicScores::with(kind, percentiles)).update_sampled(value, rate)
+    }
+
+    /// Reset the scores of every label set, adding those with values to the snapshot.
+    /// Label sets without values are dropped.
+    fn reset<'a>(&self, name: &'a MetricName, duration_seconds: f64, snapshot: &mut Vec<Snapshot<'a>>) {
+        let kind = self.unlabeled.metric_kind();
+        if let Some(scores) = self.unlabeled.reset(duration_seconds) {
+            snapshot.push((name, labels![], kind, scores))
+        }
+        let mut labeled = self.labeled.write().expect("Labeled Scores");
+        let mut idle = vec![];
+        for (label_set, scores) in labeled.iter() {
+            match scores.reset(duration_seconds) {
+                Some(scores) => {
+                    let labels: HashMap<String, Arc<String>> = self.label_keys.iter().zip(label_set)
+                        .filter_map(|(key, value)| value.clone().map(|value| (key.clone(), value)))
+                        .collect();
+                    snapshot.push((name, labels.into(), kind, scores))
+                }
+                None => idle.push(label_set.clone()),
+            }
+        }
+        for label_set in idle {
+            labeled.remove(&label_set);
+        }
+    }
+}
+
 /// A metric that holds aggregated values.
 /// Some fields are kept public to ease publishing.
 #[derive(Debug)]
 struct AtomicScores {
     /// The kind of metric
     kind: InputKind,
-    /// The actual recorded metric scores
-    scores: [AtomicIsize; 4],
+    /// The number of recorded values
+    count: AtomicIsize,
+    /// The sum, max and min of recorded values, as f64 bits
+    scores: [AtomicU64; 3],
+    /// The distribution of values, if percentiles are tracked
+    histogram: Option<AtomicHistogram>,
 }
 
 impl AtomicScores {
     /// Create new scores to track summary values of a metric
     pub fn new(kind: InputKind) -> Self {
+        let [sum, max, min] = AtomicScores::blank();
         AtomicScores {
             kind,
-            scores: unsafe { mem::transmute(AtomicScores::blank()) },
+            count: AtomicIsize::new(0),
+            scores: [AtomicU64::new(sum.to_bits()), AtomicU64::new(max.to_bits()), AtomicU64::new(min.to_bits())],
+            histogram: None,
+        }
+    }
+
+    /// Create new scores to track summary values and percentiles of a metric
+    pub fn with_percentiles(kind: InputKind, percentiles: Arc<[f64]>) -> Self {
+        let mut scores = AtomicScores::new(kind);
+        if kind != InputKind::Marker {
+            scores.histogram = Some(AtomicHistogram::new(percentiles));
+        }
+        scores
+    }
+
+    /// Create new scores, tracking percentiles if any are specified
+    fn with(kind: InputKind, percentiles: Option<Arc<[f64]>>) -> Self {
+        match percentiles {
+            Some(percentiles) => AtomicScores::with_percentiles(kind, percentiles),
+            None => AtomicScores::new(kind),
         }
     }
 
@@ -264,77 +499,105 @@ impl AtomicScores {
     }
 
     #[inline]
-    fn blank() -> [isize; 4] {
-        [0, 0, isize::MIN, isize::MAX]
+    fn blank() -> [MetricValue; 3] {
+        [0.0, f64::NEG_INFINITY, f64::INFINITY]
     }
 
     /// Update scores with new value
-    pub fn update(&self, value: MetricValue) -> () {
+    /// NaN values are ignored, as they would spoil every score.
+    pub fn update(&self, value: MetricValue) {
+        self.add(value, 1, value)
+    }
+
+    /// Update scores with a value sampled at the specified rate.
+    /// Count and sum are scaled by the inverse of the rate, min and max are not.
+    pub fn update_sampled(&self, value: MetricValue, rate: f64) {
+        if rate >= 1.0 || rate <= 0.0 {
+            return self.update(value)
+        }
+        self.add(value, scale_count(rate), value / rate)
+    }
+
+    /// Add a value to the scores, standing for `count` values summing to `sum`.
+    fn add(&self, value: MetricValue, count: isize, sum: MetricValue) {
+        if value.is_nan() {
+            return
+        }
         // TODO report any concurrent updates / resets for measurement of contention
-        let value = value;
-        self.scores[0].fetch_add(1, AcqRel);
+        self.count.fetch_add(count, AcqRel);
         match self.kind {
             InputKind::Marker => {}
             _ => {
                 // optimization - these fields are unused for Marker stats
-                self.scores[1].fetch_add(value, AcqRel);
-                swap_if(&self.scores[2], value, |new, current| new > current);
-                swap_if(&self.scores[3], value, |new, current| new < current);
+                update_value(&self.scores[0], |current| Some(current + sum));
+                update_value(&self.scores[1], |current| if value > current { Some(value) } else { None });
+                update_value(&self.scores[2], |current| if value < current { Some(value) } else { None });
+                if let Some(ref histogram) = self.histogram {
+                    histogram.update(value)
+                }
             }
         }
     }
 
-    /// Reset scores to zero, return previous values
-    fn snapshot(&self, scores: &mut [isize; 4]) -> bool {
-        // NOTE copy timestamp, count AND sum _before_ testing for data to reduce concurrent discrepancies
-        scores[0] = self.scores[0].swap(0, AcqRel);
-        scores[1] = self.scores[1].swap(0, AcqRel);
+    /// Reset scores to zero, return previous count and values
+    fn snapshot(&self, scores: &mut [MetricValue; 3]) -> isize {
+        // NOTE copy count AND sum _before_ testing for data to reduce concurrent discrepancies
+        let count = self.count.swap(0, AcqRel);
+        let blank = AtomicScores::blank();
+        scores[0] = f64::from_bits(self.scores[0].swap(blank[0].to_bits(), AcqRel));
 
         // if hit count is zero, then no values were recorded.
-        if scores[0] == 0 {
-            return false;
+        if count == 0 {
+            return 0;
         }
 
-        scores[2] = self.scores[2].swap(isize::MIN, AcqRel);
-        scores[3] = self.scores[3].swap(isize::MAX, AcqRel);
-        true
+        scores[1] = f64::from_bits(self.scores[1].swap(blank[1].to_bits(), AcqRel));
+        scores[2] = f64::from_bits(self.scores[2].swap(blank[2].to_bits(), AcqRel));
+        count
     }
 
     /// Map raw scores (if any) to applicable statistics
     pub fn reset(&self, duration_seconds: f64) -> Option<Vec<ScoreType>> {
         let mut scores = AtomicScores::blank();
-        if self.snapshot(&mut scores) {
+        let count = self.snapshot(&mut scores);
+        if count != 0 {
+            let [sum, max, min] = scores;
 
             let mut snapshot = Vec::new();
             match self.kind {
                 InputKind::Marker => {
-                    snapshot.push(Count(scores[0]));
-                    snapshot.push(Rate(scores[0] as f64 / duration_seconds))
+                    snapshot.push(Count(count));
+                    snapshot.push(Rate(count as f64 / duration_seconds))
                 }
                 InputKind::Gauge => {
-                    snapshot.push(Max(scores[2]));
-                    snapshot.push(Min(scores[3]));
-                    snapshot.push(Mean(scores[1] as f64 / scores[0] as f64));
+                    snapshot.push(Max(max));
+                    snapshot.push(Min(min));
+                    snapshot.push(Mean(sum / count as f64));
                 }
                 InputKind::Timer => {
-                    snapshot.push(Count(scores[0]));
-                    snapshot.push(Sum(scores[1]));
+                    snapshot.push(Count(count));
+                    snapshot.push(Sum(sum));
 
-                    snapshot.push(Max(scores[2]));
-                    snapshot.push(Min(scores[3]));
-                    snapshot.push(Mean(scores[1] as f64 / scores[0] as f64));
+                    snapshot.push(Max(max));
+                    snapshot.push(Min(min));
+                    snapshot.push(Mean(sum / count as f64));
                     // timer rate uses the COUNT of timer calls per second (not SUM)
-                    snapshot.push(Rate(scores[0] as f64 / duration_seconds))
+                    snapshot.push(Rate(count as f64 / duration_seconds))
                 }
                 InputKind::Counter => {
-                    snapshot.push(Count(scores[0]));
-                    snapshot.push(Sum(scores[1]));
+                    snapshot.push(Count(count));
+                    snapshot.push(Sum(sum));
 
-                    snapshot.push(Max(scores[2]));
-                    snapshot.push(Min(scores[3]));
-                    snapshot.push(Mean(scores[1] as f64 / scores[0] as f64));
+                    snapshot.push(Max(max));
+                    snapshot.push(Min(min));
+                    snapshot.push(Mean(sum / count as f64));
                     // counter rate uses the SUM of values per second (e.g. to get bytes/s)
-                    snapshot.push(Rate(scores[1] as f64 / duration_seconds))
+                    snapshot.push(Rate(sum / duration_seconds))
+                }
+            }
+            if let Some(ref histogram) = self.histogram {
+                for (percentile, value) in histogram.reset() {
+                    snapshot.push(Percentile(percentile, value))
                 }
             }
             Some(snapshot)
@@ -344,17 +607,32 @@ impl AtomicScores {
     }
 }
 
-/// Spinlock until success or clear loss to concurrent update.
+/// The number of values a value sampled at the specified rate stands for.
+/// The fraction is rounded up or down at random, in proportion, so that the sum of counts is unbiased.
+#[inline]
+fn scale_count(rate: f64) -> isize {
+    let scaled = 1.0 / rate;
+    let floor = scaled.floor();
+    let fraction = scaled - floor;
+    if fraction > 0.0 && pcg32::accept_sample(pcg32::to_int_rate(fraction)) {
+        floor as isize + 1
+    } else {
+        floor as isize
+    }
+}
+
+/// Replace the value stored as f64 bits with the one computed from it, if any.
+/// Spinlock until success or until no replacement is needed.
 #[inline]
-fn swap_if(counter: &AtomicIsize, new_value: isize, compare: fn(isize, isize) -> bool) {
-    let mut current = counter.load(Acquire);
-    while compare(new_value, current) {
-        if counter.compare_and_swap(current, new_value, Release) == new_value {
+fn update_value<F: Fn(MetricValue) -> Option<MetricValue>>(score: &AtomicU64, compute: F) {
+    let mut current = score.load(Acquire);
+    while let Some(new_value) = compute(f64::from_bits(current)) {
+        match score.compare_exchange_weak(current, new_value.to_bits(), AcqRel, Acquire) {
             // update successful
-            break;
+            Ok(_) => break,
+            // race detected, retry
+            Err(actual) => current = actual,
         }
-        // race detected, retry
-        current = counter.load(Acquire);
     }
 }
 
@@ -373,7 +651,13 @@ mod bench {
     #[bench]
     fn update_count(b: &mut test::Bencher) {
         let metric = AtomicScores::new(InputKind::Counter);
-        b.iter(|| test::black_box(metric.update(4)));
+        b.iter(|| test::black_box(metric.update(4.0)));
+    }
+
+    #[bench]
+    fn update_percentiles(b: &mut test::Bencher) {
+        let metric = AtomicScores::with_percentiles(InputKind::Timer, Arc::new([50.0, 99.0]));
+        b.iter(|| test::black_box(metric.update(4.0)));
     }
 
     #[bench]
@@ -387,14 +671,14 @@ mod bench {
     fn aggregate_marker(b: &mut test::Bencher) {
         let sink = AtomicBucket::new();
         let metric = sink.new_metric("event_a".into(), InputKind::Marker);
-        b.iter(|| test::black_box(metric.write(1, labels![])));
+        b.iter(|| test::black_box(metric.write(1.0, labels![])));
     }
 
     #[bench]
     fn aggregate_counter(b: &mut test::Bencher) {
         let sink = AtomicBucket::new();
         let metric = sink.new_metric("count_a".into(), InputKind::Counter);
-        b.iter(|| test::black_box(metric.write(1, labels![])));
+        b.iter(|| test::black_box(metric.write(1.0, labels![])));
     }
 
 }
@@ -404,11 +688,14 @@ mod test {
     use super::*;
     use bucket::{stats_all, stats_average, stats_summary};
 
+    use core::attributes::Sampling;
     use core::clock::{mock_clock_advance, mock_clock_reset};
     use output::map::StatsMap;
 
     use std::time::Duration;
     use std::collections::BTreeMap;
+    use std::rc::Rc;
+    use std::cell::RefCell;
 
     fn make_stats(stats_fn: &StatsFn) -> BTreeMap<String, MetricValue> {
         mock_clock_reset();
@@ -445,43 +732,246 @@ mod test {
     fn external_aggregate_all_stats() {
         let map = make_stats(&stats_all);
 
-        assert_eq!(map["test.counter_a.count"], 2);
-        assert_eq!(map["test.counter_a.sum"], 30);
-        assert_eq!(map["test.counter_a.mean"], 15);
-        assert_eq!(map["test.counter_a.rate"], 10);
+        assert_eq!(map["test.counter_a.count"], 2.0);
+        assert_eq!(map["test.counter_a.sum"], 30.0);
+        assert_eq!(map["test.counter_a.mean"], 15.0);
+        assert_eq!(map["test.counter_a.rate"], 10.0);
+
+        assert_eq!(map["test.timer_a.count"], 2.0);
+        assert_eq!(map["test.timer_a.sum"], 30_000_000.0);
+        assert_eq!(map["test.timer_a.min"], 10_000_000.0);
+        assert_eq!(map["test.timer_a.max"], 20_000_000.0);
+        assert_eq!(map["test.timer_a.mean"], 15_000_000.0);
+        assert_eq!(map["test.timer_a.rate"], 2.0 / 3.0);
 
-        assert_eq!(map["test.timer_a.count"], 2);
-        assert_eq!(map["test.timer_a.sum"], 30_000_000);
-        assert_eq!(map["test.timer_a.min"], 10_000_000);
-        assert_eq!(map["test.timer_a.max"], 20_000_000);
-        assert_eq!(map["test.timer_a.mean"], 15_000_000);
-        assert_eq!(map["test.timer_a.rate"], 1);
+        assert_eq!(map["test.gauge_a.mean"], 15.0);
+        assert_eq!(map["test.gauge_a.min"], 10.0);
+        assert_eq!(map["test.gauge_a.max"], 20.0);
 
-        assert_eq!(map["test.gauge_a.mean"], 15);
-        assert_eq!(map["test.gauge_a.min"], 10);
-        assert_eq!(map["test.gauge_a.max"], 20);
+        assert_eq!(map["test.marker_a.count"], 3.0);
+        assert_eq!(map["test.marker_a.rate"], 1.0);
+    }
+
+    #[test]
+    fn aggregate_fractions() {
+        let bucket = AtomicBucket::new();
+        let counter = bucket.counter("dollars");
+        let gauge = bucket.gauge("ratio");
+        counter.count(0.25);
+        counter.count(1.5);
+        gauge.value(0.1);
+        gauge.value(0.3);
+        gauge.value(f64::NAN);
+
+        let stats = StatsMap::default();
+        bucket.flush_now_to(&stats, &stats_all).unwrap();
+        let map: BTreeMap<String, MetricValue> = stats.into();
+        assert_eq!(map["dollars.sum"], 1.75);
+        assert_eq!(map["dollars.min"], 0.25);
+        assert_eq!(map["ratio.max"], 0.3);
+        assert!((map["ratio.mean"] - 0.2).abs() < 1e-9);
+    }
+
+    #[test]
+    fn scale_sampled_values() {
+        let bucket = AtomicBucket::new().sampled(Sampling::EveryNth(4));
+        let counter = bucket.counter("counter_a");
+        let gauge = bucket.gauge("gauge_a");
+        for i in 1..9 {
+            counter.count(10);
+            gauge.value(i);
+        }
 
-        assert_eq!(map["test.marker_a.count"], 3);
-        assert_eq!(map["test.marker_a.rate"], 1);
+        let stats = StatsMap::default();
+        bucket.flush_now_to(&stats, &stats_all).unwrap();
+        let map: BTreeMap<String, MetricValue> = stats.into();
+        assert_eq!(map["counter_a.count"], 8.0);
+        assert_eq!(map["counter_a.sum"], 80.0);
+        assert_eq!(map["counter_a.mean"], 10.0);
+        assert_eq!(map["gauge_a.min"], 1.0);
+        assert_eq!(map["gauge_a.max"], 5.0);
+        assert_eq!(map["gauge_a.mean"], 3.0);
     }
 
     #[test]
     fn external_aggregate_summary() {
         let map = make_stats(&stats_summary);
 
-        assert_eq!(map["test.counter_a"], 30);
-        assert_eq!(map["test.timer_a"], 30_000_000);
-        assert_eq!(map["test.gauge_a"], 15);
-        assert_eq!(map["test.marker_a"], 3);
+        assert_eq!(map["test.counter_a"], 30.0);
+        assert_eq!(map["test.timer_a"], 30_000_000.0);
+        assert_eq!(map["test.gauge_a"], 15.0);
+        assert_eq!(map["test.marker_a"], 3.0);
     }
 
     #[test]
     fn external_aggregate_average() {
         let map = make_stats(&stats_average);
 
-        assert_eq!(map["test.counter_a"], 15);
-        assert_eq!(map["test.timer_a"], 15_000_000);
-        assert_eq!(map["test.gauge_a"], 15);
-        assert_eq!(map["test.marker_a"], 3);
+        assert_eq!(map["test.counter_a"], 15.0);
+        assert_eq!(map["test.timer_a"], 15_000_000.0);
+        assert_eq!(map["test.gauge_a"], 15.0);
+        assert_eq!(map["test.marker_a"], 3.0);
+    }
+
+    #[test]
+    fn external_aggregate_percentiles() {
+        mock_clock_reset();
+
+        let metrics = AtomicBucket::new().add_prefix("test");
+        let timer = metrics.percentiles(&[50.0, 99.9]).timer("timer_p");
+        let plain = metrics.timer("timer_a");
+
+        for i in 1..=1000 {
+            timer.interval_us(i * 1000);
+            plain.interval_us(i * 1000);
+        }
+
+        mock_clock_advance(Duration::from_secs(3));
+
+        let stats = StatsMap::default();
+        metrics.flush_now_to(&stats, &stats_all).unwrap();
+        let map: BTreeMap<String, MetricValue> = stats.into();
+
+        assert_eq!(map["test.timer_p.count"], 1000.0);
+        assert_eq!(map["test.timer_p.max"], 1_000_000.0);
+        assert!((map["test.timer_p.p50"] - 500_000.0).abs() < 500_000.0 / 16.0);
+        assert!((map["test.timer_p.p99_9"] - 999_000.0).abs() < 999_000.0 / 16.0);
+        assert!(!map.contains_key("test.timer_a.p50"));
+    }
+
+    #[test]
+    fn injected_clock() {
+        use core::clock::ManualClock;
+
+        let clock = ManualClock::new();
+        let metrics = AtomicBucket::new().add_prefix("test");
+        metrics.set_clock(clock.clone());
+        let counter = metrics.counter("counter_a");
+        let timer = metrics.timer("timer_a").with_clock(clock.clone());
+
+        counter.count(10);
+        timer.time(|| clock.advance(Duration::from_millis(1500)));
+        clock.advance(Duration::from_millis(500));
+
+        let stats = StatsMap::default();
+        metrics.flush_now_to(&stats, &stats_all).unwrap();
+        let map: BTreeMap<String, MetricValue> = stats.into();
+
+        assert_eq!(map["test.counter_a.rate"], 5.0);
+        assert_eq!(map["test.timer_a.sum"], 1_500_000.0);
+    }
+
+    type RouteKey = (String, Option<String>);
+
+    /// Collects stats by name and value of the "route" label.
+    #[derive(Clone, Default)]
+    struct RouteStats {
+        stats: Rc<RefCell<BTreeMap<RouteKey, MetricValue>>>,
+    }
+
+    impl OutputScope for RouteStats {
+        fn new_metric(&self, name: MetricName, _kind: InputKind) -> OutputMetric {
+            let stats = self.stats.clone();
+            let name = name.join(".");
+            OutputMetric::new(move |value, labels| {
+                let route = labels.lookup("route").map(|route| route.as_ref().clone());
+                stats.borrow_mut().insert((name.clone(), route), value);
+            })
+        }
+    }
+
+    impl Flush for RouteStats {}
+
+    fn route(name: &str, route: Option<&str>) -> RouteKey {
+        (name.to_string(), route.map(|route| route.to_string()))
+    }
+
+    #[test]
+    fn aggregate_by_labels() {
+        let metrics = AtomicBucket::new().aggregate_labels(&["route"]).max_label_sets(2);
+        let counter = metrics.counter("requests");
+        counter.count_with(1, labels!{ "route" => "/a", "user" => "joe" });
+        counter.count_with(2, labels!{ "route" => "/a", "user" => "bob" });
+        counter.count_with(4, labels!{ "route" => "/b" });
+        counter.count(8);
+        // over the label set cap, aggregated without labels
+        counter.count_with(16, labels!{ "route" => "/c" });
+
+        let stats = RouteStats::default();
+        metrics.flush_now_to(&stats, &stats_summary).unwrap();
+        {
+            let map = RefCell::borrow(&stats.stats);
+            assert_eq!(3, map.len());
+            assert_eq!(3.0, map[&route("requests", Some("/a"))]);
+            assert_eq!(4.0, map[&route("requests", Some("/b"))]);
+            assert_eq!(24.0, map[&route("requests", None)]);
+        }
+
+        // label sets left idle for a period are dropped, making room for new ones
+        metrics.flush_now_to(&RouteStats::default(), &stats_summary).unwrap();
+        counter.count_with(32, labels!{ "route" => "/c" });
+        counter.count_with(64, labels!{ "route" => "/d" });
+        let stats = RouteStats::default();
+        metrics.flush_now_to(&stats, &stats_summary).unwrap();
+        let map = RefCell::borrow(&stats.stats);
+        assert_eq!(2, map.len());
+        assert_eq!(32.0, map[&route("requests", Some("/c"))]);
+        assert_eq!(64.0, map[&route("requests", Some("/d"))]);
+    }
+
+    #[test]
+    fn kind_conflict_policies() {
+        let metrics = AtomicBucket::new();
+        metrics.counter("a").count(1);
+        metrics.timer("a").interval_us(2);
+        metrics.set_kind_conflict(OnKindConflict::Separate);
+        metrics.gauge("a").value(3);
+        metrics.set_kind_conflict(OnKindConflict::Reject);
+        metrics.marker("a").mark();
+
+        let defined: Vec<(String, InputKind)> = metrics.inner.read().unwrap().metrics.keys()
+            .map(|&(ref name, kind)| (name.join("."), kind))
+            .collect();
+        assert_eq!(vec![
+            ("a".to_string(), InputKind::Counter),
+            ("a".to_string(), InputKind::Gauge),
+            ("a_timer".to_string(), InputKind::Timer),
+        ], defined);
+        assert_eq!(3, metrics.kind_conflicts().len());
+
+        let stats = RouteStats::default();
+        metrics.flush_now_to(&stats, &stats_summary).unwrap();
+        assert_eq!(2.0, RefCell::borrow(&stats.stats)[&route("a_timer", None)]);
+    }
+
+    /// Collects the timestamp of every written value.
+    #[derive(Clone, Default)]
+    struct Timestamps {
+        stamps: Rc<RefCell<Vec<Option<SystemTime>>>>,
+    }
+
+    impl OutputScope for Timestamps {
+        fn new_metric(&self, _name: MetricName, _kind: InputKind) -> OutputMetric {
+            let stamps = self.stamps.clone();
+            OutputMetric::new_timed(move |_value, _labels, timestamp| stamps.borrow_mut().push(timestamp))
+        }
+    }
+
+    impl Flush for Timestamps {}
+
+    #[test]
+    fn flush_with_period_timestamp() {
+        let metrics = AtomicBucket::new();
+        metrics.counter("counter_a").count(1);
+        metrics.gauge("gauge_a").value(2);
+        metrics.timer("timer_a").interval_us(3);
+
+        let stamps = Timestamps::default();
+        metrics.flush_now_to(&stamps, &stats_all).unwrap();
+        let stamps = RefCell::borrow(&stamps.stamps);
+        assert!(stamps.len() > 3);
+        let first = stamps[0].expect("period timestamp");
+        assert!(stamps.iter().all(|stamp| *stamp == Some(first)));
+        assert_eq!(0, first.duration_since(UNIX_EPOCH).unwrap().subsec_nanos());
     }
 }
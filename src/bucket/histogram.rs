@@ -0,0 +1,186 @@
+//! Lock-free log-linear histogram used by the bucket to track percentiles.
+//! Values are counted in buckets of exponentially increasing width,
+//! each power of two being split in a fixed number of linear sub-buckets.
+//! Relative error of reported values is bounded by `1 / SUB_BUCKETS`.
+//! Negative values are counted in a mirrored range of buckets, so gauges going below zero report correct percentiles.
+
+use core::MetricValue;
+
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::*;
+
+/// Number of significant bits of a value kept when assigning it to a bucket.
+const PRECISION_BITS: usize = 5;
+
+/// Number of linear sub-buckets splitting every power of two.
+const SUB_BUCKETS: usize = 1 << (PRECISION_BITS - 1);
+
+/// Smallest power of two with buckets of its own, smaller magnitudes share the bucket nearest to zero.
+const MIN_EXPONENT: i64 = -32;
+
+/// Largest power of two with buckets of its own, larger magnitudes share the outermost bucket.
+const MAX_EXPONENT: i64 = 64;
+
+/// Enough buckets to split every power of two in range, for either sign.
+const SIGNED_BUCKETS: usize = (MAX_EXPONENT - MIN_EXPONENT + 1) as usize * SUB_BUCKETS;
+
+/// Buckets for negative values, then one for zero, then buckets for positive values,
+/// so that bucket order follows value order.
+const BUCKETS: usize = 2 * SIGNED_BUCKETS + 1;
+
+/// The bucket counting zeros.
+const ZERO: usize = SIGNED_BUCKETS;
+
+/// Counts of recorded values, used to estimate the requested percentiles upon reset.
+pub struct AtomicHistogram {
+    percentiles: Arc<[f64]>,
+    buckets: Box<[AtomicUsize]>,
+}
+
+impl fmt::Debug for AtomicHistogram {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "AtomicHistogram {:?}", self.percentiles)
+    }
+}
+
+/// Map a value to its bucket, mirroring negative values around the zero bucket.
+#[inline]
+fn index_of(value: MetricValue) -> usize {
+    if value > 0.0 {
+        ZERO + 1 + magnitude_index(value)
+    } else if value < 0.0 {
+        ZERO - 1 - magnitude_index(-value)
+    } else {
+        ZERO
+    }
+}
+
+/// Map a positive value to its offset from the zero bucket,
+/// using the exponent and the leading mantissa bits of its representation.
+#[inline]
+fn magnitude_index(value: MetricValue) -> usize {
+    let bits = value.to_bits();
+    let exponent = ((bits >> 52) & 0x7ff) as i64 - 1023;
+    if exponent < MIN_EXPONENT {
+        return 0
+    }
+    if exponent > MAX_EXPONENT {
+        return SIGNED_BUCKETS - 1
+    }
+    let sub_bucket = (bits >> (52 - (PRECISION_BITS - 1))) as usize & (SUB_BUCKETS - 1);
+    (exponent - MIN_EXPONENT) as usize * SUB_BUCKETS + sub_bucket
+}
+
+/// Map a bucket back to the value at the middle of its range.
+fn value_of(index: usize) -> MetricValue {
+    if index > ZERO {
+        magnitude_of(index - ZERO - 1)
+    } else if index < ZERO {
+        -magnitude_of(ZERO - 1 - index)
+    } else {
+        0.0
+    }
+}
+
+/// Map an offset from the zero bucket back to the magnitude at the middle of its range.
+fn magnitude_of(offset: usize) -> MetricValue {
+    let exponent = MIN_EXPONENT + (offset / SUB_BUCKETS) as i64;
+    let sub_bucket = offset % SUB_BUCKETS;
+    let width = 2f64.powi(exponent as i32) / SUB_BUCKETS as f64;
+    2f64.powi(exponent as i32) + width * (sub_bucket as f64 + 0.5)
+}
+
+impl AtomicHistogram {
+    /// Create a new histogram reporting the specified percentiles.
+    pub fn new(percentiles: Arc<[f64]>) -> Self {
+        AtomicHistogram {
+            percentiles,
+            buckets: (0..BUCKETS).map(|_| AtomicUsize::new(0)).collect::<Vec<_>>().into_boxed_slice(),
+        }
+    }
+
+    /// Count a new value. NaN values are not counted.
+    #[inline]
+    pub fn update(&self, value: MetricValue) {
+        if value.is_nan() {
+            return
+        }
+        self.buckets[index_of(value)].fetch_add(1, AcqRel);
+    }
+
+    /// Reset counts to zero, return the estimated value of each tracked percentile.
+    /// Concurrent updates may end up in either the current or the next period.
+    pub fn reset(&self) -> Vec<(f64, MetricValue)> {
+        let counts: Vec<usize> = self.buckets.iter().map(|bucket| bucket.swap(0, AcqRel)).collect();
+        let total: usize = counts.iter().sum();
+        if total == 0 {
+            return vec![]
+        }
+
+        self.percentiles.iter().map(|&percentile| {
+            let rank = ((percentile / 100.0 * total as f64).ceil() as usize).max(1).min(total);
+            let mut seen = 0;
+            let mut index = 0;
+            for (i, count) in counts.iter().enumerate() {
+                seen += count;
+                if seen >= rank {
+                    index = i;
+                    break;
+                }
+            }
+            (percentile, value_of(index))
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn bucket_value_precision() {
+        let values = (1..100_000).map(|value| value as MetricValue)
+            .chain((1..1000).map(|value| value as MetricValue / 1000.0))
+            .chain(vec![(1u64 << 40) as MetricValue, (3u64 << 50) as MetricValue, 1e-9, 1e18]);
+        for value in values.flat_map(|value| vec![value, -value]) {
+            let estimate = value_of(index_of(value));
+            let error = (estimate - value).abs() / value.abs();
+            assert!(error <= 1.0 / SUB_BUCKETS as f64, "{} estimated as {}", value, estimate);
+        }
+        assert_eq!(0.0, value_of(index_of(0.0)));
+        assert_eq!(0.0, value_of(index_of(-0.0)));
+    }
+
+    #[test]
+    fn percentiles_of_negative_values() {
+        let histogram = AtomicHistogram::new(Arc::new([0.0, 25.0, 50.0, 100.0]));
+        for value in -500..=499 {
+            histogram.update(value as MetricValue);
+        }
+        histogram.update(MetricValue::NAN);
+        let percentiles = histogram.reset();
+        for (&(percentile, value), expected) in percentiles.iter().zip(&[-500.0, -251.0, -1.0, 499.0]) {
+            assert!((value - expected).abs() <= expected.abs() / SUB_BUCKETS as f64,
+                "p{} was {}", percentile, value);
+        }
+    }
+
+    #[test]
+    fn percentiles_of_uniform_values() {
+        let histogram = AtomicHistogram::new(Arc::new([50.0, 90.0, 99.0, 100.0]));
+        for value in 1..=1000 {
+            histogram.update(value as MetricValue);
+        }
+        let percentiles = histogram.reset();
+        assert_eq!(4, percentiles.len());
+        for (&(percentile, value), expected) in percentiles.iter().zip(&[500.0, 900.0, 990.0, 1000.0]) {
+            assert!((value - expected).abs() / expected <= 1.0 / SUB_BUCKETS as f64,
+                "p{} was {}", percentile, value);
+        }
+
+        // counts were reset
+        assert!(histogram.reset().is_empty());
+    }
+}
@@ -1,4 +1,5 @@
 pub mod atomic;
+pub mod histogram;
 
 use core::input::InputKind;
 use core::MetricValue;
@@ -10,15 +11,23 @@ pub enum ScoreType {
     /// Number of times the metric was used.
     Count(isize),
     /// Sum of metric values reported.
-    Sum(isize),
+    Sum(MetricValue),
     /// Biggest value reported.
-    Max(isize),
+    Max(MetricValue),
     /// Smallest value reported.
-    Min(isize),
+    Min(MetricValue),
     /// Average value (hit count / sum, non-atomic)
     Mean(f64),
     /// Mean rate (hit count / period length in seconds, non-atomic)
     Rate(f64),
+    /// Estimated value below which the given percentage (e.g. 99.9) of reported values fall.
+    /// Only produced for metrics defined from a bucket with tracked `percentiles`.
+    Percentile(f64, MetricValue),
+}
+
+/// Name a percentile stat, e.g. `p99` or `p99_9`.
+fn percentile_name(percentile: f64) -> String {
+    format!("p{}", percentile).replace('.', "_")
 }
 
 /// A predefined export strategy reporting all aggregated stats for all metric types.
@@ -28,12 +37,13 @@ pub fn stats_all(kind: InputKind, name: MetricName, score: ScoreType)
     -> Option<(InputKind, MetricName, MetricValue)>
 {
     match score {
-        ScoreType::Count(hit) => Some((InputKind::Counter, name.make_name("count"), hit)),
+        ScoreType::Count(hit) => Some((InputKind::Counter, name.make_name("count"), hit as MetricValue)),
         ScoreType::Sum(sum) => Some((kind, name.make_name("sum"), sum)),
-        ScoreType::Mean(mean) => Some((kind, name.make_name("mean"), mean.round() as MetricValue)),
+        ScoreType::Mean(mean) => Some((kind, name.make_name("mean"), mean)),
         ScoreType::Max(max) => Some((InputKind::Gauge, name.make_name("max"), max)),
         ScoreType::Min(min) => Some((InputKind::Gauge, name.make_name("min"), min)),
-        ScoreType::Rate(rate) => Some((InputKind::Gauge, name.make_name("rate"), rate.round() as MetricValue)),
+        ScoreType::Rate(rate) => Some((InputKind::Gauge, name.make_name("rate"), rate)),
+        ScoreType::Percentile(p, value) => Some((kind, name.make_name(percentile_name(p)), value)),
     }
 }
 
@@ -47,11 +57,11 @@ pub fn stats_average(kind: InputKind, name: MetricName, score: ScoreType)
 {
     match kind {
         InputKind::Marker => match score {
-            ScoreType::Count(count) => Some((InputKind::Counter, name, count)),
+            ScoreType::Count(count) => Some((InputKind::Counter, name, count as MetricValue)),
             _ => None,
         },
         _ => match score {
-            ScoreType::Mean(avg) => Some((InputKind::Gauge, name, avg.round() as MetricValue)),
+            ScoreType::Mean(avg) => Some((InputKind::Gauge, name, avg)),
             _ => None,
         },
     }
@@ -69,7 +79,7 @@ pub fn stats_summary(kind: InputKind, name: MetricName, score: ScoreType)
 {
     match kind {
         InputKind::Marker => match score {
-            ScoreType::Count(count) => Some((InputKind::Counter, name, count)),
+            ScoreType::Count(count) => Some((InputKind::Counter, name, count as MetricValue)),
             _ => None,
         },
         InputKind::Counter | InputKind::Timer => match score {
@@ -77,7 +87,7 @@ pub fn stats_summary(kind: InputKind, name: MetricName, score: ScoreType)
             _ => None,
         },
         InputKind::Gauge => match score {
-            ScoreType::Mean(mean) => Some((InputKind::Gauge, name, mean.round() as MetricValue)),
+            ScoreType::Mean(mean) => Some((InputKind::Gauge, name, mean)),
             _ => None,
         },
     }
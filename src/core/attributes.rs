@@ -4,13 +4,25 @@ use std::collections::{HashMap};
 use core::name::{NameParts, MetricName};
 
 /// The actual distribution (random, fixed-cycled, etc) depends on selected sampling method.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Sampling {
     /// Floating point sampling rate
     /// - 1.0+ records everything
     /// - 0.5 records one of two values
     /// - 0.0 records nothing
-    Random(f64)
+    Random(f64),
+
+    /// Deterministically record the first of every N values of each metric, e.g. for reproducible tests.
+    EveryNth(usize),
+
+    /// Record at most this many values per second of each metric, dropping the rest.
+    /// The effective rate reported for a value is the ratio of values recorded during the previous second.
+    MaxRate(usize),
+
+    /// Record values according to a hash of the specified label's value, at the specified rate.
+    /// Values sharing a label value (e.g. a request id) are all recorded or all dropped, across metrics.
+    /// Values without the label are sampled randomly.
+    LabelHash(&'static str, f64),
 }
 
 /// A metrics buffering strategy.
@@ -98,7 +110,11 @@ impl<T: WithAttributes> Prefixed for T {
 /// Apply statistical sampling to collected metrics data.
 pub trait Sampled: WithAttributes {
     /// Perform random sampling of values according to the specified rate.
+    /// Panics if the sampling rate is NaN.
     fn sampled(&self, sampling: Sampling) -> Self {
+        if let Sampling::Random(rate) | Sampling::LabelHash(_, rate) = sampling {
+            assert!(!rate.is_nan(), "Sampling rate is NaN");
+        }
         self.with_attributes(|new_attr| new_attr.sampling = Some(sampling))
     }
 
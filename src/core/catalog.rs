@@ -0,0 +1,112 @@
+//! Describe the metrics defined through a proxy and where their values are sent.
+
+use core::name::NameParts;
+use core::input::InputKind;
+use core::metadata::Unit;
+
+use std::sync::Arc;
+
+/// A metric defined through a proxy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MetricInfo {
+    /// The full name of the metric, including its namespaces.
+    pub name: NameParts,
+    /// The kind of the metric.
+    pub kind: InputKind,
+    /// The unit of the metric's values, if known.
+    pub unit: Option<Unit>,
+    /// What the metric measures, if described.
+    pub description: Option<Arc<str>>,
+    /// The namespace of the target currently receiving the metric's values, if any.
+    pub target: Option<NameParts>,
+}
+
+/// All the metrics and targets of a proxy, as they were when the catalog was made.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Catalog {
+    /// The live metrics, sorted by name.
+    pub metrics: Vec<MetricInfo>,
+    /// The namespaces having a target, sorted.
+    pub targets: Vec<NameParts>,
+}
+
+impl Catalog {
+    /// Serialize the catalog to JSON, e.g. to be dumped on startup or served from an admin endpoint.
+    /// Names are joined with dots, the root namespace being the empty string.
+    pub fn to_json(&self) -> String {
+        let mut json = String::from("{\"metrics\":[");
+        for (i, metric) in self.metrics.iter().enumerate() {
+            if i > 0 { json.push(',') }
+            json.push_str("{\"name\":");
+            push_json_string(&mut json, &metric.name.join("."));
+            json.push_str(",\"kind\":");
+            push_json_string(&mut json, &format!("{:?}", metric.kind));
+            json.push_str(",\"unit\":");
+            match metric.unit {
+                Some(unit) => push_json_string(&mut json, unit.name()),
+                None => json.push_str("null"),
+            }
+            json.push_str(",\"description\":");
+            match metric.description {
+                Some(ref description) => push_json_string(&mut json, description),
+                None => json.push_str("null"),
+            }
+            json.push_str(",\"target\":");
+            match metric.target {
+                Some(ref target) => push_json_string(&mut json, &target.join(".")),
+                None => json.push_str("null"),
+            }
+            json.push('}');
+        }
+        json.push_str("],\"targets\":[");
+        for (i, target) in self.targets.iter().enumerate() {
+            if i > 0 { json.push(',') }
+            push_json_string(&mut json, &target.join("."));
+        }
+        json.push_str("]}");
+        json
+    }
+}
+
+/// Append the text as a quoted JSON string.
+fn push_json_string(json: &mut String, text: &str) {
+    json.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn catalog_to_json() {
+        let mut name = NameParts::from("requests");
+        name.push_front("my \"app\"".to_string());
+        let catalog = Catalog {
+            metrics: vec![
+                MetricInfo { name: name.clone(), kind: InputKind::Counter, unit: None,
+                    description: Some("Requests served".into()), target: Some(NameParts::default()) },
+                MetricInfo { name: NameParts::from("idle"), kind: InputKind::Timer, unit: Some(Unit::Microseconds),
+                    description: None, target: None },
+            ],
+            targets: vec![NameParts::default()],
+        };
+        assert_eq!(concat!(
+            r#"{"metrics":[{"name":"my \"app\".requests","kind":"Counter","unit":null,"#,
+            r#""description":"Requests served","target":""},"#,
+            r#"{"name":"idle","kind":"Timer","unit":"microseconds","description":null,"target":null}],"#,
+            r#""targets":[""]}"#),
+            catalog.to_json());
+    }
+}
@@ -1,13 +1,68 @@
 #[cfg(test)]
 use std::ops::Add;
 
-#[cfg(test)]
-use std::time::Duration;
-
 use std::time::Instant;
 
+#[cfg(any(test, feature = "test-util"))]
+use std::time::Duration;
+#[cfg(any(test, feature = "test-util"))]
+use std::sync::{Arc, Mutex};
+
 use core::MetricValue;
 
+/// A source of the current time for metrics measuring it, such as timers and buckets.
+/// Substitute a `ManualClock` (with the `test-util` feature) to test time-dependent metrics reproducibly.
+pub trait Clock: Send + Sync {
+    /// The current time according to this clock.
+    fn now(&self) -> Instant;
+}
+
+/// The system's monotonic clock, used unless another clock is specified.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        now()
+    }
+}
+
+/// A clock that only moves when told to.
+/// Clones share the same time, so a clock given to a bucket or timer can be advanced by the test.
+#[cfg(any(test, feature = "test-util"))]
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    now: Arc<Mutex<Instant>>,
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl Default for ManualClock {
+    fn default() -> Self {
+        ManualClock::new()
+    }
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl ManualClock {
+    /// A manual clock starting at the current time.
+    pub fn new() -> Self {
+        ManualClock { now: Arc::new(Mutex::new(Instant::now())) }
+    }
+
+    /// Move the clock forward by this amount of time.
+    pub fn advance(&self, period: Duration) {
+        let mut now = self.now.lock().expect("Manual Clock");
+        *now += period;
+    }
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().expect("Manual Clock")
+    }
+}
+
 #[derive(Debug, Copy, Clone)]
 /// A handle to the start time of a counter.
 /// Wrapped so it may be changed safely later.
@@ -20,15 +75,26 @@ impl TimeHandle {
         TimeHandle(now())
     }
 
+    /// Get a handle on the current time of the clock.
+    pub fn now_from(clock: &dyn Clock) -> TimeHandle {
+        TimeHandle(clock.now())
+    }
+
     /// Get the elapsed time in microseconds since TimeHandle was obtained.
     pub fn elapsed_us(self) -> MetricValue {
-        let duration = now() - self.0;
-        (duration.as_secs() * 1_000_000) as MetricValue + duration.subsec_micros() as MetricValue
+        self.elapsed_us_from(&SystemClock)
     }
 
-    /// Get the elapsed time in microseconds since TimeHandle was obtained.
+    /// Get the elapsed time in microseconds since TimeHandle was obtained, according to the clock.
+    /// The clock should be the one the handle was obtained from.
+    pub fn elapsed_us_from(self, clock: &dyn Clock) -> MetricValue {
+        let duration = clock.now().saturating_duration_since(self.0);
+        (duration.as_secs() * 1_000_000) as MetricValue + MetricValue::from(duration.subsec_micros())
+    }
+
+    /// Get the elapsed time in whole milliseconds since TimeHandle was obtained.
     pub fn elapsed_ms(self) -> MetricValue {
-        self.elapsed_us() / 1000
+        (self.elapsed_us() / 1000.0).floor()
     }
 }
 
@@ -0,0 +1,141 @@
+//! Handle metrics defined again under the same name but with a different kind.
+
+use core::name::NameParts;
+use core::input::InputKind;
+
+/// What to do when a metric is defined again under the same name but with a different kind,
+/// e.g. when two libraries both define `a.b`, one as a Counter and the other as a Timer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OnKindConflict {
+    /// Log an error and discard the values of the conflicting metric.
+    Reject,
+    /// Log a warning and define the conflicting metric under its name suffixed with its kind,
+    /// e.g. `a.b_timer`.
+    #[default]
+    Rename,
+    /// Keep a separate metric of each kind under the same name.
+    Separate,
+}
+
+/// A metric defined again with a different kind.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KindConflict {
+    /// The full name of the metric.
+    pub name: NameParts,
+    /// The kind the metric was first defined with.
+    pub existing: InputKind,
+    /// The kind of the conflicting definition.
+    pub requested: InputKind,
+}
+
+/// How a metric definition proceeds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Definition {
+    /// No conflict, define the metric under its name.
+    Named,
+    /// Conflicting, define the metric under its name alongside the existing one.
+    Separated,
+    /// Conflicting, define the metric under its name suffixed with its kind.
+    Renamed,
+    /// Conflicting, discard the metric's values.
+    Rejected,
+}
+
+/// The kind conflict policy of a proxy or bucket, and the conflicts it met.
+#[derive(Debug, Default)]
+pub struct KindConflicts {
+    policy: OnKindConflict,
+    conflicts: Vec<KindConflict>,
+}
+
+impl KindConflicts {
+    /// Change the policy applied to upcoming definitions.
+    pub fn set_policy(&mut self, policy: OnKindConflict) {
+        self.policy = policy
+    }
+
+    /// The distinct conflicts met so far.
+    pub fn list(&self) -> Vec<KindConflict> {
+        self.conflicts.clone()
+    }
+
+    /// Check a definition against the kinds already defined under the same name.
+    /// Conflicts are logged and recorded.
+    pub fn check(&mut self, name: &NameParts, kind: InputKind, existing: &[InputKind]) -> Definition {
+        if existing.is_empty() || existing.contains(&kind) {
+            return Definition::Named
+        }
+        let conflict = KindConflict { name: name.clone(), existing: existing[0], requested: kind };
+        let definition = match self.policy {
+            OnKindConflict::Reject => {
+                error!("Metric {:?} already defined as {:?}, discarding {:?} values", name, existing[0], kind);
+                Definition::Rejected
+            }
+            OnKindConflict::Rename => {
+                warn!("Metric {:?} already defined as {:?}, renaming {:?}", name, existing[0], kind);
+                Definition::Renamed
+            }
+            OnKindConflict::Separate => {
+                debug!("Metric {:?} already defined as {:?}, adding {:?}", name, existing[0], kind);
+                Definition::Separated
+            }
+        };
+        self.record(conflict);
+        definition
+    }
+
+    /// Check a renamed definition against the kinds already defined under the new name.
+    /// A metric is not renamed twice, the values of a renamed metric that still conflicts are discarded.
+    pub fn check_renamed(&mut self, name: &NameParts, kind: InputKind, existing: &[InputKind]) -> Definition {
+        if existing.is_empty() || existing.contains(&kind) {
+            return Definition::Renamed
+        }
+        error!("Metric renamed to {:?} already defined as {:?}, discarding {:?} values", name, existing[0], kind);
+        self.record(KindConflict { name: name.clone(), existing: existing[0], requested: kind });
+        Definition::Rejected
+    }
+
+    fn record(&mut self, conflict: KindConflict) {
+        if !self.conflicts.contains(&conflict) {
+            self.conflicts.push(conflict)
+        }
+    }
+}
+
+/// Suffix the last part of the name with the kind, e.g. `latency_timer`.
+pub fn suffix_kind(name: &mut NameParts, kind: InputKind) {
+    if let Some(last) = name.back_mut() {
+        last.push('_');
+        last.push_str(&format!("{:?}", kind).to_lowercase());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn check_definitions() {
+        let name = NameParts::from("latency");
+        let mut conflicts = KindConflicts::default();
+        assert_eq!(Definition::Named, conflicts.check(&name, InputKind::Timer, &[]));
+        assert_eq!(Definition::Named, conflicts.check(&name, InputKind::Timer, &[InputKind::Timer]));
+        assert_eq!(Definition::Renamed, conflicts.check(&name, InputKind::Gauge, &[InputKind::Timer]));
+        conflicts.set_policy(OnKindConflict::Reject);
+        assert_eq!(Definition::Rejected, conflicts.check(&name, InputKind::Gauge, &[InputKind::Timer]));
+        conflicts.set_policy(OnKindConflict::Separate);
+        assert_eq!(Definition::Separated, conflicts.check(&name, InputKind::Counter, &[InputKind::Timer]));
+        assert_eq!(vec![
+            KindConflict { name: name.clone(), existing: InputKind::Timer, requested: InputKind::Gauge },
+            KindConflict { name: name.clone(), existing: InputKind::Timer, requested: InputKind::Counter },
+        ], conflicts.list());
+
+        let mut renamed = name.clone();
+        suffix_kind(&mut renamed, InputKind::Gauge);
+        assert_eq!(NameParts::from("latency_gauge"), renamed);
+        assert_eq!(Definition::Renamed, conflicts.check_renamed(&renamed, InputKind::Gauge, &[]));
+        assert_eq!(Definition::Renamed, conflicts.check_renamed(&renamed, InputKind::Gauge, &[InputKind::Gauge]));
+        assert_eq!(Definition::Rejected, conflicts.check_renamed(&renamed, InputKind::Gauge, &[InputKind::Counter]));
+        assert_eq!(3, conflicts.list().len());
+    }
+}
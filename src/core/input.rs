@@ -1,6 +1,7 @@
-use core::clock::TimeHandle;
+use core::clock::{TimeHandle, Clock};
 use core::{MetricValue, Flush};
 use core::name::MetricName;
+use core::metadata::Unit;
 use core::label::Labels;
 
 use std::sync::Arc;
@@ -64,7 +65,7 @@ pub trait InputScope: Flush {
 /// A metric is actually a function that knows to write a metric value to a metric output.
 #[derive(Clone)]
 pub struct InputMetric {
-    inner: Arc<Fn(MetricValue, Labels) + Send + Sync>
+    inner: Arc<dyn Fn(MetricValue, Labels, f64) + Send + Sync>
 }
 
 impl fmt::Debug for InputMetric {
@@ -75,19 +76,33 @@ impl fmt::Debug for InputMetric {
 
 impl InputMetric {
     /// Utility constructor
+    /// The sampling rate of values is ignored.
     pub fn new<F: Fn(MetricValue, Labels) + Send + Sync + 'static>(metric: F) -> InputMetric {
+        InputMetric { inner: Arc::new(move |value, labels, _rate| metric(value, labels)) }
+    }
+
+    /// Constructor of a metric that receives the rate at which values were sampled upstream,
+    /// e.g. to scale aggregated counts.
+    pub fn new_sampled<F: Fn(MetricValue, Labels, f64) + Send + Sync + 'static>(metric: F) -> InputMetric {
         InputMetric { inner: Arc::new(metric) }
     }
 
     /// Collect a new value for this metric.
     #[inline]
     pub fn write(&self, value: MetricValue, labels: Labels) {
-        (self.inner)(value, labels)
+        (self.inner)(value, labels, 1.0)
+    }
+
+    /// Collect a new value for this metric, recorded at the specified sampling rate.
+    /// A value sampled at 0.25 stands for four values.
+    #[inline]
+    pub fn write_sampled(&self, value: MetricValue, labels: Labels, rate: f64) {
+        (self.inner)(value, labels, rate)
     }
 }
 
 /// Used to differentiate between metric kinds in the backend.
-#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Ord, PartialOrd)]
 pub enum InputKind {
     /// Handling one item at a time.
     Marker,
@@ -112,18 +127,36 @@ impl<'a> From<&'a str> for InputKind {
     }
 }
 
+/// Convert any number to a metric value.
+/// Numbers that can not be represented, if any, become NaN rather than panicking.
+#[inline]
+fn to_value<V: ToPrimitive>(value: &V) -> MetricValue {
+    value.to_f64().unwrap_or(f64::NAN)
+}
+
 /// A monotonic counter metric.
 /// Since value is only ever increased by one, no value parameter is provided,
 /// preventing programming errors.
 #[derive(Debug, Clone)]
 pub struct Marker {
     inner: InputMetric,
+    labels: Labels,
 }
 
 impl Marker {
     /// Record a single event occurence.
     pub fn mark(&self) {
-        self.inner.write(1, labels![])
+        self.inner.write(1.0, self.labels.clone())
+    }
+
+    /// Record a single event occurence, with one-off labels.
+    pub fn mark_with(&self, labels: Labels) {
+        self.inner.write(1.0, self.labels.merge(labels))
+    }
+
+    /// Return a handle recording every event with these labels.
+    pub fn with_labels(&self, labels: Labels) -> Marker {
+        Marker { inner: self.inner.clone(), labels: self.labels.merge(labels) }
     }
 }
 
@@ -131,12 +164,23 @@ impl Marker {
 #[derive(Debug, Clone)]
 pub struct Counter {
     inner: InputMetric,
+    labels: Labels,
 }
 
 impl Counter {
     /// Record a value count.
     pub fn count<V: ToPrimitive>(&self, count: V) {
-        self.inner.write(count.to_isize().unwrap(), labels![])
+        self.inner.write(to_value(&count), self.labels.clone())
+    }
+
+    /// Record a value count, with one-off labels.
+    pub fn count_with<V: ToPrimitive>(&self, count: V, labels: Labels) {
+        self.inner.write(to_value(&count), self.labels.merge(labels))
+    }
+
+    /// Return a handle recording every count with these labels.
+    pub fn with_labels(&self, labels: Labels) -> Counter {
+        Counter { inner: self.inner.clone(), labels: self.labels.merge(labels) }
     }
 }
 
@@ -144,12 +188,23 @@ impl Counter {
 #[derive(Debug, Clone)]
 pub struct Gauge {
     inner: InputMetric,
+    labels: Labels,
 }
 
 impl Gauge {
     /// Record a value point for this gauge.
     pub fn value<V: ToPrimitive>(&self, value: V) {
-        self.inner.write(value.to_isize().unwrap(), labels![])
+        self.inner.write(to_value(&value), self.labels.clone())
+    }
+
+    /// Record a value point for this gauge, with one-off labels.
+    pub fn value_with<V: ToPrimitive>(&self, value: V, labels: Labels) {
+        self.inner.write(to_value(&value), self.labels.merge(labels))
+    }
+
+    /// Return a handle recording every value with these labels.
+    pub fn with_labels(&self, labels: Labels) -> Gauge {
+        Gauge { inner: self.inner.clone(), labels: self.labels.merge(labels) }
     }
 }
 
@@ -159,19 +214,48 @@ impl Gauge {
 /// - with the time(Fn) methodhich wraps a closure with start() and stop() calls.
 /// - with start() and stop() methodsrapping around the operation to time
 /// - with the interval_us() method, providing an externally determined microsecond interval
-#[derive(Debug, Clone)]
+///
+/// Intervals are recorded in microseconds, unless the timer was created from a name with another time unit.
+#[derive(Clone)]
 pub struct Timer {
     inner: InputMetric,
+    labels: Labels,
+    clock: Option<Arc<dyn Clock>>,
+    /// The number of microseconds in the unit of recorded intervals.
+    unit_us: f64,
+}
+
+impl fmt::Debug for Timer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Timer").field("inner", &self.inner).field("labels", &self.labels).finish()
+    }
 }
 
 impl Timer {
     /// Record a microsecond interval for this timer
     /// Can be used in place of start()/stop() if an external time interval source is used
     pub fn interval_us<V: ToPrimitive>(&self, interval_us: V) -> V {
-        self.inner.write(interval_us.to_isize().unwrap(), labels![]);
+        self.inner.write(to_value(&interval_us) / self.unit_us, self.labels.clone());
+        interval_us
+    }
+
+    /// Record a microsecond interval for this timer, with one-off labels.
+    pub fn interval_us_with<V: ToPrimitive>(&self, interval_us: V, labels: Labels) -> V {
+        self.inner.write(to_value(&interval_us) / self.unit_us, self.labels.merge(labels));
         interval_us
     }
 
+    /// Return a handle recording every interval with these labels.
+    pub fn with_labels(&self, labels: Labels) -> Timer {
+        Timer { labels: self.labels.merge(labels), ..self.clone() }
+    }
+
+    /// Return a handle measuring time intervals with this clock instead of the system's.
+    /// Handles obtained from its start() method should only be passed back to it.
+    pub fn with_clock<C: Clock + 'static>(&self, clock: C) -> Timer {
+        Timer { clock: Some(Arc::new(clock)), ..self.clone() }
+    }
+
     /// Obtain a opaque handle to the current time.
     /// The handle is passed back to the stop() method to record a time interval.
     /// This is actually a convenience method to the TimeHandle::now()
@@ -179,18 +263,35 @@ impl Timer {
     /// _for now_ but might be in the future for safety.
     /// If you require safe multi-timer handles, get them through TimeType::now()
     pub fn start(&self) -> TimeHandle {
-        TimeHandle::now()
+        match self.clock {
+            Some(ref clock) => TimeHandle::now_from(clock.as_ref()),
+            None => TimeHandle::now(),
+        }
+    }
+
+    fn elapsed_us(&self, start_time: TimeHandle) -> MetricValue {
+        match self.clock {
+            Some(ref clock) => start_time.elapsed_us_from(clock.as_ref()),
+            None => start_time.elapsed_us(),
+        }
     }
 
     /// Record the time elapsed since the start_time handle was obtained.
     /// This call can be performed multiple times using the same handle,
     /// reporting distinct time intervals each time.
-    /// Returns the microsecond interval value that was recorded.
+    /// Returns the measured interval in microseconds.
     pub fn stop(&self, start_time: TimeHandle) -> MetricValue {
-        let elapsed_us = start_time.elapsed_us();
+        let elapsed_us = self.elapsed_us(start_time);
         self.interval_us(elapsed_us)
     }
 
+    /// Record the time elapsed since the start_time handle was obtained, with one-off labels.
+    /// Returns the measured interval in microseconds.
+    pub fn stop_with(&self, start_time: TimeHandle, labels: Labels) -> MetricValue {
+        let elapsed_us = self.elapsed_us(start_time);
+        self.interval_us_with(elapsed_us, labels)
+    }
+
     /// Record the time taken to execute the provided closure
     pub fn time<F: FnOnce() -> R, R>(&self, operations: F) -> R {
         let start_time = self.start();
@@ -198,28 +299,163 @@ impl Timer {
         self.stop(start_time);
         value
     }
+
+    /// Record the time taken to execute the provided closure, with one-off labels.
+    pub fn time_with<F: FnOnce() -> R, R>(&self, labels: Labels, operations: F) -> R {
+        let start_time = self.start();
+        let value: R = operations();
+        self.stop_with(start_time, labels);
+        value
+    }
 }
 
 impl From<InputMetric> for Gauge {
     fn from(metric: InputMetric) -> Gauge {
-        Gauge { inner: metric }
+        Gauge { inner: metric, labels: Labels::default() }
     }
 }
 
 impl From<InputMetric> for Timer {
     fn from(metric: InputMetric) -> Timer {
-        Timer { inner: metric }
+        Timer { inner: metric, labels: Labels::default(), clock: None, unit_us: 1.0 }
     }
 }
 
 impl From<InputMetric> for Counter {
     fn from(metric: InputMetric) -> Counter {
-        Counter { inner: metric }
+        Counter { inner: metric, labels: Labels::default() }
     }
 }
 
 impl From<InputMetric> for Marker {
     fn from(metric: InputMetric) -> Marker {
-        Marker { inner: metric }
+        Marker { inner: metric, labels: Labels::default() }
+    }
+}
+
+/// Create a metric handle from the metric and the name it was defined with.
+/// Used by the metrics! macro, so that timers record intervals in the time unit of their name.
+pub trait FromMetric: From<InputMetric> {
+    /// Create a handle writing to the metric.
+    fn from_metric(metric: InputMetric, _name: &MetricName) -> Self {
+        Self::from(metric)
+    }
+}
+
+impl FromMetric for Marker {}
+impl FromMetric for Counter {}
+impl FromMetric for Gauge {}
+
+impl FromMetric for Timer {
+    /// Create a timer recording intervals in the name's time unit, microseconds by default.
+    fn from_metric(metric: InputMetric, name: &MetricName) -> Timer {
+        let unit_us = name.metadata().unit
+            .and_then(Unit::nanoseconds)
+            .map_or(1.0, |nanoseconds| nanoseconds as f64 / 1000.0);
+        Timer { unit_us, ..Timer::from(metric) }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::proxy::Proxy;
+    use cache::cache_in::InputCache;
+    use queue::queue_in::InputQueue;
+    use multi::multi_in::MultiInput;
+    use core::clock::ManualClock;
+    use output::recording::{RecordingInput, Recorded};
+
+    use std::sync::{Mutex, mpsc};
+    use std::time::Duration;
+
+    type RouteWrite = (MetricValue, Option<String>);
+
+    /// Sends every written value with the value of its "route" label.
+    #[derive(Clone)]
+    struct RouteSink {
+        sender: Arc<Mutex<mpsc::Sender<RouteWrite>>>,
+    }
+
+    impl Input for RouteSink {
+        type SCOPE = RouteSink;
+        fn input(&self) -> RouteSink { self.clone() }
+    }
+
+    impl InputScope for RouteSink {
+        fn new_metric(&self, _name: MetricName, _kind: InputKind) -> InputMetric {
+            let sender = self.sender.clone();
+            InputMetric::new(move |value, labels| {
+                let route = labels.lookup("route").map(|route| route.as_ref().clone());
+                sender.lock().unwrap().send((value, route)).unwrap()
+            })
+        }
+    }
+
+    impl Flush for RouteSink {}
+
+    fn route_sink() -> (RouteSink, mpsc::Receiver<RouteWrite>) {
+        let (sender, receiver) = mpsc::channel();
+        (RouteSink { sender: Arc::new(Mutex::new(sender)) }, receiver)
+    }
+
+    fn assert_labeled_writes<S: InputScope>(scope: S, received: mpsc::Receiver<RouteWrite>) {
+        let counter = scope.counter("requests");
+        counter.count_with(3, labels!{ "route" => "/a" });
+        counter.with_labels(labels!{ "route" => "/b" }).count(5);
+        counter.with_labels(labels!{ "route" => "/b" }).count_with(7, labels!{ "route" => "/c" });
+        counter.count(11);
+        scope.timer("latency").time_with(labels!{ "route" => "/d" }, || ());
+        scope.gauge("queued").value_with(13, labels!{ "route" => "/e" });
+        scope.marker("hits").mark_with(labels!{ "route" => "/f" });
+
+        assert_eq!((3.0, Some("/a".to_string())), received.recv().unwrap());
+        assert_eq!((5.0, Some("/b".to_string())), received.recv().unwrap());
+        assert_eq!((7.0, Some("/c".to_string())), received.recv().unwrap());
+        assert_eq!((11.0, None), received.recv().unwrap());
+        assert_eq!(Some("/d".to_string()), received.recv().unwrap().1);
+        assert_eq!((13.0, Some("/e".to_string())), received.recv().unwrap());
+        assert_eq!((1.0, Some("/f".to_string())), received.recv().unwrap());
+    }
+
+    #[test]
+    fn labels_through_proxy() {
+        let (sink, received) = route_sink();
+        let proxy = Proxy::new();
+        proxy.set_target(sink);
+        assert_labeled_writes(proxy, received);
+    }
+
+    #[test]
+    fn labels_through_cache() {
+        let (sink, received) = route_sink();
+        assert_labeled_writes(InputCache::wrap(sink, 10).input(), received);
+    }
+
+    #[test]
+    fn labels_through_queue() {
+        let (sink, received) = route_sink();
+        assert_labeled_writes(InputQueue::new(sink, 10).input(), received);
+    }
+
+    #[test]
+    fn labels_through_multi() {
+        let (sink, received) = route_sink();
+        assert_labeled_writes(MultiInput::input().add_target(sink).input(), received);
+    }
+
+    #[test]
+    fn timer_in_declared_unit() {
+        let recording = RecordingInput::new();
+        let clock = ManualClock::new();
+        let name = MetricName::from("t").unit(Unit::Milliseconds);
+        let timer = Timer::from_metric(recording.new_metric(name.clone(), InputKind::Timer), &name)
+            .with_clock(clock.clone());
+
+        let start = timer.start();
+        clock.advance(Duration::from_millis(3));
+        assert_eq!(3000.0, timer.stop(start));
+        timer.interval_us(500);
+        assert_eq!(vec![3.0, 0.5], recording.values("t", InputKind::Timer));
     }
 }
@@ -0,0 +1,333 @@
+//! Instrument futures and streams, e.g. async request handlers.
+//! Measures are taken from the time the future or stream is instrumented, usually when it is created.
+
+use core::input::{Timer, Marker};
+#[cfg(feature="futures")]
+use core::input::Counter;
+use core::clock::TimeHandle;
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+#[cfg(feature="futures")]
+use futures_core::Stream;
+
+/// Record metrics about futures.
+pub trait InstrumentFuture: Future + Sized {
+    /// Record the time from now until the future completes.
+    fn timed(self, timer: &Timer) -> Timed<Self> {
+        Timed { inner: self, timer: timer.clone(), start: timer.start(), done: false, cancelled: None }
+    }
+
+    /// Record the time from now until the future completes, or mark `cancelled` if it is dropped before.
+    fn timed_or_cancelled(self, timer: &Timer, cancelled: &Marker) -> Timed<Self> {
+        Timed { inner: self, timer: timer.clone(), start: timer.start(), done: false, cancelled: Some(cancelled.clone()) }
+    }
+
+    /// Record the time from now until the future is first polled, e.g. the time spent waiting for an executor.
+    fn timed_to_first_poll(self, timer: &Timer) -> FirstPolled<Self> {
+        FirstPolled { inner: self, timer: timer.clone(), start: Some(timer.start()), cancelled: None }
+    }
+
+    /// Record the time from now until the future is first polled, or mark `cancelled` if it is dropped before.
+    fn timed_to_first_poll_or_cancelled(self, timer: &Timer, cancelled: &Marker) -> FirstPolled<Self> {
+        FirstPolled { inner: self, timer: timer.clone(), start: Some(timer.start()), cancelled: Some(cancelled.clone()) }
+    }
+
+    /// Mark the future's completion.
+    fn counted(self, marker: &Marker) -> Counted<Self> {
+        Counted { inner: self, marker: marker.clone(), done: false, cancelled: None }
+    }
+
+    /// Mark the future's completion, or mark `cancelled` if it is dropped before.
+    fn counted_or_cancelled(self, marker: &Marker, cancelled: &Marker) -> Counted<Self> {
+        Counted { inner: self, marker: marker.clone(), done: false, cancelled: Some(cancelled.clone()) }
+    }
+}
+
+impl<F: Future> InstrumentFuture for F {}
+
+/// A future recording its latency.
+#[derive(Debug)]
+pub struct Timed<F> {
+    inner: F,
+    timer: Timer,
+    start: TimeHandle,
+    done: bool,
+    cancelled: Option<Marker>,
+}
+
+impl<F: Future> Future for Timed<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<F::Output> {
+        // the wrapped future is never moved out of its pinned wrapper, including when dropped
+        let this = unsafe { self.get_unchecked_mut() };
+        let poll = unsafe { Pin::new_unchecked(&mut this.inner) }.poll(cx);
+        if poll.is_ready() && !this.done {
+            this.done = true;
+            this.timer.stop(this.start);
+        }
+        poll
+    }
+}
+
+impl<F> Drop for Timed<F> {
+    fn drop(&mut self) {
+        if !self.done {
+            if let Some(ref cancelled) = self.cancelled {
+                cancelled.mark()
+            }
+        }
+    }
+}
+
+/// A future recording the time until it was first polled.
+#[derive(Debug)]
+pub struct FirstPolled<F> {
+    inner: F,
+    timer: Timer,
+    start: Option<TimeHandle>,
+    cancelled: Option<Marker>,
+}
+
+impl<F: Future> Future for FirstPolled<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<F::Output> {
+        // the wrapped future is never moved out of its pinned wrapper
+        let this = unsafe { self.get_unchecked_mut() };
+        if let Some(start) = this.start.take() {
+            this.timer.stop(start);
+        }
+        unsafe { Pin::new_unchecked(&mut this.inner) }.poll(cx)
+    }
+}
+
+impl<F> Drop for FirstPolled<F> {
+    fn drop(&mut self) {
+        if self.start.is_some() {
+            if let Some(ref cancelled) = self.cancelled {
+                cancelled.mark()
+            }
+        }
+    }
+}
+
+/// A future marking its completion.
+#[derive(Debug)]
+pub struct Counted<F> {
+    inner: F,
+    marker: Marker,
+    done: bool,
+    cancelled: Option<Marker>,
+}
+
+impl<F: Future> Future for Counted<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<F::Output> {
+        // the wrapped future is never moved out of its pinned wrapper, including when dropped
+        let this = unsafe { self.get_unchecked_mut() };
+        let poll = unsafe { Pin::new_unchecked(&mut this.inner) }.poll(cx);
+        if poll.is_ready() && !this.done {
+            this.done = true;
+            this.marker.mark();
+        }
+        poll
+    }
+}
+
+impl<F> Drop for Counted<F> {
+    fn drop(&mut self) {
+        if !self.done {
+            if let Some(ref cancelled) = self.cancelled {
+                cancelled.mark()
+            }
+        }
+    }
+}
+
+/// Record metrics about streams.
+#[cfg(feature="futures")]
+pub trait InstrumentStream: Stream + Sized {
+    /// Count the items of the stream, once it ends or is dropped.
+    fn metered(self, counter: &Counter) -> Metered<Self> {
+        Metered { inner: self, counter: counter.clone(), items: 0, done: false, cancelled: None }
+    }
+
+    /// Count the items of the stream once it ends or is dropped, and mark `cancelled` if it is dropped before its end.
+    fn metered_or_cancelled(self, counter: &Counter, cancelled: &Marker) -> Metered<Self> {
+        Metered { inner: self, counter: counter.clone(), items: 0, done: false, cancelled: Some(cancelled.clone()) }
+    }
+}
+
+#[cfg(feature="futures")]
+impl<S: Stream> InstrumentStream for S {}
+
+/// A stream counting its items.
+#[cfg(feature="futures")]
+#[derive(Debug)]
+pub struct Metered<S> {
+    inner: S,
+    counter: Counter,
+    items: usize,
+    done: bool,
+    cancelled: Option<Marker>,
+}
+
+#[cfg(feature="futures")]
+impl<S: Stream> Stream for Metered<S> {
+    type Item = S::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<S::Item>> {
+        // the wrapped stream is never moved out of its pinned wrapper, including when dropped
+        let this = unsafe { self.get_unchecked_mut() };
+        let poll = unsafe { Pin::new_unchecked(&mut this.inner) }.poll_next(cx);
+        match poll {
+            Poll::Ready(Some(_)) => this.items += 1,
+            Poll::Ready(None) if !this.done => {
+                this.done = true;
+                this.counter.count(this.items);
+            }
+            _ => {}
+        }
+        poll
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(feature="futures")]
+impl<S> Drop for Metered<S> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.counter.count(self.items);
+            if let Some(ref cancelled) = self.cancelled {
+                cancelled.mark()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::clock::ManualClock;
+    use core::input::{InputScope, InputKind};
+    use output::recording::{RecordingInput, Recorded};
+
+    use std::future;
+    use std::task::{RawWaker, RawWakerVTable, Waker};
+    use std::time::Duration;
+
+    fn noop_waker() -> Waker {
+        fn clone(_: *const ()) -> RawWaker { RawWaker::new(::std::ptr::null(), &VTABLE) }
+        fn noop(_: *const ()) {}
+        static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+        unsafe { Waker::from_raw(RawWaker::new(::std::ptr::null(), &VTABLE)) }
+    }
+
+    fn poll<F: Future + Unpin>(future: &mut F) -> Poll<F::Output> {
+        let waker = noop_waker();
+        Pin::new(future).poll(&mut Context::from_waker(&waker))
+    }
+
+    /// A future completing on its second poll.
+    struct YieldOnce(bool);
+
+    impl Future for YieldOnce {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, _cx: &mut Context) -> Poll<()> {
+            if self.0 {
+                Poll::Ready(())
+            } else {
+                self.0 = true;
+                Poll::Pending
+            }
+        }
+    }
+
+    #[test]
+    fn time_futures() {
+        let clock = ManualClock::new();
+        let recording = RecordingInput::new();
+        let latency = recording.timer("latency").with_clock(clock.clone());
+        let scheduling = recording.timer("scheduling").with_clock(clock.clone());
+
+        let mut future = YieldOnce(false).timed(&latency).timed_to_first_poll(&scheduling);
+        clock.advance(Duration::from_millis(2));
+        assert!(poll(&mut future).is_pending());
+        clock.advance(Duration::from_millis(3));
+        assert!(poll(&mut future).is_ready());
+
+        assert_eq!(vec![5000.0], recording.values("latency", InputKind::Timer));
+        assert_eq!(vec![2000.0], recording.values("scheduling", InputKind::Timer));
+    }
+
+    #[test]
+    fn count_and_cancel_futures() {
+        let recording = RecordingInput::new();
+        let done = recording.marker("done");
+        let cancelled = recording.marker("cancelled");
+        let latency = recording.timer("latency");
+
+        let mut ready = future::ready(1).counted_or_cancelled(&done, &cancelled);
+        assert_eq!(Poll::Ready(1), poll(&mut ready));
+        drop(ready);
+
+        let mut pending = YieldOnce(false).counted_or_cancelled(&done, &cancelled);
+        assert!(poll(&mut pending).is_pending());
+        drop(pending);
+        drop(YieldOnce(false).timed_or_cancelled(&latency, &cancelled));
+        drop(YieldOnce(false).counted(&done));
+        drop(YieldOnce(false).timed_to_first_poll_or_cancelled(&latency, &cancelled));
+        let mut polled = YieldOnce(false).timed_to_first_poll_or_cancelled(&latency, &cancelled);
+        assert!(poll(&mut polled).is_pending());
+        drop(polled);
+
+        recording.assert_marker_count("done", 1);
+        recording.assert_marker_count("cancelled", 3);
+        recording.assert_timer_count("latency", 1);
+    }
+
+    #[cfg(feature="futures")]
+    #[test]
+    fn meter_streams() {
+        /// A stream of the remaining items.
+        struct Items(usize);
+
+        impl Stream for Items {
+            type Item = usize;
+
+            fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Option<usize>> {
+                if self.0 == 0 {
+                    return Poll::Ready(None)
+                }
+                self.0 -= 1;
+                Poll::Ready(Some(self.0))
+            }
+        }
+
+        let recording = RecordingInput::new();
+        let items = recording.counter("items");
+        let cancelled = recording.marker("cancelled");
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        let mut stream = Items(3).metered_or_cancelled(&items, &cancelled);
+        while let Poll::Ready(Some(_)) = Pin::new(&mut stream).poll_next(&mut cx) {}
+        drop(stream);
+
+        let mut stream = Items(3).metered_or_cancelled(&items, &cancelled);
+        assert_eq!(Poll::Ready(Some(2)), Pin::new(&mut stream).poll_next(&mut cx));
+        drop(stream);
+
+        assert_eq!(vec![3.0, 1.0], recording.values("items", InputKind::Counter));
+        recording.assert_marker_count("cancelled", 1);
+    }
+}
@@ -1,4 +1,4 @@
-use std::collections::{HashMap};
+use std::collections::{HashMap, BTreeMap};
 use std::sync::{Arc, RwLock};
 use std::cell::RefCell;
 
@@ -45,6 +45,12 @@ impl LabelScope {
         }
     }
 
+    fn collect_into(&self, map: &mut BTreeMap<String, LabelValue>) {
+        if let Some(ref pairs) = self.pairs {
+            map.extend(pairs.iter().map(|(k, v)| (k.clone(), v.clone())))
+        }
+    }
+
     fn get(&self, key: &str) -> Option<LabelValue> {
         // FIXME should use .and_then(), how?
         match &self.pairs {
@@ -152,6 +158,26 @@ impl Labels {
         self.scopes.push(APP_LABELS.read().expect("Global Labels").clone());
     }
 
+    /// Combine these one-off labels with others, which take precedence on duplicate keys.
+    /// Used to apply write-time labels over the labels bound to a metric handle.
+    /// Saved context labels are not supported and must be added after merging.
+    pub fn merge(&self, overrides: Labels) -> Labels {
+        match (self.scopes.first(), overrides.scopes.first()) {
+            (None, _) => overrides,
+            (Some(_), None) => self.clone(),
+            (Some(base), Some(top)) => {
+                let mut pairs = match base.pairs {
+                    Some(ref base_pairs) => base_pairs.as_ref().clone(),
+                    None => HashMap::new(),
+                };
+                if let Some(ref top_pairs) = top.pairs {
+                    pairs.extend(top_pairs.iter().map(|(k, v)| (k.clone(), v.clone())));
+                }
+                pairs.into()
+            }
+        }
+    }
+
     /// Generic label lookup function.
     /// Searches provided labels, provided scopes or default scopes.
     // TODO needs less magic, add checks?
@@ -182,8 +208,22 @@ impl Labels {
             }
         }
     }
-}
 
+    /// Collect all labels visible from these labels, ordered by key.
+    /// Follows the same precedence as `lookup()` for keys defined in multiple scopes.
+    pub fn into_map(self) -> BTreeMap<String, LabelValue> {
+        let mut map = BTreeMap::new();
+        if self.scopes.len() < 2 {
+            // implicit context, overridden by value labels (if any)
+            APP_LABELS.read().expect("Global Labels").collect_into(&mut map);
+            THREAD_LABELS.with(|labels| labels.borrow().collect_into(&mut map));
+        }
+        for scope in self.scopes.iter().rev() {
+            scope.collect_into(&mut map);
+        }
+        map
+    }
+}
 
 #[cfg(test)]
 pub mod test {
@@ -229,4 +269,34 @@ pub mod test {
         assert_eq!(false, labels.lookup("abc").is_some());
     }
 
+    #[test]
+    fn labels_map() {
+        AppLabel::set("map_app", "1");
+        AppLabel::set("map_all", "app");
+        ThreadLabel::set("map_all", "thread");
+        let map = labels!{ "map_value" => "2" }.into_map();
+        assert_eq!(Arc::new("1".into()), map["map_app"]);
+        assert_eq!(Arc::new("2".into()), map["map_value"]);
+        assert_eq!(Arc::new("thread".into()), map["map_all"]);
+
+        let mut saved = labels!{ "map_all" => "value" };
+        saved.save_context();
+        ThreadLabel::unset("map_all");
+        AppLabel::unset("map_all");
+        AppLabel::unset("map_app");
+        let map = saved.into_map();
+        assert_eq!(Arc::new("value".into()), map["map_all"]);
+        assert_eq!(Arc::new("1".into()), map["map_app"]);
+    }
+
+    #[test]
+    fn merged_labels() {
+        let bound = labels!{ "abc" => "123", "xyz" => "456" };
+        let merged = bound.merge(labels!{ "abc" => "789" });
+        assert_eq!(Arc::new("789".into()), merged.lookup("abc").unwrap());
+        assert_eq!(Arc::new("456".into()), merged.lookup("xyz").unwrap());
+        assert_eq!(Arc::new("123".into()), bound.merge(labels![]).lookup("abc").unwrap());
+        assert_eq!(Arc::new("789".into()), labels![].merge(labels!{ "abc" => "789" }).lookup("abc").unwrap());
+    }
+
 }
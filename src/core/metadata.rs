@@ -0,0 +1,105 @@
+//! Describe metrics with a unit and a description, for outputs to convert and document values.
+
+use core::input::InputKind;
+use core::name::MetricName;
+
+use std::sync::Arc;
+
+/// The unit of a metric's values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Unit {
+    /// Time in nanoseconds.
+    Nanoseconds,
+    /// Time in microseconds, the unit of timers unless specified otherwise.
+    Microseconds,
+    /// Time in milliseconds.
+    Milliseconds,
+    /// Time in seconds.
+    Seconds,
+    /// Size in bytes.
+    Bytes,
+    /// Fraction of a whole, from 0 to 100.
+    Percent,
+    /// Fraction of a whole, from 0 to 1.
+    Ratio,
+}
+
+impl Unit {
+    /// The lowercase plural name of the unit, e.g. `seconds`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Unit::Nanoseconds => "nanoseconds",
+            Unit::Microseconds => "microseconds",
+            Unit::Milliseconds => "milliseconds",
+            Unit::Seconds => "seconds",
+            Unit::Bytes => "bytes",
+            Unit::Percent => "percent",
+            Unit::Ratio => "ratio",
+        }
+    }
+
+    /// The length of a time unit in nanoseconds, None for other units.
+    pub fn nanoseconds(self) -> Option<u64> {
+        match self {
+            Unit::Nanoseconds => Some(1),
+            Unit::Microseconds => Some(1_000),
+            Unit::Milliseconds => Some(1_000_000),
+            Unit::Seconds => Some(1_000_000_000),
+            _ => None,
+        }
+    }
+}
+
+/// Optional information about a metric, carried along with its name.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Metadata {
+    /// The unit of the metric's values.
+    pub unit: Option<Unit>,
+    /// What the metric measures, e.g. to be published as help text.
+    pub description: Option<Arc<str>>,
+}
+
+impl Metadata {
+    /// Returns true if no metadata was specified.
+    pub fn is_empty(&self) -> bool {
+        self.unit.is_none() && self.description.is_none()
+    }
+
+    /// The unit of a metric's values, timers being in microseconds unless specified otherwise.
+    pub fn effective_unit(&self, kind: InputKind) -> Option<Unit> {
+        match (self.unit, kind) {
+            (Some(unit), _) => Some(unit),
+            (None, InputKind::Timer) => Some(Unit::Microseconds),
+            (None, _) => None,
+        }
+    }
+}
+
+/// The divisor converting the metric's values to the target time unit.
+/// Values of metrics not measuring time are not converted.
+pub fn time_scale(name: &MetricName, kind: InputKind, target: Unit) -> f64 {
+    let source = name.metadata().effective_unit(kind).and_then(Unit::nanoseconds);
+    match (source, target.nanoseconds()) {
+        (Some(source), Some(target)) => target as f64 / source as f64,
+        _ => 1.0,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn convert_time_units() {
+        let timer = MetricName::from("latency");
+        assert_eq!(1000.0, time_scale(&timer, InputKind::Timer, Unit::Milliseconds));
+        assert_eq!(1.0, time_scale(&timer, InputKind::Gauge, Unit::Milliseconds));
+
+        let seconds = MetricName::from("uptime").unit(Unit::Seconds);
+        assert_eq!(0.001, time_scale(&seconds, InputKind::Gauge, Unit::Milliseconds));
+        assert_eq!(1.0, time_scale(&seconds, InputKind::Timer, Unit::Seconds));
+
+        let bytes = MetricName::from("received").unit(Unit::Bytes);
+        assert_eq!(1.0, time_scale(&bytes, InputKind::Counter, Unit::Seconds));
+    }
+}
@@ -10,10 +10,27 @@ metrics!{
     /// Dipstick's own internal metrics.
     pub DIPSTICK_METRICS = "dipstick" => {
 
+        "bucket" => {
+            pub BUCKET_LABEL_OVERFLOW: Marker = "label_overflow";
+            pub BUCKET_KIND_CONFLICT: Marker = "kind_conflict";
+        }
+
+        "proxy" => {
+            pub PROXY_KIND_CONFLICT: Marker = "kind_conflict";
+        }
+
         "queue" => {
             pub SEND_FAILED: Marker = "send_failed";
         }
 
+        "retry" => {
+            pub RETRY_QUEUED_BYTES: Counter = "queued_bytes";
+            pub RETRY_REPLAYED_BYTES: Counter = "replayed_bytes";
+            pub RETRY_DROPPED_BYTES: Counter = "dropped_bytes";
+            pub RETRY_SPOOL_ERR: Marker = "spool_failed";
+            pub RETRY_SEND_ERR: Marker = "send_failed";
+        }
+
         "graphite" => {
             pub GRAPHITE_SEND_ERR: Marker = "send_failed";
             pub GRAPHITE_OVERFLOW: Marker = "buf_overflow";
@@ -24,5 +41,16 @@ metrics!{
             pub STATSD_SEND_ERR: Marker ="send_failed";
             pub STATSD_SENT_BYTES: Counter = "sent_bytes";
         }
+
+        "influx" => {
+            pub INFLUX_SEND_ERR: Marker = "send_failed";
+            pub INFLUX_OVERFLOW: Marker = "buf_overflow";
+            pub INFLUX_SENT_BYTES: Counter = "sent_bytes";
+        }
+
+        "prometheus" => {
+            pub PROMETHEUS_SEND_ERR: Marker = "send_failed";
+            pub PROMETHEUS_SENT_BYTES: Counter = "sent_bytes";
+        }
     }
 }
@@ -1,5 +1,6 @@
 pub mod error;
 pub mod name;
+pub mod metadata;
 pub mod attributes;
 pub mod input;
 pub mod output;
@@ -7,13 +8,18 @@ pub mod out_lock;
 pub mod clock;
 pub mod void;
 pub mod proxy;
+pub mod catalog;
+pub mod conflict;
 pub mod label;
 pub mod pcg32;
+pub mod sampler;
 pub mod scheduler;
+pub mod instrument;
 pub mod metrics;
 
 /// Base type for recorded metric values.
-pub type MetricValue = isize;
+/// Integers are carried exactly up to 2^53, fractions such as ratios or dollar amounts are kept as is.
+pub type MetricValue = f64;
 
 /// Both InputScope and OutputScope share the ability to flush the recorded data.
 pub trait Flush {
@@ -32,7 +38,7 @@ pub mod test {
     fn test_to_void() {
         let c = void::Void::metrics().input();
         let m = c.new_metric("test".into(), input::InputKind::Marker);
-        m.write(33, labels![]);
+        m.write(33.0, labels![]);
     }
 }
 
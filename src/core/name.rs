@@ -1,5 +1,9 @@
+use core::metadata::{Metadata, Unit};
+
 use std::ops::{Deref,DerefMut};
 use std::collections::{VecDeque};
+use std::cmp::Ordering;
+use std::hash::{Hash, Hasher};
 
 /// A double-ended vec of strings constituting a metric name or a future part thereof.
 #[derive(Debug, Clone, Hash, Eq, PartialEq, Ord, PartialOrd, Default)]
@@ -31,7 +35,7 @@ impl NameParts {
     pub fn make_name<S: Into<String>>(&self, leaf: S) -> MetricName {
         let mut nodes = self.clone();
         nodes.push_back(leaf.into());
-        MetricName { nodes }
+        MetricName { nodes, metadata: Metadata::default() }
     }
 
     /// Extract a copy of the last name part
@@ -39,6 +43,11 @@ impl NameParts {
     pub fn short(&self) -> MetricName {
         self.back().expect("Short metric name").clone().into()
     }
+
+    /// Join name parts with the separator.
+    pub fn join(&self, separator: &str) -> String {
+        self.nodes.iter().map(|s| &**s).collect::<Vec<&str>>().join(separator)
+    }
 }
 
 /// Turn any string into a StringDeque
@@ -69,9 +78,11 @@ impl DerefMut for NameParts {
 }
 
 /// The name of a metric, including the concatenated possible namespaces in which it was defined.
-#[derive(Debug, Clone, Hash, Eq, PartialEq, Ord, PartialOrd)]
+/// The name also carries the metric's metadata, which is not part of its identity.
+#[derive(Debug, Clone)]
 pub struct MetricName {
     nodes: NameParts,
+    metadata: Metadata,
 }
 
 impl MetricName {
@@ -97,13 +108,62 @@ impl MetricName {
 
     /// Combine name parts into a string.
     pub fn join(&self, separator: &str) -> String {
-        self.nodes.iter().map(|s| &**s).collect::<Vec<&str>>().join(separator)
+        self.nodes.join(separator)
+    }
+
+    /// Specify the unit of the metric's values.
+    pub fn unit(mut self, unit: Unit) -> Self {
+        self.metadata.unit = Some(unit);
+        self
+    }
+
+    /// Describe what the metric measures.
+    pub fn description<S: AsRef<str>>(mut self, description: S) -> Self {
+        self.metadata.description = Some(description.as_ref().into());
+        self
+    }
+
+    /// Replace the metric's metadata.
+    pub fn with_metadata(mut self, metadata: Metadata) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    /// The metric's unit and description, if specified.
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
     }
 }
 
 impl<S: Into<String>> From<S> for MetricName {
     fn from(name: S) -> Self {
-        MetricName { nodes: NameParts::from(name) }
+        MetricName { nodes: NameParts::from(name), metadata: Metadata::default() }
+    }
+}
+
+impl PartialEq for MetricName {
+    fn eq(&self, other: &MetricName) -> bool {
+        self.nodes == other.nodes
+    }
+}
+
+impl Eq for MetricName {}
+
+impl Hash for MetricName {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.nodes.hash(state)
+    }
+}
+
+impl PartialOrd for MetricName {
+    fn partial_cmp(&self, other: &MetricName) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for MetricName {
+    fn cmp(&self, other: &MetricName) -> Ordering {
+        self.nodes.cmp(&other.nodes)
     }
 }
 
@@ -1,5 +1,5 @@
 use core::input::{InputScope, InputMetric, Input, InputKind};
-use core::output::{Output, OutputScope};
+use core::output::{Output, OutputScope, OutputMetric};
 use core::attributes::{Attributes, WithAttributes, Prefixed};
 use core::name::MetricName;
 use core::Flush;
@@ -26,10 +26,12 @@ impl InputScope for LockingScopeBox {
     fn new_metric(&self, name: MetricName, kind: InputKind) -> InputMetric {
         let name = self.prefix_append(name);
         let raw_metric = self.inner.lock().expect("RawScope Lock").new_metric(name, kind);
-        let mutex = self.inner.clone();
-        InputMetric::new(move |value, labels| {
-            let _guard = mutex.lock().expect("OutputMetric Lock");
-            raw_metric.write(value, labels)
+        let metric = LockedMetric { metric: Some(raw_metric), lock: self.inner.clone() };
+        InputMetric::new_sampled(move |value, labels, rate| {
+            let _guard = metric.lock.lock().expect("OutputMetric Lock");
+            if let Some(ref raw_metric) = metric.metric {
+                raw_metric.write_sampled(value, labels, rate)
+            }
         } )
     }
 
@@ -68,3 +70,17 @@ impl ops::Deref for LockScope {
 unsafe impl Send for LockScope {}
 unsafe impl Sync for LockScope {}
 
+/// An output metric that is written to and dropped only while holding its scope's lock,
+/// as it may share state with the scope, e.g. a buffer that is flushed on drop.
+struct LockedMetric {
+    metric: Option<OutputMetric>,
+    lock: Arc<Mutex<LockScope>>,
+}
+
+impl Drop for LockedMetric {
+    fn drop(&mut self) {
+        let _guard = self.lock.lock().expect("OutputMetric Lock");
+        self.metric = None;
+    }
+}
+
@@ -5,6 +5,7 @@ use core::void::Void;
 use core::label::Labels;
 
 use std::rc::Rc;
+use std::time::SystemTime;
 
 /// Define metrics, write values and flush them.
 pub trait OutputScope: Flush {
@@ -17,12 +18,30 @@ pub trait OutputScope: Flush {
 /// Output metrics are not thread safe.
 #[derive(Clone)]
 pub struct OutputMetric {
-    inner: Rc<Fn(MetricValue, Labels)>
+    inner: Rc<dyn Fn(MetricValue, Labels, Option<SystemTime>, f64)>
 }
 
 impl OutputMetric {
     /// Utility constructor
+    /// Values written with an explicit timestamp are handled as if they had been written now.
+    /// The sampling rate of values is ignored.
     pub fn new<F: Fn(MetricValue, Labels) + 'static>(metric: F) -> OutputMetric {
+        OutputMetric { inner: Rc::new(move |value, labels, _timestamp, _rate| metric(value, labels)) }
+    }
+
+    /// Constructor for metrics honoring the timestamp values are written with.
+    /// A `None` timestamp stands for the current time.
+    pub fn new_timed<F: Fn(MetricValue, Labels, Option<SystemTime>) + 'static>(metric: F) -> OutputMetric {
+        OutputMetric { inner: Rc::new(move |value, labels, timestamp, _rate| metric(value, labels, timestamp)) }
+    }
+
+    /// Constructor for metrics publishing the rate at which values were sampled upstream, e.g. statsd's `|@rate`.
+    pub fn new_sampled<F: Fn(MetricValue, Labels, f64) + 'static>(metric: F) -> OutputMetric {
+        OutputMetric { inner: Rc::new(move |value, labels, _timestamp, rate| metric(value, labels, rate)) }
+    }
+
+    /// Constructor for metrics passing both the timestamp and the sampling rate of values on to other metrics.
+    pub fn new_forwarding<F: Fn(MetricValue, Labels, Option<SystemTime>, f64) + 'static>(metric: F) -> OutputMetric {
         OutputMetric { inner: Rc::new(metric) }
     }
 
@@ -30,7 +49,27 @@ impl OutputMetric {
     /// This shouldn't matter as metrics should be of type Counter, Marker, etc.
     #[inline]
     pub fn write(&self, value: MetricValue, labels: Labels) {
-        (self.inner)(value, labels)
+        (self.inner)(value, labels, None, 1.0)
+    }
+
+    /// Write a value observed at the specified time, e.g. the end of an aggregation period.
+    /// A `None` timestamp stands for the current time.
+    #[inline]
+    pub fn write_timed(&self, value: MetricValue, labels: Labels, timestamp: Option<SystemTime>) {
+        (self.inner)(value, labels, timestamp, 1.0)
+    }
+
+    /// Write a value recorded at the specified sampling rate.
+    /// A value sampled at 0.25 stands for four values.
+    #[inline]
+    pub fn write_sampled(&self, value: MetricValue, labels: Labels, rate: f64) {
+        (self.inner)(value, labels, None, rate)
+    }
+
+    /// Write a value with both its timestamp and sampling rate, e.g. as received by a forwarding metric.
+    #[inline]
+    pub fn forward(&self, value: MetricValue, labels: Labels, timestamp: Option<SystemTime>, rate: f64) {
+        (self.inner)(value, labels, timestamp, rate)
     }
 }
 
@@ -60,4 +99,4 @@ impl<T: Output + Send + Sync + 'static> OutputDyn for T {
 /// Discard all metric values sent to it.
 pub fn output_none() -> Void {
     Void {}
-}
\ No newline at end of file
+}
\ No newline at end of file
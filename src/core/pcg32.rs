@@ -42,6 +42,11 @@ pub fn to_int_rate(float_rate: f64) -> u32 {
     ((1.0 - float_rate) * ::std::u32::MAX as f64) as u32
 }
 
+/// A random fraction, from 0.0 included to 1.0 excluded.
+pub fn random_fraction() -> f64 {
+    f64::from(pcg32_random()) / (f64::from(u32::MAX) + 1.0)
+}
+
 /// randomly select samples based on an int rate
 pub fn accept_sample(int_rate: u32) -> bool {
     pcg32_random() > int_rate
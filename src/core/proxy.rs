@@ -1,9 +1,14 @@
 //! Decouple metric definition from configuration with trait objects.
 
-use core::attributes::{Attributes, WithAttributes, Prefixed};
+use core::attributes::{Attributes, WithAttributes, Prefixed, Sampled};
 use core::name::{MetricName, NameParts};
+use core::metadata::Metadata;
 use core::Flush;
 use core::input::{InputKind, InputMetric, InputScope};
+use core::catalog::{Catalog, MetricInfo};
+use core::conflict::{OnKindConflict, KindConflict, KindConflicts, Definition, suffix_kind};
+use core::metrics;
+use core::sampler::Sampler;
 use core::void::VOID_INPUT;
 use core::error;
 
@@ -27,6 +32,7 @@ struct ProxyMetric {
     // basic info for this metric, needed to recreate new corresponding trait object if target changes
     name: NameParts,
     kind: InputKind,
+    metadata: Metadata,
 
     // the metric trait object to proxy metric values to
     // the second part can be up to namespace.len() + 1 if this metric was individually targeted
@@ -37,10 +43,17 @@ struct ProxyMetric {
     proxy: Arc<RwLock<InnerProxy>>,
 }
 
+impl ProxyMetric {
+    /// The name under which the metric is defined in its target.
+    fn target_name(&self) -> MetricName {
+        self.name.short().with_metadata(self.metadata.clone())
+    }
+}
+
 /// Dispatcher weak ref does not prevent dropping but still needs to be cleaned out.
 impl Drop for ProxyMetric {
     fn drop(&mut self) {
-        self.proxy.write().expect("Dispatch Lock").drop_metric(&self.name)
+        self.proxy.write().expect("Dispatch Lock").drop_metric(&self.name, self.kind)
     }
 }
 
@@ -66,12 +79,15 @@ struct InnerProxy {
     // namespaces can target one, many or no metrics
     targets: HashMap<NameParts, Arc<InputScope + Send + Sync>>,
     // last part of the namespace is the metric's name
-    metrics: BTreeMap<NameParts, Weak<ProxyMetric>>,
+    // metrics of different kinds may share a name, depending on the kind conflict policy
+    metrics: BTreeMap<(NameParts, InputKind), Weak<ProxyMetric>>,
+    kind_conflicts: KindConflicts,
 }
 
 impl fmt::Debug for InnerProxy {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "metrics: {:?}", self.metrics.keys())?;
+        write!(f, "kind_conflicts: {:?}", self.kind_conflicts)?;
         write!(f, "targets: {:?}", self.targets.keys())
     }
 }
@@ -82,13 +98,14 @@ impl InnerProxy {
         Self {
             targets: HashMap::new(),
             metrics: BTreeMap::new(),
+            kind_conflicts: KindConflicts::default(),
         }
     }
 
     fn set_target(&mut self, namespace: &NameParts, target_scope: Arc<InputScope + Send + Sync>) {
         self.targets.insert(namespace.clone(), target_scope.clone());
 
-        for (metric_name, metric) in self.metrics.range_mut(namespace.clone()..) {
+        for (&(ref metric_name, _kind), metric) in self.metrics.range_mut((namespace.clone(), InputKind::Marker)..) {
             if let Some(metric) = metric.upgrade() {
                 // check for range end
                 if !metric_name.is_within(namespace) { break }
@@ -96,7 +113,7 @@ impl InnerProxy {
                 // check if metric targeted by _lower_ namespace
                 if metric.target.borrow().1 > namespace.len() { continue }
 
-                let target_metric = target_scope.new_metric(metric.name.short(), metric.kind);
+                let target_metric = target_scope.new_metric(metric.target_name(), metric.kind);
                 *metric.target.borrow_mut() = (target_metric, namespace.len());
             }
         }
@@ -127,7 +144,7 @@ impl InnerProxy {
             .unwrap_or_else(|| (VOID_INPUT.input_dyn(), 0));
 
         // update all affected metrics to next upper targeted namespace
-        for (name, metric) in self.metrics.range_mut(namespace.clone()..) {
+        for (&(ref name, _kind), metric) in self.metrics.range_mut((namespace.clone(), InputKind::Marker)..) {
             // check for range end
             if !name.is_within(namespace) { break }
 
@@ -135,18 +152,57 @@ impl InnerProxy {
                 // check if metric targeted by _lower_ namespace
                 if metric.target.borrow().1 > namespace.len() { continue }
 
-                let new_metric = up_target.new_metric(name.short(), metric.kind);
+                let new_metric = up_target.new_metric(metric.target_name(), metric.kind);
                 *metric.target.borrow_mut() = (new_metric, up_nslen);
             }
         }
     }
 
-    fn drop_metric(&mut self, name: &NameParts) {
-        if self.metrics.remove(name).is_none() {
+    /// The kinds of the live metrics defined under the name.
+    fn defined_kinds(&self, name: &NameParts) -> Vec<InputKind> {
+        self.metrics.range((name.clone(), InputKind::Marker)..=(name.clone(), InputKind::Timer))
+            .filter(|&(_, metric)| metric.upgrade().is_some())
+            .map(|(&(_, kind), _)| kind)
+            .collect()
+    }
+
+    fn drop_metric(&mut self, name: &NameParts, kind: InputKind) {
+        if self.metrics.remove(&(name.clone(), kind)).is_none() {
             panic!("Could not remove DelegatingMetric weak ref from delegation point")
         }
     }
 
+    /// Describe the live metrics within the namespace, with the namespace of their current target.
+    fn list_metrics(&self, namespace: &NameParts) -> Vec<MetricInfo> {
+        self.metrics.range((namespace.clone(), InputKind::Marker)..)
+            .take_while(|&((name, _), _)| name.is_within(namespace))
+            .filter_map(|((name, _), metric)| metric.upgrade().map(|metric| {
+                let target = self.get_effective_target(name).map(|(_target, nslen)| {
+                    let mut target_namespace = name.clone();
+                    target_namespace.truncate(nslen);
+                    target_namespace
+                });
+                MetricInfo {
+                    name: name.clone(),
+                    kind: metric.kind,
+                    unit: metric.metadata.effective_unit(metric.kind),
+                    description: metric.metadata.description.clone(),
+                    target,
+                }
+            }))
+            .collect()
+    }
+
+    /// The namespaces within the namespace that have a target.
+    fn list_targets(&self, namespace: &NameParts) -> Vec<NameParts> {
+        let mut targets: Vec<NameParts> = self.targets.keys()
+            .filter(|target| target.is_within(namespace))
+            .cloned()
+            .collect();
+        targets.sort();
+        targets
+    }
+
     fn flush(&self, namespace: &NameParts) -> error::Result<()> {
         if let Some((target, _nslen)) = self.get_effective_target(namespace) {
             target.flush()
@@ -193,6 +249,37 @@ impl Proxy {
         ROOT_PROXY.unset_target()
     }
 
+    /// Set the policy applied when a metric is defined again with a different kind.
+    /// The policy applies to the whole proxy tree this proxy is part of.
+    pub fn set_kind_conflict(&self, policy: OnKindConflict) {
+        self.inner.write().expect("Dispatch Lock").kind_conflicts.set_policy(policy)
+    }
+
+    /// The distinct kind conflicts met by the proxy tree this proxy is part of.
+    pub fn kind_conflicts(&self) -> Vec<KindConflict> {
+        self.inner.read().expect("Dispatch Lock").kind_conflicts.list()
+    }
+
+    /// List the live metrics defined in this proxy's namespace, sorted by name.
+    /// Each metric comes with its kind and the namespace of the target its values are currently sent to.
+    pub fn list_metrics(&self) -> Vec<MetricInfo> {
+        self.inner.read().expect("Dispatch Lock").list_metrics(self.get_prefixes())
+    }
+
+    /// List the namespaces having a target in this proxy's namespace, sorted.
+    pub fn list_targets(&self) -> Vec<NameParts> {
+        self.inner.read().expect("Dispatch Lock").list_targets(self.get_prefixes())
+    }
+
+    /// Describe the metrics and targets of this proxy's namespace.
+    pub fn catalog(&self) -> Catalog {
+        let inner = self.inner.read().expect("Dispatch Lock");
+        Catalog {
+            metrics: inner.list_metrics(self.get_prefixes()),
+            targets: inner.list_targets(self.get_prefixes()),
+        }
+    }
+
 }
 
 impl<S: AsRef<str>> From<S> for Proxy {
@@ -204,12 +291,26 @@ impl<S: AsRef<str>> From<S> for Proxy {
 impl InputScope for Proxy {
     /// Lookup or create a proxy stub for the requested metric.
     fn new_metric(&self, name: MetricName, kind: InputKind) -> InputMetric {
-        let name: MetricName = self.prefix_append(name);
+        let mut name: MetricName = self.prefix_append(name);
         let mut inner = self.inner.write().expect("Dispatch Lock");
+        let existing = inner.defined_kinds(&name);
+        let mut definition = inner.kind_conflicts.check(&name, kind, &existing);
+        if definition == Definition::Renamed {
+            suffix_kind(&mut name, kind);
+            let existing = inner.defined_kinds(&name);
+            definition = inner.kind_conflicts.check_renamed(&name, kind, &existing);
+        }
+        match definition {
+            Definition::Named | Definition::Separated | Definition::Renamed => {},
+            Definition::Rejected => {
+                drop(inner);
+                metrics::PROXY_KIND_CONFLICT.mark();
+                return InputMetric::new(|_value, _labels| {})
+            }
+        }
         let proxy = inner
             .metrics
-            .get(&name)
-            // TODO validate that InputKind matches existing
+            .get(&((*name).clone(), kind))
             .and_then(|proxy_ref| Weak::upgrade(proxy_ref))
             .unwrap_or_else(|| {
                 let namespace = &*name;
@@ -217,18 +318,35 @@ impl InputScope for Proxy {
                     // not found, define new
                     let (target, target_namespace_length) = inner.get_effective_target(namespace)
                         .unwrap_or_else(|| (VOID_INPUT.input_dyn(), 0));
-                    let metric_object = target.new_metric(namespace.short(), kind);
+                    let metric_object = target.new_metric(namespace.short().with_metadata(name.metadata().clone()), kind);
                     let proxy = Arc::new(ProxyMetric {
                         name: namespace.clone(),
                         kind,
+                        metadata: name.metadata().clone(),
                         target: AtomicRefCell::new((metric_object, target_namespace_length)),
                         proxy: self.inner.clone(),
                     });
-                    inner.metrics.insert(namespace.clone(), Arc::downgrade(&proxy));
+                    inner.metrics.insert((namespace.clone(), kind), Arc::downgrade(&proxy));
                     proxy
                 }
             });
-        InputMetric::new(move |value, labels| proxy.target.borrow().0.write(value, labels))
+        drop(inner);
+        if definition != Definition::Named {
+            metrics::PROXY_KIND_CONFLICT.mark();
+        }
+        match self.get_sampling() {
+            Some(sampling) => {
+                let sampler = Sampler::new(&sampling);
+                InputMetric::new_sampled(move |value, labels, rate| {
+                    if let Some(sampled) = sampler.sample(&labels) {
+                        proxy.target.borrow().0.write_sampled(value, labels, rate * sampled)
+                    }
+                })
+            }
+            None => InputMetric::new_sampled(move |value, labels, rate| {
+                proxy.target.borrow().0.write_sampled(value, labels, rate)
+            }),
+        }
     }
 }
 
@@ -244,6 +362,127 @@ impl WithAttributes for Proxy {
     fn mut_attributes(&mut self) -> &mut Attributes { &mut self.attributes }
 }
 
+impl Sampled for Proxy {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bucket::atomic::AtomicBucket;
+    use bucket::stats_all;
+    use core::MetricValue;
+    use core::metadata::Unit;
+    use core::attributes::Sampling;
+    use output::map::StatsMap;
+
+    fn name(parts: &[&str]) -> NameParts {
+        let mut name = NameParts::default();
+        for part in parts {
+            name.push_back(part.to_string());
+        }
+        name
+    }
+
+    #[test]
+    fn list_metrics_and_targets() {
+        let proxy = Proxy::new();
+        let app = proxy.add_prefix("app");
+        let requests = app.new_metric(MetricName::from("requests").description("Requests served"), InputKind::Counter);
+        let latency = app.add_prefix("db").timer("latency");
+        let _other = proxy.marker("other");
+
+        app.set_target(AtomicBucket::new());
+        app.add_prefix("db").add_prefix("latency").set_target(AtomicBucket::new());
+
+        assert_eq!(vec![name(&["app"]), name(&["app", "db", "latency"])], proxy.list_targets());
+        assert_eq!(vec![
+            MetricInfo { name: name(&["app", "db", "latency"]), kind: InputKind::Timer, unit: Some(Unit::Microseconds),
+                description: None, target: Some(name(&["app", "db", "latency"])) },
+            MetricInfo { name: name(&["app", "requests"]), kind: InputKind::Counter, unit: None,
+                description: Some("Requests served".into()), target: Some(name(&["app"])) },
+            MetricInfo { name: name(&["other"]), kind: InputKind::Marker, unit: None, description: None, target: None },
+        ], proxy.list_metrics());
+
+        // metrics and targets listed from a namespace are within it
+        assert_eq!(2, app.list_metrics().len());
+        assert_eq!(1, app.add_prefix("db").list_targets().len());
+
+        // dropped metrics are no longer listed
+        drop(latency);
+        drop(requests);
+        let catalog = proxy.catalog();
+        assert_eq!(1, catalog.metrics.len());
+        assert_eq!(2, catalog.targets.len());
+    }
+
+    #[test]
+    fn kind_conflict_policies() {
+        let proxy = Proxy::new();
+        let bucket = AtomicBucket::new();
+        proxy.set_target(bucket.clone());
+
+        let _counter = proxy.counter("a");
+        let renamed = proxy.timer("a");
+        renamed.interval_us(10);
+        assert_eq!(vec![
+            KindConflict { name: name(&["a"]), existing: InputKind::Counter, requested: InputKind::Timer },
+        ], proxy.kind_conflicts());
+
+        proxy.set_kind_conflict(OnKindConflict::Separate);
+        let _gauge = proxy.gauge("a");
+
+        proxy.set_kind_conflict(OnKindConflict::Reject);
+        proxy.marker("a").mark();
+
+        let kinds: Vec<(NameParts, InputKind)> = proxy.list_metrics().into_iter()
+            .map(|metric| (metric.name, metric.kind))
+            .collect();
+        assert_eq!(vec![
+            (name(&["a"]), InputKind::Counter),
+            (name(&["a"]), InputKind::Gauge),
+            (name(&["a_timer"]), InputKind::Timer),
+        ], kinds);
+        assert_eq!(3, proxy.kind_conflicts().len());
+    }
+
+    #[test]
+    fn renamed_kind_conflict() {
+        let proxy = Proxy::new();
+        let _gauge = proxy.gauge("a_timer");
+        let _counter = proxy.counter("a");
+        let _timer = proxy.timer("a");
+
+        let kinds: Vec<(NameParts, InputKind)> = proxy.list_metrics().into_iter()
+            .map(|metric| (metric.name, metric.kind))
+            .collect();
+        assert_eq!(vec![
+            (name(&["a"]), InputKind::Counter),
+            (name(&["a_timer"]), InputKind::Gauge),
+        ], kinds);
+        assert_eq!(vec![
+            KindConflict { name: name(&["a"]), existing: InputKind::Counter, requested: InputKind::Timer },
+            KindConflict { name: name(&["a_timer"]), existing: InputKind::Gauge, requested: InputKind::Timer },
+        ], proxy.kind_conflicts());
+    }
+
+    #[test]
+    fn sampled_values_reach_target_scaled() {
+        let proxy = Proxy::new();
+        let bucket = AtomicBucket::new().sampled(Sampling::EveryNth(2));
+        proxy.set_target(bucket.clone());
+
+        // a value sampled by both the proxy and the bucket stands for four
+        let marker = proxy.sampled(Sampling::EveryNth(2)).marker("a");
+        for _ in 0..8 {
+            marker.mark();
+        }
+
+        let stats = StatsMap::default();
+        bucket.flush_now_to(&stats, &stats_all).unwrap();
+        let map: BTreeMap<String, MetricValue> = stats.into();
+        assert_eq!(map["a.count"], 8.0);
+    }
+}
+
 #[cfg(feature = "bench")]
 mod bench {
 
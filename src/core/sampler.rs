@@ -0,0 +1,180 @@
+//! Select which values of a metric are recorded, according to its sampling strategy.
+
+use core::attributes::Sampling;
+use core::clock::TimeHandle;
+use core::label::Labels;
+use core::pcg32;
+
+use std::sync::atomic::{AtomicUsize, AtomicU64};
+use std::sync::atomic::Ordering::*;
+
+/// The sampling state of a single metric.
+/// Thread safe, so that values can be skipped before any locking.
+#[derive(Debug)]
+pub struct Sampler {
+    sampling: Sampling,
+    rate: f64,
+    int_rate: u32,
+    count: AtomicUsize,
+    window: Option<RateWindow>,
+}
+
+/// Counts of values offered and recorded during the current second, for max rate sampling.
+#[derive(Debug)]
+struct RateWindow {
+    start: TimeHandle,
+    second: AtomicUsize,
+    offered: AtomicUsize,
+    recorded: AtomicUsize,
+    // f64 bits of the previous second's recorded ratio
+    last_rate: AtomicU64,
+}
+
+impl Sampler {
+    /// Prepare sampling a metric's values with the strategy.
+    /// Panics if the sampling rate is NaN.
+    pub fn new(sampling: &Sampling) -> Sampler {
+        let rate = match *sampling {
+            Sampling::Random(rate) | Sampling::LabelHash(_, rate) => {
+                assert!(!rate.is_nan(), "Sampling rate is NaN");
+                rate.clamp(0.0, 1.0)
+            }
+            _ => 1.0,
+        };
+        let int_rate = pcg32::to_int_rate(rate);
+        let window = match *sampling {
+            Sampling::MaxRate(_) => Some(RateWindow {
+                start: TimeHandle::now(),
+                second: AtomicUsize::new(0),
+                offered: AtomicUsize::new(0),
+                recorded: AtomicUsize::new(0),
+                last_rate: AtomicU64::new(1.0f64.to_bits()),
+            }),
+            _ => None,
+        };
+        Sampler {
+            sampling: *sampling,
+            rate,
+            int_rate,
+            count: AtomicUsize::new(0),
+            window,
+        }
+    }
+
+    /// Returns the rate at which the value is sampled if it should be recorded, or None if it should be dropped.
+    pub fn sample(&self, labels: &Labels) -> Option<f64> {
+        match self.sampling {
+            Sampling::Random(_) => if pcg32::accept_sample(self.int_rate) { Some(self.rate) } else { None },
+            Sampling::EveryNth(n) => {
+                let n = n.max(1);
+                if self.count.fetch_add(1, Relaxed).is_multiple_of(n) { Some(1.0 / n as f64) } else { None }
+            }
+            Sampling::MaxRate(max) => self.window.as_ref().and_then(|window| window.sample(max)),
+            Sampling::LabelHash(key, _) => {
+                let accept = match labels.lookup(key) {
+                    Some(value) => (label_hash(value.as_bytes()) >> 32) as u32 > self.int_rate,
+                    None => pcg32::accept_sample(self.int_rate),
+                };
+                if accept { Some(self.rate) } else { None }
+            }
+        }
+    }
+}
+
+impl RateWindow {
+    fn sample(&self, max: usize) -> Option<f64> {
+        let second = self.start.elapsed_us() as usize / 1_000_000;
+        let current = self.second.load(Relaxed);
+        if second != current && self.second.compare_exchange(current, second, AcqRel, Relaxed).is_ok() {
+            let offered = self.offered.swap(0, AcqRel);
+            let recorded = self.recorded.swap(0, AcqRel);
+            let rate = if offered == 0 || second != current + 1 { 1.0 } else { recorded as f64 / offered as f64 };
+            self.last_rate.store(rate.to_bits(), Release);
+        }
+        self.offered.fetch_add(1, Relaxed);
+        if self.recorded.fetch_add(1, AcqRel) < max {
+            Some(f64::from_bits(self.last_rate.load(Acquire)))
+        } else {
+            // undo, keeping the recorded count exact
+            self.recorded.fetch_sub(1, AcqRel);
+            None
+        }
+    }
+}
+
+/// FNV-1a hash, stable across processes and platforms.
+/// Bits are then mixed so that short, similar values (e.g. sequential ids) spread evenly.
+fn label_hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in bytes {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::clock::{mock_clock_advance, mock_clock_reset};
+
+    use std::time::Duration;
+
+    fn recorded(sampler: &Sampler, values: usize) -> Vec<f64> {
+        (0..values).filter_map(|_| sampler.sample(&labels![])).collect()
+    }
+
+    #[test]
+    fn every_nth() {
+        let sampler = Sampler::new(&Sampling::EveryNth(4));
+        assert_eq!(vec![0.25, 0.25, 0.25], recorded(&sampler, 10));
+    }
+
+    #[test]
+    fn max_rate() {
+        mock_clock_reset();
+        let sampler = Sampler::new(&Sampling::MaxRate(10));
+        assert_eq!(vec![1.0; 10], recorded(&sampler, 40));
+
+        // the rate reported during a second is the previous second's
+        mock_clock_advance(Duration::from_secs(1));
+        assert_eq!(vec![0.25; 10], recorded(&sampler, 20));
+        mock_clock_advance(Duration::from_secs(1));
+        assert_eq!(vec![0.5; 5], recorded(&sampler, 5));
+
+        // after an idle second, values are all recorded again
+        mock_clock_advance(Duration::from_secs(2));
+        assert_eq!(vec![1.0], recorded(&sampler, 1));
+    }
+
+    #[test]
+    fn label_hash_is_consistent() {
+        let sampler = Sampler::new(&Sampling::LabelHash("request_id", 0.5));
+        let other = Sampler::new(&Sampling::LabelHash("request_id", 0.5));
+        let mut kept = 0;
+        for i in 0..1000 {
+            let labels = labels!{ "request_id" => i.to_string() };
+            let sampled = sampler.sample(&labels);
+            assert_eq!(sampled, other.sample(&labels));
+            assert_eq!(sampled, sampler.sample(&labels));
+            if sampled.is_some() { kept += 1 }
+        }
+        assert!(kept > 400 && kept < 600, "{}", kept);
+    }
+
+    #[test]
+    fn out_of_range_rates() {
+        assert_eq!(vec![1.0; 5], recorded(&Sampler::new(&Sampling::Random(f64::INFINITY)), 5));
+        assert!(recorded(&Sampler::new(&Sampling::LabelHash("id", f64::NEG_INFINITY)), 5).is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "Sampling rate is NaN")]
+    fn reject_nan_rate() {
+        Sampler::new(&Sampling::Random(f64::NAN));
+    }
+}
@@ -1,65 +1,535 @@
 //! Task scheduling facilities.
+//! All scheduled tasks run from a single, shared scheduler thread.
 
 use core::input::InputScope;
+use core::pcg32;
 
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::thread;
-use std::sync::Arc;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex, MutexGuard, Condvar};
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering::SeqCst;
+use std::cell::Cell;
+
+#[cfg(feature="tokio")]
+use tokio::runtime::Handle;
+#[cfg(feature="tokio")]
+use tokio::time::{self as tokio_time, Sleep};
+#[cfg(feature="tokio")]
+use tokio::sync::futures::OwnedNotified;
+#[cfg(feature="tokio")]
+use tokio::sync::Notify;
+#[cfg(feature="tokio")]
+use std::future::Future;
+#[cfg(feature="tokio")]
+use std::pin::Pin;
+#[cfg(feature="tokio")]
+use std::task::{Context, Poll};
+
+/// An operation run by the scheduler, never concurrently with itself.
+type Task = Mutex<Box<dyn Fn() + Send>>;
+
+thread_local! {
+    /// The address of the task running on this thread, if any.
+    static RUNNING: Cell<usize> = const { Cell::new(0) };
+}
 
 /// A handle to cancel a scheduled task if required.
-#[derive(Debug, Clone)]
-pub struct CancelHandle(Arc<AtomicBool>);
+/// Handles of tasks started from a `Schedule` can also wait for the task to complete and run it a last time.
+#[derive(Clone, Default)]
+pub struct CancelHandle {
+    cancelled: Arc<AtomicBool>,
+    task: Option<Arc<Task>>,
+    /// Wakes the task of a schedule running on a runtime, so that it ends when cancelled.
+    #[cfg(feature="tokio")]
+    wakeup: Option<Arc<Notify>>,
+}
+
+impl fmt::Debug for CancelHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "cancelled: {:?}", self.is_cancelled())
+    }
+}
 
 impl CancelHandle {
-    fn new() -> CancelHandle {
-        CancelHandle(Arc::new(AtomicBool::new(false)))
+    /// Create a new, uncancelled handle.
+    pub fn new() -> CancelHandle {
+        CancelHandle::default()
+    }
+
+    fn scheduled<F: Fn() + Send + 'static>(operation: F) -> CancelHandle {
+        CancelHandle {
+            cancelled: Arc::new(AtomicBool::new(false)),
+            task: Some(Arc::new(Mutex::new(Box::new(operation)))),
+            #[cfg(feature="tokio")]
+            wakeup: None,
+        }
     }
 
     /// Signals the task to stop.
+    /// A scheduled task currently running completes its run.
     pub fn cancel(&self) {
-        self.0.store(true, SeqCst);
+        self.cancelled.store(true, SeqCst);
+        #[cfg(feature="tokio")]
+        {
+            if let Some(ref wakeup) = self.wakeup {
+                wakeup.notify_one();
+                return
+            }
+        }
+        if self.task.is_some() {
+            SCHEDULER.wakeup.notify_one();
+        }
+    }
+
+    /// Returns true if the task was signaled to stop.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(SeqCst)
     }
 
-    fn is_cancelled(&self) -> bool {
-        self.0.load(SeqCst)
+    /// Signals the task to stop and waits for its current run (if any) to complete.
+    /// The task never runs again after this returns.
+    /// Called from the task itself, only signals it to stop.
+    pub fn join(&self) {
+        self.cancel();
+        if let Some(ref task) = self.task {
+            if !is_running(task) {
+                drop(lock_task(task));
+            }
+        }
+    }
+
+    /// Signals the task to stop, waits for its current run (if any) to complete, then runs it a last time.
+    /// Use it to flush metrics recorded since the last scheduled flush, e.g. before the application exits.
+    /// Called from the task itself, only signals it to stop.
+    pub fn join_and_flush(&self) {
+        self.cancel();
+        if let Some(ref task) = self.task {
+            if !is_running(task) {
+                run_task(task, &**lock_task(task));
+            }
+        }
+    }
+
+    /// Run the task on schedule, unless it was cancelled meanwhile.
+    fn run_scheduled(&self) {
+        if let Some(ref task) = self.task {
+            let operation = lock_task(task);
+            if !self.is_cancelled() {
+                run_task(task, &**operation)
+            }
+        }
+    }
+}
+
+fn task_address(task: &Arc<Task>) -> usize {
+    Arc::as_ptr(task) as usize
+}
+
+/// Returns true if the task is running on this thread, i.e. it is being joined from itself.
+fn is_running(task: &Arc<Task>) -> bool {
+    RUNNING.with(|running| running.get() == task_address(task))
+}
+
+fn lock_task(task: &Task) -> MutexGuard<'_, Box<dyn Fn() + Send>> {
+    task.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Run a locked task, keeping the scheduler thread alive if it panics.
+fn run_task(task: &Arc<Task>, operation: &(dyn Fn() + Send)) {
+    let outer = RUNNING.with(|running| running.replace(task_address(task)));
+    if panic::catch_unwind(AssertUnwindSafe(operation)).is_err() {
+        error!("Scheduled task panicked")
     }
+    RUNNING.with(|running| running.set(outer));
 }
 
-/// Schedule a task to run periodically.
-/// Starts a new thread for every task.
-fn set_schedule<F>(every: Duration, operation: F) -> CancelHandle
-where
-    F: Fn() -> () + Send + 'static,
-{
-    let handle = CancelHandle::new();
-    let inner_handle = handle.clone();
+/// What to do when a task runs for longer than its period, or could not be run on time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Overrun {
+    /// Run the task immediately for every missed run, until back on schedule.
+    CatchUp,
+    /// Skip the missed runs and run the task again at the next scheduled time.
+    #[default]
+    Skip,
+}
 
-    thread::spawn(move || loop {
-        thread::sleep(every);
-        if inner_handle.is_cancelled() {
-            break;
+/// When to run a periodic task.
+/// Runs are scheduled at fixed intervals from the start, so that run times don't drift.
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    period: Duration,
+    aligned: bool,
+    jitter: Duration,
+    overrun: Overrun,
+    #[cfg(feature="tokio")]
+    runtime: Option<Handle>,
+}
+
+impl Schedule {
+    /// Run a task at this interval, the first time one period from now.
+    pub fn every(period: Duration) -> Schedule {
+        assert!(period > Duration::from_secs(0), "Schedule period must not be zero");
+        Schedule {
+            period,
+            aligned: false,
+            jitter: Duration::from_secs(0),
+            overrun: Overrun::default(),
+            #[cfg(feature="tokio")]
+            runtime: None,
         }
-        operation();
-    });
-    handle
+    }
+
+    /// Return a clone of this schedule running at wall-clock multiples of the period,
+    /// e.g. at :00, :10, :20... of every minute for a ten seconds period.
+    pub fn aligned(&self) -> Schedule {
+        let mut cloned = self.clone();
+        cloned.aligned = true;
+        cloned
+    }
+
+    /// Return a clone of this schedule delaying every run by a random duration up to `jitter`,
+    /// e.g. so that multiple hosts do not all publish at the same time.
+    /// Jitter should be small relative to the period.
+    pub fn jitter(&self, jitter: Duration) -> Schedule {
+        let mut cloned = self.clone();
+        cloned.jitter = jitter;
+        cloned
+    }
+
+    /// Return a clone of this schedule applying this policy to runs that could not be done on time.
+    pub fn on_overrun(&self, overrun: Overrun) -> Schedule {
+        let mut cloned = self.clone();
+        cloned.overrun = overrun;
+        cloned
+    }
+
+    /// Return a clone of this schedule running tasks on the tokio runtime instead of the scheduler thread.
+    /// Tasks run from a worker thread of the runtime and should not block, e.g. flush to async transports.
+    #[cfg(feature="tokio")]
+    pub fn on_runtime(&self, runtime: Handle) -> Schedule {
+        let mut cloned = self.clone();
+        cloned.runtime = Some(runtime);
+        cloned
+    }
+
+    /// Run a task periodically from the scheduler thread, or from the runtime if one was specified.
+    pub fn start<F: Fn() + Send + 'static>(&self, operation: F) -> CancelHandle {
+        let handle = CancelHandle::scheduled(operation);
+        #[cfg(feature="tokio")]
+        {
+            if let Some(ref runtime) = self.runtime {
+                let wakeup = Arc::new(Notify::new());
+                let handle = CancelHandle { wakeup: Some(wakeup.clone()), ..handle };
+                let entry = self.entry(handle.clone());
+                let _context = runtime.enter();
+                let sleep = Box::pin(tokio_time::sleep_until(entry.due.into()));
+                let cancelled = Box::pin(wakeup.notified_owned());
+                runtime.spawn(AsyncEntry { entry, sleep, cancelled });
+                return handle
+            }
+        }
+        SCHEDULER.add(self.entry(handle.clone()));
+        handle
+    }
+
+    /// The first run of a task.
+    fn entry(&self, handle: CancelHandle) -> Entry {
+        let base = self.first_base(Instant::now(), SystemTime::now());
+        Entry {
+            due: base + self.jitter_delay(),
+            base,
+            schedule: self.clone(),
+            handle,
+        }
+    }
+
+    /// The time of the first run, before jitter.
+    fn first_base(&self, now: Instant, wall: SystemTime) -> Instant {
+        if self.aligned {
+            now + aligned_delay(wall, self.period)
+        } else {
+            now + self.period
+        }
+    }
+
+    /// The time of the run following the one scheduled at `base`, before jitter.
+    fn next_base(&self, base: Instant, now: Instant, wall: SystemTime) -> Instant {
+        let next = base + self.period;
+        if next <= now && self.overrun == Overrun::CatchUp {
+            return next
+        }
+        if self.aligned {
+            return now + aligned_delay(wall, self.period)
+        }
+        if next > now {
+            return next
+        }
+        let missed = (now - base).as_nanos() / self.period.as_nanos();
+        base + Duration::from_nanos((self.period.as_nanos() * (missed + 1)) as u64)
+    }
+
+    fn jitter_delay(&self) -> Duration {
+        self.jitter.mul_f64(pcg32::random_fraction())
+    }
+}
+
+/// The time until the next wall-clock multiple of the period.
+fn aligned_delay(wall: SystemTime, period: Duration) -> Duration {
+    let since_epoch = wall.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let remainder = since_epoch.as_nanos() % period.as_nanos();
+    Duration::from_nanos((period.as_nanos() - remainder) as u64)
+}
+
+/// A task waiting for its next run.
+struct Entry {
+    due: Instant,
+    base: Instant,
+    schedule: Schedule,
+    handle: CancelHandle,
+}
+
+impl Entry {
+    /// Run the task then schedule its next run.
+    fn run(&mut self) {
+        self.handle.run_scheduled();
+        self.base = self.schedule.next_base(self.base, Instant::now(), SystemTime::now());
+        self.due = self.base + self.schedule.jitter_delay();
+    }
+}
+
+/// A task running on a tokio runtime, waiting for its next run.
+#[cfg(feature="tokio")]
+struct AsyncEntry {
+    entry: Entry,
+    sleep: Pin<Box<Sleep>>,
+    cancelled: Pin<Box<OwnedNotified>>,
+}
+
+#[cfg(feature="tokio")]
+impl Future for AsyncEntry {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let this = self.get_mut();
+        loop {
+            if this.entry.handle.is_cancelled() || this.cancelled.as_mut().poll(cx).is_ready() {
+                return Poll::Ready(())
+            }
+            if this.sleep.as_mut().poll(cx).is_pending() {
+                return Poll::Pending
+            }
+            this.entry.run();
+            this.sleep.as_mut().reset(this.entry.due.into());
+        }
+    }
+}
+
+/// The tasks run by the scheduler thread.
+struct Scheduler {
+    entries: Mutex<Vec<Entry>>,
+    wakeup: Condvar,
+}
+
+lazy_static! {
+    static ref SCHEDULER: Arc<Scheduler> = Scheduler::start();
+}
+
+impl Scheduler {
+    fn start() -> Arc<Scheduler> {
+        let scheduler = Arc::new(Scheduler { entries: Mutex::new(vec![]), wakeup: Condvar::new() });
+        let thread_scheduler = scheduler.clone();
+        thread::Builder::new()
+            .name("dipstick-scheduler".to_string())
+            .spawn(move || thread_scheduler.run())
+            .expect("Scheduler thread");
+        scheduler
+    }
+
+    fn add(&self, entry: Entry) {
+        self.entries.lock().expect("Scheduler").push(entry);
+        self.wakeup.notify_one();
+    }
+
+    /// Run tasks as they come due, one at a time.
+    /// Tasks run without holding the lock, so that others can be added or cancelled meanwhile.
+    fn run(&self) {
+        let mut entries = self.entries.lock().expect("Scheduler");
+        loop {
+            entries.retain(|entry| !entry.handle.is_cancelled());
+            let now = Instant::now();
+            let earliest = entries.iter().enumerate()
+                .min_by_key(|&(_, entry)| entry.due)
+                .map(|(idx, entry)| (idx, entry.due));
+
+            entries = match earliest {
+                None => self.wakeup.wait(entries).expect("Scheduler"),
+                Some((_, due)) if due > now => self.wakeup.wait_timeout(entries, due - now).expect("Scheduler").0,
+                Some((idx, _)) => {
+                    let mut entry = entries.swap_remove(idx);
+                    drop(entries);
+
+                    entry.run();
+
+                    let mut entries = self.entries.lock().expect("Scheduler");
+                    entries.push(entry);
+                    entries
+                }
+            }
+        }
+    }
 }
 
 /// Enable background periodical publication of metrics
 pub trait ScheduleFlush {
-    /// Start a thread dedicated to flushing this scope at regular intervals.
+    /// Flush this scope at regular intervals, from the scheduler thread.
     fn flush_every(&self, period: Duration) -> CancelHandle;
+
+    /// Flush this scope on schedule, from the scheduler thread.
+    fn flush_on(&self, schedule: &Schedule) -> CancelHandle;
 }
 
 impl<T: InputScope + Send + Sync + Clone + 'static> ScheduleFlush for T {
-    /// Start a thread dedicated to flushing this scope at regular intervals.
     fn flush_every(&self, period: Duration) -> CancelHandle {
+        self.flush_on(&Schedule::every(period))
+    }
+
+    fn flush_on(&self, schedule: &Schedule) -> CancelHandle {
         let scope = self.clone();
-        set_schedule(period, move || {
+        schedule.start(move || {
             if let Err(err) = scope.flush() {
                 error!("Could not flush metrics: {}", err);
             }
         })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    #[test]
+    fn align_to_wall_clock() {
+        let period = Duration::from_secs(10);
+        assert_eq!(Duration::from_secs(5), aligned_delay(UNIX_EPOCH + Duration::from_secs(125), period));
+        assert_eq!(Duration::from_secs(10), aligned_delay(UNIX_EPOCH + Duration::from_secs(130), period));
+        assert_eq!(Duration::from_millis(9_750), aligned_delay(UNIX_EPOCH + Duration::from_millis(130_250), period));
+    }
+
+    #[test]
+    fn overrun_policies() {
+        let base = Instant::now();
+        let wall = UNIX_EPOCH + Duration::from_millis(1_035);
+        let period = Duration::from_millis(10);
+        let schedule = Schedule::every(period);
+
+        // on time
+        assert_eq!(base + period, schedule.next_base(base, base + Duration::from_millis(2), wall));
+
+        let late = base + Duration::from_millis(35);
+        assert_eq!(base + Duration::from_millis(40), schedule.next_base(base, late, wall));
+        assert_eq!(base + period, schedule.on_overrun(Overrun::CatchUp).next_base(base, late, wall));
+        assert_eq!(late + Duration::from_millis(5), schedule.aligned().next_base(base, late, wall));
+    }
+
+    #[test]
+    fn run_on_scheduler_thread_until_joined() {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let thread_names = Arc::new(Mutex::new(vec![]));
+
+        let handles: Vec<CancelHandle> = (0..3).map(|_| {
+            let runs = runs.clone();
+            let thread_names = thread_names.clone();
+            Schedule::every(Duration::from_millis(5)).start(move || {
+                runs.fetch_add(1, SeqCst);
+                thread_names.lock().unwrap().push(thread::current().name().map(String::from));
+            })
+        }).collect();
+
+        thread::sleep(Duration::from_millis(100));
+        for handle in &handles {
+            handle.join();
+        }
+        let joined_runs = runs.load(SeqCst);
+        thread::sleep(Duration::from_millis(20));
+
+        assert!(joined_runs >= 3, "{} runs", joined_runs);
+        assert_eq!(joined_runs, runs.load(SeqCst));
+        assert!(thread_names.lock().unwrap().iter().all(|name| name.as_ref().map(String::as_str) == Some("dipstick-scheduler")));
+    }
+
+    #[cfg(feature="tokio")]
+    #[test]
+    fn run_on_runtime() {
+        let runtime = ::tokio::runtime::Builder::new_multi_thread().worker_threads(1).enable_all().build().unwrap();
+        let runs = Arc::new(AtomicUsize::new(0));
+        let task_runs = runs.clone();
+        let handle = Schedule::every(Duration::from_millis(5)).on_runtime(runtime.handle().clone()).start(move || {
+            assert!(thread::current().name() != Some("dipstick-scheduler"));
+            task_runs.fetch_add(1, SeqCst);
+        });
+
+        thread::sleep(Duration::from_millis(50));
+        handle.join();
+        let joined_runs = runs.load(SeqCst);
+        thread::sleep(Duration::from_millis(20));
+
+        assert!(joined_runs >= 2, "{} runs", joined_runs);
+        assert_eq!(joined_runs, runs.load(SeqCst));
+    }
+
+    #[cfg(feature="tokio")]
+    #[test]
+    fn cancel_on_runtime() {
+        let runtime = ::tokio::runtime::Builder::new_multi_thread().worker_threads(1).enable_all().build().unwrap();
+        let handle = Schedule::every(Duration::from_secs(3600)).on_runtime(runtime.handle().clone()).start(|| {});
+        let wakeup = handle.wakeup.clone().expect("Runtime wakeup");
+        handle.cancel();
+
+        // the runtime task ends right away, dropping its references to the wakeup
+        for _ in 0..200 {
+            if Arc::strong_count(&wakeup) == 2 { break }
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(2, Arc::strong_count(&wakeup));
+    }
+
+    #[test]
+    fn final_flush() {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let task_runs = runs.clone();
+        let handle = Schedule::every(Duration::from_secs(3600)).start(move || {
+            task_runs.fetch_add(1, SeqCst);
+        });
+
+        handle.join_and_flush();
+        assert_eq!(1, runs.load(SeqCst));
+        assert!(handle.is_cancelled());
+    }
+
+    #[test]
+    fn join_from_task() {
+        let slot: Arc<Mutex<Option<CancelHandle>>> = Arc::new(Mutex::new(None));
+        let runs = Arc::new(AtomicUsize::new(0));
+        let (task_slot, task_runs) = (slot.clone(), runs.clone());
+        let handle = Schedule::every(Duration::from_millis(10)).start(move || {
+            if let Some(handle) = task_slot.lock().unwrap().clone() {
+                handle.join();
+                handle.join_and_flush();
+                task_runs.fetch_add(1, SeqCst);
+            }
+        });
+        *slot.lock().unwrap() = Some(handle.clone());
+
+        for _ in 0..200 {
+            if runs.load(SeqCst) > 0 { break }
+            thread::sleep(Duration::from_millis(10));
+        }
+        handle.join();
+        assert_eq!(1, runs.load(SeqCst));
+        assert!(handle.is_cancelled());
+    }
+}
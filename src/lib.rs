@@ -3,7 +3,7 @@
 #![cfg_attr(feature = "bench", feature(test))]
 #![warn(missing_docs, trivial_casts, trivial_numeric_casts, unused_extern_crates,
         unused_qualifications)]
-#![recursion_limit="32"]
+#![recursion_limit="64"]
 
 #[cfg(feature = "bench")]
 extern crate test;
@@ -22,6 +22,15 @@ extern crate time;
 //#[cfg(feature="prometheus")]
 extern crate prometheus;
 
+#[cfg(feature="tiny_http")]
+extern crate tiny_http;
+
+#[cfg(feature="tokio")]
+extern crate tokio;
+
+#[cfg(feature="futures")]
+extern crate futures_core;
+
 #[macro_use]
 mod macros;
 pub use macros::*;
@@ -30,30 +39,46 @@ mod core;
 pub use core::{Flush, MetricValue};
 pub use core::attributes::{Prefixed, Sampling, Sampled, Buffered, Buffering};
 pub use core::name::{MetricName, NameParts};
-pub use core::input::{Input, InputDyn, InputScope, InputMetric, Counter, Timer, Marker, Gauge, InputKind};
+pub use core::metadata::{Unit, Metadata};
+pub use core::input::{Input, InputDyn, InputScope, InputMetric, Counter, Timer, Marker, Gauge, InputKind, FromMetric};
 pub use core::output::{Output, OutputDyn, OutputScope, OutputMetric};
-pub use core::scheduler::{ScheduleFlush, CancelHandle};
+pub use core::scheduler::{ScheduleFlush, CancelHandle, Schedule, Overrun};
 pub use core::out_lock::{LockingScopeBox};
 pub use core::error::{Result};
-pub use core::clock::{TimeHandle};
+pub use core::clock::{TimeHandle, Clock, SystemClock};
+pub use core::instrument::{InstrumentFuture, Timed, FirstPolled, Counted};
+#[cfg(feature="futures")]
+pub use core::instrument::{InstrumentStream, Metered};
+
+#[cfg(feature = "test-util")]
+pub use core::clock::ManualClock;
 pub use core::label::{Labels, AppLabel, ThreadLabel};
 
 #[cfg(test)]
 pub use core::clock::{mock_clock_advance, mock_clock_reset};
 
 pub use core::proxy::Proxy;
+pub use core::catalog::{Catalog, MetricInfo};
+pub use core::conflict::{OnKindConflict, KindConflict};
 
 mod output;
 pub use output::format::{LineFormat, SimpleFormat, LineOp, LabelOp, LineTemplate, Formatting};
 pub use output::stream::{Stream, TextScope};
+pub use output::socket::Transport;
+pub use output::retry::RetryBuffer;
 pub use output::graphite::{Graphite, GraphiteScope, GraphiteMetric};
-pub use output::statsd::{Statsd, StatsdScope, StatsdMetric};
+pub use output::statsd::{Statsd, StatsdScope, StatsdMetric, StatsdDialect, StatsdType, ServiceCheckStatus};
+pub use output::influx::{Influx, InfluxScope, InfluxMetric};
 pub use output::map::{StatsMap};
+pub use output::recording::{RecordingOutput, RecordingInput, Record, Recorded};
 pub use output::log::{Log, LogScope};
 
 //#[cfg(feature="prometheus")]
 pub use output::prometheus::{Prometheus, PrometheusScope};
 
+#[cfg(feature="tiny_http")]
+pub use output::http_serve::PrometheusServer;
+
 mod bucket;
 pub use bucket::{ScoreType, stats_all, stats_average, stats_summary};
 pub use bucket::atomic::{AtomicBucket};
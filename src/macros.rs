@@ -98,14 +98,14 @@ macro_rules! metrics {
     };
 
     // LEAF NODE - public typed decl
-    ($(#[$attr:meta])* pub $IDENT:ident: $TYPE:ty = $e:expr; $($REST:tt)*) => {
-        metrics!{ @internal Proxy::default(); Proxy; $(#[$attr])* pub $IDENT: $TYPE = $e; }
+    ($(#[$attr:meta])* pub $IDENT:ident: $TYPE:ty = $e:expr $(, $META:ident: $VALUE:expr)*; $($REST:tt)*) => {
+        metrics!{ @internal Proxy::default(); Proxy; $(#[$attr])* pub $IDENT: $TYPE = $e $(, $META: $VALUE)*; }
         metrics!{ $($REST)* }
     };
 
     // LEAF NODE - private typed decl
-    ($(#[$attr:meta])* $IDENT:ident: $TYPE:ty = $e:expr; $($REST:tt)*) => {
-        metrics!{ @internal Proxy::default(); Proxy; $(#[$attr])* $IDENT: $TYPE = $e; }
+    ($(#[$attr:meta])* $IDENT:ident: $TYPE:ty = $e:expr $(, $META:ident: $VALUE:expr)*; $($REST:tt)*) => {
+        metrics!{ @internal Proxy::default(); Proxy; $(#[$attr])* $IDENT: $TYPE = $e $(, $META: $VALUE)*; }
         metrics!{ $($REST)* }
     };
 
@@ -113,21 +113,34 @@ macro_rules! metrics {
     () => ();
 
     // METRIC NODE - public
-    (@internal $WITH:expr; $TY:ty; $(#[$attr:meta])* pub $IDENT:ident: $MTY:ty = $METRIC_NAME:expr; $($REST:tt)*) => {
+    // optional metadata follows the name, e.g. `unit: Unit::Bytes, description: "Bytes received"`
+    (@internal $WITH:expr; $TY:ty; $(#[$attr:meta])* pub $IDENT:ident: $MTY:ty = $METRIC_NAME:expr $(, $META:ident: $VALUE:expr)*; $($REST:tt)*) => {
         lazy_static! { $(#[$attr])* pub static ref $IDENT: $MTY =
-            $WITH.new_metric($METRIC_NAME.into(), stringify!($MTY).into()).into();
+            metrics!(@metric $WITH; $MTY; metrics!(@name $METRIC_NAME $(, $META: $VALUE)*));
         }
         metrics!{ @internal $WITH; $TY; $($REST)* }
     };
 
     // METRIC NODE - private
-    (@internal $WITH:expr; $TY:ty; $(#[$attr:meta])* $IDENT:ident: $MTY:ty = $METRIC_NAME:expr; $($REST:tt)*) => {
+    (@internal $WITH:expr; $TY:ty; $(#[$attr:meta])* $IDENT:ident: $MTY:ty = $METRIC_NAME:expr $(, $META:ident: $VALUE:expr)*; $($REST:tt)*) => {
         lazy_static! { $(#[$attr])* static ref $IDENT: $MTY =
-            $WITH.new_metric($METRIC_NAME.into(), stringify!($MTY).into()).into();
+            metrics!(@metric $WITH; $MTY; metrics!(@name $METRIC_NAME $(, $META: $VALUE)*));
         }
         metrics!{ @internal $WITH; $TY; $($REST)* }
     };
 
+    // METRIC - from its name, so that handles can apply the name's metadata
+    (@metric $WITH:expr; $MTY:ty; $NAME:expr) => {{
+        let name: $crate::MetricName = $NAME;
+        <$MTY as $crate::FromMetric>::from_metric($WITH.new_metric(name.clone(), stringify!($MTY).into()), &name)
+    }};
+
+    // METRIC NAME - with metadata
+    (@name $METRIC_NAME:expr $(, $META:ident: $VALUE:expr)*) => {{
+        let name: $crate::MetricName = $METRIC_NAME.into();
+        name $(.$META($VALUE))*
+    }};
+
     // SUB BRANCH NODE - public identifier
     (@internal $WITH:expr; $TY:ty; $(#[$attr:meta])* pub $IDENT:ident = $e:expr => { $($BRANCH:tt)*} $($REST:tt)*) => {
         lazy_static! { $(#[$attr])* pub static ref $IDENT = $WITH.add_prefix($e); }
@@ -163,18 +176,32 @@ mod test {
     use core::input::*;
     use core::proxy::Proxy;
 
+    use core::metadata::Unit;
+
     metrics!{TEST: Proxy = "test_prefix" => {
         pub M1: Marker = "failed";
         C1: Counter = "failed";
         G1: Gauge = "failed";
         T1: Timer = "failed";
+        C2: Counter = "received", unit: Unit::Bytes, description: "Bytes received";
     }}
 
+    metrics!{
+        T2: Timer = "described", description: "Time spent";
+    }
+
     #[test]
     fn call_new_macro_defined_metrics() {
         M1.mark();
         C1.count(1);
         G1.value(1);
         T1.interval_us(1);
+        C2.count(1);
+        T2.interval_us(1);
+
+        let catalog = TEST.catalog();
+        let received = catalog.metrics.iter().find(|metric| metric.name.join(".") == "test_prefix.received").unwrap();
+        assert_eq!(Some(Unit::Bytes), received.unit);
+        assert_eq!(Some("Bytes received".into()), received.description);
     }
 }
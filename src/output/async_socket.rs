@@ -0,0 +1,289 @@
+//! Send payloads to metrics servers from a task on a tokio runtime, through non-blocking async sockets.
+//! Outputs only queue their payloads, so that flushing never blocks on network I/O.
+//! If the queue is full, e.g. while the server is unreachable, new payloads are dropped.
+
+use output::socket::{Transport, MIN_RECONNECT_DELAY_MS, MAX_RECONNECT_DELAY_MS};
+
+use tokio::runtime::Handle;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::time::{self, Sleep};
+use tokio::io::AsyncWrite;
+use tokio::net::{TcpStream, UdpSocket};
+#[cfg(unix)]
+use tokio::net::{UnixStream, UnixDatagram};
+
+use std::future::{self, Future};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use std::io;
+use std::net;
+#[cfg(unix)]
+use std::os::unix::net as unix;
+
+/// Queues payloads for the task writing them to the server.
+/// The task stops once all clones of the socket are dropped and the queue is empty.
+#[derive(Debug, Clone)]
+pub struct AsyncSocket {
+    sender: mpsc::Sender<Vec<u8>>,
+}
+
+impl AsyncSocket {
+    /// Spawn a task on the runtime, writing queued payloads to the server.
+    pub fn spawn(transport: Transport, runtime: &Handle, queue_length: usize) -> AsyncSocket {
+        let (sender, receiver) = mpsc::channel(queue_length);
+        runtime.spawn(AsyncWriter {
+            transport,
+            receiver,
+            state: State::Disconnected,
+            retries: 0,
+            pending: None,
+        });
+        AsyncSocket { sender }
+    }
+
+    /// Queue the whole payload, failing without blocking if the queue is full.
+    pub fn send(&self, payload: &[u8]) -> io::Result<()> {
+        self.sender.try_send(payload.to_vec()).map_err(|err| match err {
+            TrySendError::Full(_) => io::Error::new(io::ErrorKind::WouldBlock, "Async socket queue is full"),
+            TrySendError::Closed(_) => io::Error::new(io::ErrorKind::BrokenPipe, "Async socket task has stopped"),
+        })
+    }
+}
+
+/// A connected non-blocking socket.
+enum Connection {
+    Tcp(TcpStream),
+    Udp(UdpSocket),
+    #[cfg(unix)]
+    UnixStream(UnixStream),
+    #[cfg(unix)]
+    UnixDatagram(UnixDatagram),
+}
+
+impl Connection {
+    /// Write some of the payload, or all of it as a single datagram.
+    fn poll_write(&mut self, cx: &mut Context, payload: &[u8]) -> Poll<io::Result<usize>> {
+        match *self {
+            Connection::Tcp(ref mut stream) => Pin::new(stream).poll_write(cx, payload),
+            Connection::Udp(ref socket) => socket.poll_send(cx, payload),
+            #[cfg(unix)]
+            Connection::UnixStream(ref mut stream) => Pin::new(stream).poll_write(cx, payload),
+            #[cfg(unix)]
+            Connection::UnixDatagram(ref socket) => socket.poll_send(cx, payload),
+        }
+    }
+
+    fn is_datagram(&self) -> bool {
+        match *self {
+            Connection::Udp(_) => true,
+            #[cfg(unix)]
+            Connection::UnixDatagram(_) => true,
+            _ => false,
+        }
+    }
+}
+
+type Connecting = Pin<Box<dyn Future<Output = io::Result<Connection>> + Send>>;
+
+/// Wraps the socket resulting from a connect future.
+struct Connect<F: Future> {
+    future: Pin<Box<F>>,
+    wrap: fn(F::Output) -> io::Result<Connection>,
+}
+
+impl<F: Future> Future for Connect<F> {
+    type Output = io::Result<Connection>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        this.future.as_mut().poll(cx).map(this.wrap)
+    }
+}
+
+fn connecting<F: Future + Send + 'static>(future: F, wrap: fn(F::Output) -> io::Result<Connection>) -> Connecting {
+    Box::pin(Connect { future: Box::pin(future), wrap })
+}
+
+/// Connect to the server, trying each of its addresses in turn on successive attempts.
+/// Datagram sockets are connected immediately, must be called from within the runtime.
+fn connect(transport: &Transport, attempt: usize) -> Connecting {
+    match *transport {
+        Transport::Tcp(ref addresses) if !addresses.is_empty() => {
+            let address = addresses[attempt % addresses.len()];
+            connecting(TcpStream::connect(address), |stream| stream.map(Connection::Tcp))
+        }
+        Transport::Udp(ref addresses) => {
+            let socket = net::UdpSocket::bind("0.0.0.0:0")
+                .and_then(|socket| socket.set_nonblocking(true).map(|_| socket))
+                .and_then(|socket| socket.connect(&addresses[..]).map(|_| socket))
+                .and_then(UdpSocket::from_std);
+            connecting(future::ready(socket), |socket| socket.map(Connection::Udp))
+        }
+        #[cfg(unix)]
+        Transport::UnixStream(ref path) => {
+            connecting(UnixStream::connect(path.clone()), |stream| stream.map(Connection::UnixStream))
+        }
+        #[cfg(unix)]
+        Transport::UnixDatagram(ref path) => {
+            let socket = unix::UnixDatagram::unbound()
+                .and_then(|socket| socket.set_nonblocking(true).map(|_| socket))
+                .and_then(|socket| socket.connect(path).map(|_| socket))
+                .and_then(UnixDatagram::from_std);
+            connecting(future::ready(socket), |socket| socket.map(Connection::UnixDatagram))
+        }
+        _ => {
+            let err = io::Error::new(io::ErrorKind::InvalidInput, format!("Can not connect to {:?}", transport));
+            connecting(future::ready(Err(err)), |connection| connection)
+        }
+    }
+}
+
+enum State {
+    Disconnected,
+    Connecting(Connecting),
+    Connected(Connection),
+    Backoff(Pin<Box<Sleep>>),
+}
+
+/// The task writing queued payloads to the server, reconnecting with an exponential backoff.
+/// Payloads stay in the queue while disconnected.
+struct AsyncWriter {
+    transport: Transport,
+    receiver: mpsc::Receiver<Vec<u8>>,
+    state: State,
+    retries: usize,
+    /// The payload being written and how much of it was written already.
+    pending: Option<(Vec<u8>, usize)>,
+}
+
+impl AsyncWriter {
+    fn backoff(&mut self, err: io::Error) -> State {
+        self.retries += 1;
+        let delay = MAX_RECONNECT_DELAY_MS.min(MIN_RECONNECT_DELAY_MS << self.retries.min(16));
+        warn!(
+            "Could not send to {:?} after {} trie(s). Backing off reconnection by {}ms. {}",
+            self.transport, self.retries, delay, err
+        );
+        State::Backoff(Box::pin(time::sleep(Duration::from_millis(delay))))
+    }
+}
+
+impl Future for AsyncWriter {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let this = self.get_mut();
+        loop {
+            if this.pending.is_none() {
+                match this.receiver.poll_recv(cx) {
+                    Poll::Ready(Some(payload)) => this.pending = Some((payload, 0)),
+                    Poll::Ready(None) => return Poll::Ready(()),
+                    Poll::Pending => return Poll::Pending,
+                }
+            }
+
+            let next: io::Result<Option<State>> = match this.state {
+                State::Disconnected => Ok(Some(State::Connecting(connect(&this.transport, this.retries)))),
+                State::Backoff(ref mut sleep) => match sleep.as_mut().poll(cx) {
+                    Poll::Ready(()) => Ok(Some(State::Disconnected)),
+                    Poll::Pending => return Poll::Pending,
+                },
+                State::Connecting(ref mut connecting) => match connecting.as_mut().poll(cx) {
+                    Poll::Ready(Ok(connection)) => {
+                        this.retries = 0;
+                        info!("Connected to {:?}", this.transport);
+                        Ok(Some(State::Connected(connection)))
+                    }
+                    Poll::Ready(Err(err)) => Err(err),
+                    Poll::Pending => return Poll::Pending,
+                },
+                State::Connected(ref mut connection) => {
+                    let done = {
+                        let (ref payload, ref mut offset) = *this.pending.as_mut().expect("Pending payload");
+                        match connection.poll_write(cx, &payload[*offset..]) {
+                            Poll::Ready(Ok(written)) => {
+                                *offset += written;
+                                Ok(connection.is_datagram() || *offset >= payload.len())
+                            }
+                            Poll::Ready(Err(err)) => {
+                                // resend whole payloads over the next connection, drop datagrams
+                                *offset = 0;
+                                Err((err, connection.is_datagram()))
+                            }
+                            Poll::Pending => return Poll::Pending,
+                        }
+                    };
+                    match done {
+                        Ok(done) => {
+                            if done {
+                                this.pending = None
+                            }
+                            Ok(None)
+                        }
+                        Err((err, datagram)) => {
+                            if datagram {
+                                this.pending = None
+                            }
+                            Err(err)
+                        }
+                    }
+                }
+            };
+
+            match next {
+                Ok(Some(state)) => this.state = state,
+                Ok(None) => {}
+                Err(err) => this.state = this.backoff(err),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::runtime::Builder;
+
+    use std::net::{TcpListener, UdpSocket};
+    use std::io::Read;
+
+    #[test]
+    fn send_from_runtime() {
+        let runtime = Builder::new_multi_thread().worker_threads(1).enable_all().build().unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let socket = AsyncSocket::spawn(Transport::tcp(listener.local_addr().unwrap()).unwrap(), runtime.handle(), 8);
+        socket.send(b"over ").unwrap();
+        socket.send(b"async tcp").unwrap();
+        let mut received = String::new();
+        listener.accept().unwrap().0.take(14).read_to_string(&mut received).unwrap();
+        assert_eq!("over async tcp", received);
+
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let socket = AsyncSocket::spawn(Transport::udp(receiver.local_addr().unwrap()).unwrap(), runtime.handle(), 8);
+        socket.send(b"over async udp").unwrap();
+        let mut datagram = [0; 32];
+        let size = receiver.recv(&mut datagram).unwrap();
+        assert_eq!(b"over async udp", &datagram[..size]);
+    }
+
+    #[test]
+    fn queue_while_disconnected() {
+        let runtime = Builder::new_multi_thread().worker_threads(1).enable_all().build().unwrap();
+
+        // find a free port, then leave it closed
+        let address = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let socket = AsyncSocket::spawn(Transport::tcp(address).unwrap(), runtime.handle(), 1);
+        socket.send(b"lost ").unwrap();
+        ::std::thread::sleep(Duration::from_millis(MIN_RECONNECT_DELAY_MS));
+        socket.send(b"found").unwrap();
+        assert_eq!(io::ErrorKind::WouldBlock, socket.send(b"dropped").unwrap_err().kind());
+
+        let listener = TcpListener::bind(address).unwrap();
+        let mut received = String::new();
+        listener.accept().unwrap().0.take(10).read_to_string(&mut received).unwrap();
+        assert_eq!("lost found", received);
+    }
+}
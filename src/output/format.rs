@@ -5,6 +5,7 @@ use self::LineOp::*;
 
 use std::io;
 use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 /// Print commands are steps in the execution of output templates.
 pub enum LineOp {
@@ -16,6 +17,8 @@ pub enum LineOp {
     ValueAsText,
     /// Print metric value, divided by the given scale, as text.
     ScaledValueAsText(f64),
+    /// Print the time the value was written at, in seconds since the epoch.
+    Timestamp,
     /// Print the newline character.labels.lookup(key)
     NewLine,
 }
@@ -45,15 +48,28 @@ impl LineTemplate {
     /// Template execution applies commands in turn, writing to the output.
     pub fn print<L>(&self, output: &mut io::Write, value: MetricValue, lookup: L) -> Result<(), io::Error>
     where L: Fn(&str) -> Option<Arc<String>>
+    {
+        self.print_timed(output, value, None, lookup)
+    }
+
+    /// Template execution of a value written at the specified time rather than now.
+    pub fn print_timed<L>(&self, output: &mut dyn io::Write, value: MetricValue, timestamp: Option<SystemTime>, lookup: L)
+        -> Result<(), io::Error>
+    where L: Fn(&str) -> Option<Arc<String>>
     {
         for cmd in &self.ops {
             match cmd {
                 Literal(src) => output.write_all(src.as_ref())?,
                 ValueAsText => output.write_all(format!("{}", value).as_ref())?,
                 ScaledValueAsText(scale) => {
-                    let scaled = value as f64 / scale;
+                    let scaled = value / scale;
                     output.write_all(format!("{}", scaled).as_ref())?
                 },
+                Timestamp => {
+                    let timestamp = timestamp.unwrap_or_else(SystemTime::now);
+                    let seconds = timestamp.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+                    output.write_all(seconds.to_string().as_ref())?
+                },
                 NewLine => writeln!(output)?,
                 LabelExists(label_key, print_label) => {
                     if let Some(label_value) = lookup(label_key.as_ref()) {
@@ -147,10 +163,19 @@ pub mod test {
         name = name.prepend("xyz");
         let template = format.template(&name, InputKind::Counter);
         let mut out = vec![];
-        template.print(&mut out, 123000, |key| labels.lookup(key)).unwrap();
+        template.print(&mut out, 123000.0, |key| labels.lookup(key)).unwrap();
         assert_eq!("Counter/xyz.abc 123000 123 test_key=456\n", String::from_utf8(out).unwrap());
     }
 
+    #[test]
+    fn print_timestamp() {
+        let template = LineTemplate::from(vec![Literal("abc ".into()), ValueAsText, Literal(" ".into()), Timestamp]);
+        let timestamp = UNIX_EPOCH + ::std::time::Duration::from_millis(1_500_000_000_500);
+        let mut out = vec![];
+        template.print_timed(&mut out, 123.0, Some(timestamp), |_key| None).unwrap();
+        assert_eq!("abc 123 1500000000", String::from_utf8(out).unwrap());
+    }
+
     #[test]
     fn print_label_not_exists() {
         let format = TestFormat {};
@@ -158,7 +183,7 @@ pub mod test {
         name = name.prepend("xyz");
         let template = format.template(&name, InputKind::Counter);
         let mut out = vec![];
-        template.print(&mut out, 123000, |_key| None).unwrap();
+        template.print(&mut out, 123000.0, |_key| None).unwrap();
         assert_eq!("Counter/xyz.abc 123000 123 \n", String::from_utf8(out).unwrap());
     }
 }
@@ -2,31 +2,47 @@
 
 use core::attributes::{Buffered, Attributes, WithAttributes, Prefixed};
 use core::name::MetricName;
+use core::metadata::{Unit, time_scale};
 use core::{Flush, MetricValue};
 use core::input::InputKind;
+use core::label::Labels;
 use core::metrics;
 use core::output::{Output, OutputScope, OutputMetric};
 use core::error;
 use queue::queue_out;
 use cache::cache_out;
-use output::socket::RetrySocket;
+use output::socket::{Transport, TransportSocket};
+use output::retry::RetryBuffer;
 
 use std::net::ToSocketAddrs;
 
-use std::sync::{Arc, RwLock};
+use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
-use std::io::Write;
 use std::fmt::Debug;
+use std::borrow::Cow;
 
 use std::rc::Rc;
 use std::cell::{RefCell, RefMut};
 
+/// The wire format spoken to the graphite server.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum GraphiteProtocol {
+    /// One `path value timestamp` line per datapoint.
+    Plaintext,
+    /// Batches of datapoints, as length-prefixed pickled `(path, (timestamp, value))` lists.
+    Pickle,
+}
+
 /// Graphite output holds a socket to a graphite server.
 /// The socket is shared between scopes opened from the output.
 #[derive(Clone, Debug)]
 pub struct Graphite {
     attributes: Attributes,
-    socket: Arc<RwLock<RetrySocket>>,
+    socket: Arc<TransportSocket>,
+    max_payload: usize,
+    protocol: GraphiteProtocol,
+    /// Label keys to look up, with the tag names they are sent as.
+    tag_keys: Arc<Vec<(String, String)>>,
 }
 
 impl Output for Graphite {
@@ -35,8 +51,11 @@ impl Output for Graphite {
     fn output(&self) -> Self::SCOPE {
         GraphiteScope {
             attributes: self.attributes.clone(),
-            buffer: Rc::new(RefCell::new(String::new())),
+            buffer: Rc::new(RefCell::new(Vec::new())),
             socket: self.socket.clone(),
+            max_payload: self.max_payload,
+            protocol: self.protocol,
+            tag_keys: self.tag_keys.clone(),
         }
     }
 }
@@ -45,13 +64,62 @@ impl Graphite {
     /// Send metrics to a graphite server at the address and port provided.
     pub fn send_to<A: ToSocketAddrs + Debug + Clone>(address: A) -> error::Result<Graphite> {
         debug!("Connecting to graphite {:?}", address);
-        let socket = Arc::new(RwLock::new(RetrySocket::new(address.clone())?));
+        Graphite::send_via(Transport::tcp(address)?)
+    }
+
+    /// Send metrics to a graphite server through any transport, e.g. a Unix domain socket.
+    /// Over datagram transports, each flush is sent as a single datagram,
+    /// flushing early to keep datagrams under a safe size for UDP.
+    pub fn send_via(transport: Transport) -> error::Result<Graphite> {
+        Graphite::open(transport, GraphiteProtocol::Plaintext)
+    }
+
+    /// Send metrics to a graphite server at the address and port provided,
+    /// using the pickle protocol (usually on port 2004).
+    /// Each flush sends all buffered datapoints in a single frame.
+    pub fn send_pickle_to<A: ToSocketAddrs + Debug + Clone>(address: A) -> error::Result<Graphite> {
+        debug!("Connecting to graphite {:?}", address);
+        Graphite::send_pickle_via(Transport::tcp(address)?)
+    }
+
+    /// Send metrics to a graphite server through any transport, using the pickle protocol.
+    pub fn send_pickle_via(transport: Transport) -> error::Result<Graphite> {
+        Graphite::open(transport, GraphiteProtocol::Pickle)
+    }
+
+    fn open(transport: Transport, protocol: GraphiteProtocol) -> error::Result<Graphite> {
+        let max_payload = if transport.is_datagram() { MAX_UDP_PAYLOAD } else { BUFFER_FLUSH_THRESHOLD };
+        let socket = Arc::new(transport.open()?);
 
         Ok(Graphite {
             attributes: Attributes::default(),
             socket,
+            max_payload,
+            protocol,
+            tag_keys: Arc::new(vec![]),
         })
     }
+
+    /// Return a clone of the output keeping payloads that could not be sent in the retry buffer,
+    /// replaying them in order once the server can be reached again.
+    /// The buffer is installed on the output's connection, which all its clones share.
+    /// Datagram transports do not buffer unsent payloads.
+    pub fn retry_buffer(&self, buffer: RetryBuffer) -> Self {
+        self.socket.set_retry_buffer(buffer);
+        self.clone()
+    }
+
+    /// Return a clone of the output sending the values of these label keys as graphite tags,
+    /// e.g. `name;host=web01;region=eu` (requires Graphite 1.1+).
+    /// Values are looked up in each written value's labels, then in `ThreadLabel` and `AppLabel`.
+    /// Keys without any value are left out of the series name.
+    pub fn tag_labels<S: AsRef<str>>(&self, keys: &[S]) -> Self {
+        let mut cloned = self.clone();
+        cloned.tag_keys = Arc::new(keys.iter()
+            .map(|key| (key.as_ref().to_string(), sanitize_tag(key.as_ref(), true)))
+            .collect());
+        cloned
+    }
 }
 
 impl WithAttributes for Graphite {
@@ -65,27 +133,26 @@ impl Buffered for Graphite {}
 #[derive(Debug, Clone)]
 pub struct GraphiteScope {
     attributes: Attributes,
-    buffer: Rc<RefCell<String>>,
-    socket: Arc<RwLock<RetrySocket>>,
+    buffer: Rc<RefCell<Vec<u8>>>,
+    socket: Arc<TransportSocket>,
+    max_payload: usize,
+    protocol: GraphiteProtocol,
+    /// Label keys to look up, with the tag names they are sent as.
+    tag_keys: Arc<Vec<(String, String)>>,
 }
 
 impl OutputScope for GraphiteScope {
     /// Define a metric of the specified type.
     fn new_metric(&self, name: MetricName, kind: InputKind) -> OutputMetric {
-        let mut prefix = self.prefix_prepend(name).join(".");
-        prefix.push(' ');
-
-        let scale = match kind {
-            // timers are in µs, but we give graphite milliseconds
-            InputKind::Timer => 1000,
-            _ => 1,
-        };
+        // we give graphite times in milliseconds
+        let scale = time_scale(&name, kind, Unit::Milliseconds);
+        let path = self.prefix_prepend(name).join(".");
 
         let cloned = self.clone();
-        let metric = GraphiteMetric { prefix, scale };
+        let metric = GraphiteMetric { path, scale };
 
-        OutputMetric::new(move |value, _labels| {
-            cloned.print(&metric, value);
+        OutputMetric::new_timed(move |value, labels, timestamp| {
+            cloned.print(&metric, value, &labels, timestamp);
         })
     }
 }
@@ -98,28 +165,70 @@ impl Flush for GraphiteScope {
     }
 }
 
+/// Replace characters graphite does not accept in tag names (or values) with an underscore.
+/// Names may not contain `;!^=`, values may not contain `;~`, neither may contain whitespace.
+fn sanitize_tag(text: &str, name: bool) -> String {
+    text.chars().map(|c| match c {
+        ';' | '~' if !name => '_',
+        ';' | '!' | '^' | '=' if name => '_',
+        c if c.is_whitespace() => '_',
+        c => c,
+    }).collect()
+}
+
 impl GraphiteScope {
-    fn print(&self, metric: &GraphiteMetric, value: MetricValue)  {
+    /// Append the tags valued from the labels to the metric path.
+    fn tagged_path<'a>(&self, path: &'a str, labels: &Labels) -> Cow<'a, str> {
+        if self.tag_keys.is_empty() {
+            return Cow::Borrowed(path)
+        }
+        let mut tagged = path.to_string();
+        for (key, tag) in self.tag_keys.iter() {
+            if let Some(value) = labels.lookup(key) {
+                if value.is_empty() { continue }
+                tagged.push(';');
+                tagged.push_str(tag);
+                tagged.push('=');
+                tagged.push_str(&sanitize_tag(&value, false));
+            }
+        }
+        Cow::Owned(tagged)
+    }
+
+    fn print(&self, metric: &GraphiteMetric, value: MetricValue, labels: &Labels, timestamp: Option<SystemTime>)  {
+        if !value.is_finite() {
+            debug!("Skipping non-finite value {} of {}", value, metric.path);
+            return
+        }
         let scaled_value = value / metric.scale;
-        let value_str = scaled_value.to_string();
+        let path = self.tagged_path(&metric.path, labels);
 
-        let start = SystemTime::now();
+        let start = timestamp.unwrap_or_else(SystemTime::now);
 
         let mut buffer = self.buffer.borrow_mut();
         match start.duration_since(UNIX_EPOCH) {
             Ok(timestamp) => {
-                buffer.push_str(&metric.prefix);
-                buffer.push_str(&value_str);
-                buffer.push(' ');
-                buffer.push_str(&timestamp.as_secs().to_string());
-                buffer.push('\n');
-
-                if buffer.len() > BUFFER_FLUSH_THRESHOLD {
-                    metrics::GRAPHITE_OVERFLOW.mark();
-                    warn!("Graphite Buffer Size Exceeded: {}", BUFFER_FLUSH_THRESHOLD);
+                let timestamp = timestamp.as_secs();
+                let mut datapoint = vec![];
+                match self.protocol {
+                    GraphiteProtocol::Plaintext => {
+                        let line = format!("{} {} {}\n", path, scaled_value, timestamp);
+                        datapoint.extend_from_slice(line.as_bytes());
+                    }
+                    GraphiteProtocol::Pickle => {
+                        pickle_datapoint(&mut datapoint, &path, timestamp as i64, scaled_value);
+                    }
+                }
+
+                if !buffer.is_empty() && buffer.len() + datapoint.len() > self.max_payload {
+                    if self.max_payload == BUFFER_FLUSH_THRESHOLD {
+                        metrics::GRAPHITE_OVERFLOW.mark();
+                        warn!("Graphite Buffer Size Exceeded: {}", BUFFER_FLUSH_THRESHOLD);
+                    }
                     let _ = self.flush_inner(buffer);
                     buffer = self.buffer.borrow_mut();
                 }
+                buffer.extend_from_slice(&datapoint);
             }
             Err(e) => {
                 warn!("Could not compute epoch timestamp. {}", e);
@@ -133,15 +242,20 @@ impl GraphiteScope {
         }
     }
 
-    fn flush_inner(&self, mut buf: RefMut<String>) -> error::Result<()> {
+    fn flush_inner(&self, mut buf: RefMut<Vec<u8>>) -> error::Result<()> {
         if buf.is_empty() { return Ok(()) }
 
-        let mut sock = self.socket.write().expect("Lock Graphite Socket");
-        match sock.write_all(buf.as_bytes()) {
+        let payload = match self.protocol {
+            GraphiteProtocol::Plaintext => None,
+            GraphiteProtocol::Pickle => Some(pickle_frame(&buf)),
+        };
+        let payload = payload.as_ref().unwrap_or(&*buf);
+
+        match self.socket.send(payload) {
             Ok(()) => {
+                metrics::GRAPHITE_SENT_BYTES.count(payload.len());
+                trace!("Sent {} bytes to graphite", payload.len());
                 buf.clear();
-                metrics::GRAPHITE_SENT_BYTES.count(buf.len());
-                trace!("Sent {} bytes to graphite", buf.len());
                 Ok(())
             }
             Err(e) => {
@@ -154,6 +268,72 @@ impl GraphiteScope {
     }
 }
 
+// Pickle protocol 2 opcodes, see Python's `pickletools` module.
+const PICKLE_PROTO: u8 = 0x80;
+const PICKLE_EMPTY_LIST: u8 = b']';
+const PICKLE_MARK: u8 = b'(';
+const PICKLE_APPENDS: u8 = b'e';
+const PICKLE_STOP: u8 = b'.';
+const PICKLE_BINUNICODE: u8 = b'X';
+const PICKLE_BININT: u8 = b'J';
+const PICKLE_LONG1: u8 = 0x8a;
+const PICKLE_BINFLOAT: u8 = b'G';
+const PICKLE_TUPLE2: u8 = 0x86;
+
+/// Append a pickled `(path, (timestamp, value))` tuple to the buffer.
+fn pickle_datapoint(buffer: &mut Vec<u8>, path: &str, timestamp: i64, value: MetricValue) {
+    buffer.push(PICKLE_BINUNICODE);
+    buffer.extend_from_slice(&(path.len() as u32).to_le_bytes());
+    buffer.extend_from_slice(path.as_bytes());
+    pickle_int(buffer, timestamp);
+    pickle_value(buffer, value);
+    buffer.push(PICKLE_TUPLE2);
+    buffer.push(PICKLE_TUPLE2);
+}
+
+/// Append a pickled value, as an integer if it is a whole number or as a double precision float.
+fn pickle_value(buffer: &mut Vec<u8>, value: MetricValue) {
+    if value.fract() == 0.0 && value.abs() < 2f64.powi(63) {
+        pickle_int(buffer, value as i64)
+    } else {
+        buffer.push(PICKLE_BINFLOAT);
+        buffer.extend_from_slice(&value.to_bits().to_be_bytes());
+    }
+}
+
+/// Append a pickled integer, as a 4 bytes int if it fits or as a variable length long.
+fn pickle_int(buffer: &mut Vec<u8>, value: i64) {
+    if value >= i64::from(i32::MIN) && value <= i64::from(i32::MAX) {
+        buffer.push(PICKLE_BININT);
+        buffer.extend_from_slice(&(value as i32).to_le_bytes());
+    } else {
+        // shortest little-endian two's complement encoding
+        let bytes = value.to_le_bytes();
+        let mut len = bytes.len();
+        while len > 1 {
+            let sign_bit = bytes[len - 2] & 0x80;
+            let redundant = (bytes[len - 1] == 0 && sign_bit == 0) || (bytes[len - 1] == 0xff && sign_bit != 0);
+            if !redundant { break }
+            len -= 1;
+        }
+        buffer.push(PICKLE_LONG1);
+        buffer.push(len as u8);
+        buffer.extend_from_slice(&bytes[..len]);
+    }
+}
+
+/// Wrap pickled datapoints in a list, prefixed by the 4 bytes big-endian length of the pickle.
+fn pickle_frame(datapoints: &[u8]) -> Vec<u8> {
+    let pickle_len = datapoints.len() + 6;
+    let mut frame = Vec::with_capacity(pickle_len + 4);
+    frame.extend_from_slice(&(pickle_len as u32).to_be_bytes());
+    frame.extend_from_slice(&[PICKLE_PROTO, 2, PICKLE_EMPTY_LIST, PICKLE_MARK]);
+    frame.extend_from_slice(datapoints);
+    frame.push(PICKLE_APPENDS);
+    frame.push(PICKLE_STOP);
+    frame
+}
+
 impl WithAttributes for GraphiteScope {
     fn get_attributes(&self) -> &Attributes { &self.attributes }
     fn mut_attributes(&mut self) -> &mut Attributes { &mut self.attributes }
@@ -168,11 +348,14 @@ impl cache_out::CachedOutput for Graphite {}
 // TODO make configurable?
 const BUFFER_FLUSH_THRESHOLD: usize = 65_536;
 
+/// Use a safe maximum size for UDP to prevent fragmentation.
+const MAX_UDP_PAYLOAD: usize = 1432;
+
 /// Key of a graphite metric.
 #[derive(Debug, Clone)]
 pub struct GraphiteMetric {
-    prefix: String,
-    scale: isize,
+    path: String,
+    scale: MetricValue,
 }
 
 /// Any remaining buffered data is flushed on Drop.
@@ -184,6 +367,167 @@ impl Drop for GraphiteScope {
     }
 }
 
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::attributes::Buffering;
+
+    use std::net::{TcpListener, UdpSocket};
+    use std::io::{BufRead, BufReader, Read};
+    use std::time::Duration;
+
+    #[test]
+    fn pickle_encoding() {
+        let mut datapoints = Vec::new();
+        pickle_datapoint(&mut datapoints, "a.b", 1_500_000_000, -3.0);
+        assert_eq!(vec![b'X', 3, 0, 0, 0, b'a', b'.', b'b',
+                        b'J', 0x00, 0x2f, 0x68, 0x59,
+                        b'J', 0xfd, 0xff, 0xff, 0xff,
+                        0x86, 0x86], datapoints);
+
+        let mut long = Vec::new();
+        pickle_int(&mut long, 1 << 32);
+        assert_eq!(vec![0x8a, 5, 0, 0, 0, 0, 1], long);
+        long.clear();
+        pickle_int(&mut long, -(1 << 31) - 1);
+        assert_eq!(vec![0x8a, 5, 0xff, 0xff, 0xff, 0x7f, 0xff], long);
+
+        let mut float = Vec::new();
+        pickle_value(&mut float, 2.5);
+        assert_eq!(vec![b'G', 0x40, 0x04, 0, 0, 0, 0, 0, 0], float);
+
+        let frame = pickle_frame(&datapoints);
+        assert_eq!(&[0, 0, 0, 26, 0x80, 2, b']', b'('], &frame[..8]);
+        assert_eq!(b"e.", &frame[frame.len() - 2..]);
+    }
+
+    #[test]
+    fn replay_lines_after_outage() {
+        // find a free port, then leave it closed
+        let address = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let scope = Graphite::send_to(address).unwrap()
+            .retry_buffer(RetryBuffer::new(1024))
+            .output();
+        let hits = scope.new_metric("hits".into(), InputKind::Counter);
+        hits.write(1.0, labels![]);
+        assert!(scope.buffer.borrow().is_empty());
+
+        let listener = TcpListener::bind(address).unwrap();
+        ::std::thread::sleep(Duration::from_millis(400));
+        hits.write(2.0, labels![]);
+
+        let (stream, _) = listener.accept().unwrap();
+        let mut lines = BufReader::new(stream).lines();
+        assert!(lines.next().unwrap().unwrap().starts_with("hits 1 "));
+        assert!(lines.next().unwrap().unwrap().starts_with("hits 2 "));
+    }
+
+    #[test]
+    fn send_plaintext_unbuffered() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let scope = Graphite::send_to(listener.local_addr().unwrap()).unwrap()
+            .add_prefix("my_app")
+            .output();
+        let latency = scope.new_metric("latency".into(), InputKind::Timer);
+        latency.write(f64::NAN, labels![]);
+        latency.write(2000.0, labels![]);
+
+        let (stream, _) = listener.accept().unwrap();
+        let mut line = String::new();
+        BufReader::new(stream).read_line(&mut line).unwrap();
+        assert!(line.starts_with("my_app.latency 2 "), "{}", line);
+    }
+
+    #[test]
+    fn send_explicit_timestamp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let scope = Graphite::send_to(listener.local_addr().unwrap()).unwrap().output();
+        let timestamp = UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+        scope.new_metric("hits".into(), InputKind::Counter).write_timed(3.0, labels![], Some(timestamp));
+
+        let (stream, _) = listener.accept().unwrap();
+        let mut line = String::new();
+        BufReader::new(stream).read_line(&mut line).unwrap();
+        assert_eq!("hits 3 1500000000\n", line);
+    }
+
+    #[test]
+    fn sanitized_tags() {
+        assert_eq!("a_b_c_d_e~f", sanitize_tag("a;b!c^d=e~f", true));
+        assert_eq!("a_b_c=d!e", sanitize_tag("a;b~c=d!e", false));
+        assert_eq!("a_b", sanitize_tag("a b", false));
+    }
+
+    #[test]
+    fn send_tagged_series() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let scope = Graphite::send_to(listener.local_addr().unwrap()).unwrap()
+            .tag_labels(&["region", "host name", "missing"])
+            .buffered(Buffering::Unlimited)
+            .output();
+        let hits = scope.new_metric("hits".into(), InputKind::Counter);
+        hits.write(1.0, labels!{ "host name" => "web 01", "region" => "eu;west" });
+        hits.write(2.0, labels![]);
+        scope.flush().unwrap();
+
+        let (stream, _) = listener.accept().unwrap();
+        let mut lines = BufReader::new(stream).lines();
+        let line = lines.next().unwrap().unwrap();
+        assert!(line.starts_with("hits;region=eu_west;host_name=web_01 1 "), "{}", line);
+        let line = lines.next().unwrap().unwrap();
+        assert!(line.starts_with("hits 2 "), "{}", line);
+    }
+
+    #[test]
+    fn udp_datagrams_under_cap() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let scope = Graphite::send_via(Transport::udp(receiver.local_addr().unwrap()).unwrap()).unwrap()
+            .buffered(Buffering::Unlimited)
+            .output();
+        let hits = scope.new_metric("hits".into(), InputKind::Counter);
+        for i in 0..100 {
+            hits.write(f64::from(i), labels![]);
+        }
+        scope.flush().unwrap();
+
+        let mut lines = 0;
+        let mut datagram = [0; 65_536];
+        while lines < 100 {
+            let size = receiver.recv(&mut datagram).unwrap();
+            assert!(size <= MAX_UDP_PAYLOAD, "{}", size);
+            lines += datagram[..size].iter().filter(|b| **b == b'\n').count();
+        }
+    }
+
+    #[test]
+    fn send_pickle_buffered() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let scope = Graphite::send_pickle_to(listener.local_addr().unwrap()).unwrap()
+            .buffered(Buffering::Unlimited)
+            .output();
+        let hits = scope.new_metric("hits".into(), InputKind::Counter);
+        let depth = scope.new_metric("depth".into(), InputKind::Gauge);
+        hits.write(3.0, labels![]);
+        depth.write(7.0, labels![]);
+        scope.flush().unwrap();
+
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut len = [0; 4];
+        stream.read_exact(&mut len).unwrap();
+        let len = u32::from_be_bytes(len) as usize;
+        let mut pickle = vec![0; len];
+        stream.read_exact(&mut pickle).unwrap();
+
+        // list framing, then each datapoint's path, two ints and two tuples
+        assert_eq!(6 + (5 + 4 + 12) + (5 + 5 + 12), len);
+        assert_eq!(&[0x80, 2, b']', b'(', b'X', 4, 0, 0, 0], &pickle[..9]);
+        assert_eq!(b"hits", &pickle[9..13]);
+        assert_eq!(&[b'J', 3, 0, 0, 0, 0x86, 0x86], &pickle[18..25]);
+        assert_eq!(b"depth", &pickle[30..35]);
+        assert_eq!(b"e.", &pickle[len - 2..]);
+    }
+}
+
 #[cfg(feature = "bench")]
 mod bench {
 
@@ -197,7 +541,7 @@ mod bench {
         let sd = Graphite::send_to("localhost:2003").unwrap().input();
         let timer = sd.new_metric("timer".into(), InputKind::Timer);
 
-        b.iter(|| test::black_box(timer.write(2000, labels![])));
+        b.iter(|| test::black_box(timer.write(2000.0, labels![])));
     }
 
     #[bench]
@@ -206,7 +550,7 @@ mod bench {
             .buffered(Buffering::BufferSize(65465)).input();
         let timer = sd.new_metric("timer".into(), InputKind::Timer);
 
-        b.iter(|| test::black_box(timer.write(2000, labels![])));
+        b.iter(|| test::black_box(timer.write(2000.0, labels![])));
     }
 
 }
@@ -0,0 +1,144 @@
+//! Minimal blocking HTTP/1.1 client for outputs pushing metrics to an HTTP endpoint.
+//! A new connection is opened for every request.
+
+use core::error;
+use output::socket::Transport;
+
+use std::net::ToSocketAddrs;
+use std::io::{Read, Write};
+use std::time::Duration;
+use std::fmt::Display;
+
+const HTTP_TIMEOUT_MS: u64 = 5_000;
+
+/// An HTTP server to send requests to.
+#[derive(Debug, Clone)]
+pub struct HttpClient {
+    host: String,
+    transport: Transport,
+}
+
+impl HttpClient {
+    /// Resolve the server address. No connection is made until a request is sent.
+    /// Requests name the server as it was given, e.g. `Host: pushgateway:9091`.
+    pub fn new<A: ToSocketAddrs + Display>(address: A) -> error::Result<HttpClient> {
+        let transport = Transport::tcp(&address)?;
+        match transport {
+            Transport::Tcp(ref addresses) if addresses.is_empty() =>
+                Err(format!("No address for HTTP server {}", address).into()),
+            _ => Ok(HttpClient { host: address.to_string(), transport }),
+        }
+    }
+
+    /// Reach the server through a blocking stream transport, e.g. a Unix domain socket.
+    /// No connection is made until a request is sent.
+    pub fn via(transport: Transport) -> error::Result<HttpClient> {
+        match transport {
+            #[cfg(feature="tokio")]
+            Transport::Async { .. } =>
+                return Err(format!("HTTP requests can not be sent through an async transport: {:?}", transport).into()),
+            _ if transport.is_datagram() =>
+                return Err(format!("HTTP requires a stream transport, not {:?}", transport).into()),
+            _ => {}
+        }
+        let host = match transport {
+            Transport::Tcp(ref addresses) if !addresses.is_empty() => addresses[0].to_string(),
+            _ => "localhost".to_string(),
+        };
+        Ok(HttpClient { host, transport })
+    }
+
+    /// Send a request carrying the payload, wait for the response.
+    /// Any response status other than 2xx is returned as an error.
+    pub fn send(&self, method: &str, path: &str, content_type: &str, body: &[u8]) -> error::Result<()> {
+        let mut stream = self.transport.connect_stream()?;
+        stream.set_timeouts(Some(Duration::from_millis(HTTP_TIMEOUT_MS)))?;
+
+        let mut request = Vec::with_capacity(body.len() + 256);
+        write!(request, "{} {} HTTP/1.1\r\n", method, path)?;
+        write!(request, "Host: {}\r\n", self.host)?;
+        write!(request, "Content-Type: {}\r\n", content_type)?;
+        write!(request, "Content-Length: {}\r\n", body.len())?;
+        write!(request, "Connection: close\r\n\r\n")?;
+        request.extend_from_slice(body);
+        stream.write_all(&request)?;
+
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response)?;
+        let response = String::from_utf8_lossy(&response);
+        let status_line = response.lines().next().unwrap_or("");
+        match status_line.split(' ').nth(1).and_then(|code| code.parse::<u16>().ok()) {
+            Some(code) if (200..300).contains(&code) => Ok(()),
+            _ => Err(format!("HTTP {} {} failed: '{}'", method, path, status_line).into()),
+        }
+    }
+}
+
+/// Escape a string for use as an URL path segment.
+pub fn escape_path_segment(segment: &str) -> String {
+    let mut escaped = String::with_capacity(segment.len());
+    for byte in segment.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => escaped.push(byte as char),
+            _ => escaped.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    escaped
+}
+
+/// A local HTTP server stand-in to test outputs against.
+#[cfg(test)]
+pub mod test {
+    use std::net::{TcpListener, SocketAddr};
+    use std::io::{Read, Write, BufRead, BufReader};
+    use std::sync::mpsc;
+    use std::thread;
+
+    #[test]
+    fn host_as_given() {
+        use super::HttpClient;
+        assert_eq!("localhost:9091", HttpClient::new("localhost:9091").unwrap().host);
+        assert_eq!("[::1]:9091", HttpClient::new(SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 1], 9091))).unwrap().host);
+    }
+
+    #[cfg(feature="tokio")]
+    #[test]
+    fn reject_async_transport() {
+        use super::HttpClient;
+        use output::socket::Transport;
+        let runtime = ::tokio::runtime::Runtime::new().unwrap();
+        let transport = Transport::tcp("127.0.0.1:9091").unwrap().on_runtime(runtime.handle().clone(), 1);
+        assert!(HttpClient::via(transport).is_err());
+    }
+
+    /// Accept requests, answering each with the given status.
+    /// Received requests (head and body) are sent back through the channel.
+    pub fn stand_in(status: u16) -> (SocketAddr, mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request = String::new();
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.to_lowercase().starts_with("content-length:") {
+                        content_length = line[15..].trim().parse().unwrap();
+                    }
+                    request.push_str(&line);
+                    if line == "\r\n" || line.is_empty() { break }
+                }
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+                request.push_str(&String::from_utf8_lossy(&body));
+                write!(stream, "HTTP/1.1 {} Stand-In\r\nContent-Length: 0\r\n\r\n", status).unwrap();
+                if sender.send(request).is_err() { break }
+            }
+        });
+        (address, receiver)
+    }
+}
@@ -0,0 +1,142 @@
+//! Serve metrics to Prometheus scrapers over HTTP.
+//! Requires the `tiny_http` feature.
+
+use core::Flush;
+use core::error;
+use core::scheduler::CancelHandle;
+use bucket::atomic::AtomicBucket;
+use output::prometheus::Prometheus;
+
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::thread;
+use std::time::Duration;
+
+use prometheus::{Registry, Encoder, TextEncoder};
+use tiny_http::{Server, Request, Response, Header};
+
+/// How often the serving thread checks if it was cancelled.
+const CANCEL_POLL_MS: u64 = 100;
+
+/// An HTTP endpoint exposing metrics at `/metrics` in the Prometheus text exposition format.
+/// Requests are handled by a dedicated thread.
+/// Dropping the server does not stop it, use `cancel()`.
+#[derive(Debug, Clone)]
+pub struct PrometheusServer {
+    address: SocketAddr,
+    cancel: CancelHandle,
+}
+
+impl PrometheusServer {
+    /// Serve the metrics recorded to the registry, as they are when scraped.
+    pub fn serve_registry<A: ToSocketAddrs>(address: A, registry: Registry) -> error::Result<PrometheusServer> {
+        PrometheusServer::serve(address, Prometheus::to_registry(registry), None)
+    }
+
+    /// Serve the bucket's aggregated metrics.
+    /// Each scrape flushes the bucket, resetting its scores for the next period.
+    /// The bucket's flush target is replaced by the server's own registry.
+    pub fn serve_bucket<A: ToSocketAddrs>(address: A, bucket: AtomicBucket) -> error::Result<PrometheusServer> {
+        let prometheus = Prometheus::to_registry(Registry::new());
+        bucket.set_flush_to(prometheus.clone());
+        PrometheusServer::serve(address, prometheus, Some(Box::new(bucket)))
+    }
+
+    fn serve<A>(address: A, prometheus: Prometheus, source: Option<Box<dyn Flush + Send>>) -> error::Result<PrometheusServer>
+        where A: ToSocketAddrs
+    {
+        let server = Server::http(address).map_err(|e| e.to_string())?;
+        let address = server.server_addr();
+        let cancel = CancelHandle::new();
+        let inner_cancel = cancel.clone();
+
+        thread::spawn(move || {
+            while !inner_cancel.is_cancelled() {
+                match server.recv_timeout(Duration::from_millis(CANCEL_POLL_MS)) {
+                    Ok(Some(request)) => {
+                        if let Err(e) = respond(request, &prometheus, &source) {
+                            debug!("Could not respond to metrics scrape: {}", e)
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(e) => {
+                        warn!("Prometheus server stopped: {}", e);
+                        break
+                    }
+                }
+            }
+        });
+
+        info!("Serving Prometheus metrics on {}", address);
+        Ok(PrometheusServer { address, cancel })
+    }
+
+    /// The address the server actually listens on.
+    pub fn server_addr(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Stop serving metrics.
+    pub fn cancel(&self) {
+        self.cancel.cancel()
+    }
+}
+
+fn respond(request: Request, prometheus: &Prometheus, source: &Option<Box<dyn Flush + Send>>) -> error::Result<()> {
+    let path = request.url().split('?').next().unwrap_or("").to_string();
+    if path != "/metrics" {
+        request.respond(Response::empty(404))?;
+        return Ok(())
+    }
+
+    if let Some(ref source) = *source {
+        if let Err(e) = source.flush() {
+            request.respond(Response::from_string(e.to_string()).with_status_code(500))?;
+            return Ok(())
+        }
+    }
+
+    let encoder = TextEncoder::new();
+    let mut buffer = vec![];
+    encoder.encode(&prometheus.gather(), &mut buffer)?;
+    let content_type = Header::from_bytes(&b"Content-Type"[..], encoder.format_type().as_bytes())
+        .expect("Content-Type Header");
+    request.respond(Response::from_data(buffer).with_header(content_type))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::input::InputScope;
+
+    use std::net::TcpStream;
+    use std::io::{Read, Write};
+
+    fn scrape(address: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(stream, "GET {} HTTP/1.0\r\nHost: localhost\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn scrape_bucket() {
+        let bucket = AtomicBucket::new();
+        let server = PrometheusServer::serve_bucket("127.0.0.1:0", bucket.clone()).unwrap();
+
+        bucket.counter("requests").count(3);
+        let response = scrape(server.server_addr(), "/metrics");
+        assert!(response.starts_with("HTTP/1.0 200"), "{}", response);
+        // stamped with the end of the bucket's period
+        assert!(response.contains("\nrequests 3 "), "{}", response);
+
+        // scrape flushed the bucket, counter keeps its total
+        bucket.counter("requests").count(4);
+        let response = scrape(server.server_addr(), "/metrics");
+        assert!(response.contains("\nrequests 7 "), "{}", response);
+
+        assert!(scrape(server.server_addr(), "/other").starts_with("HTTP/1.0 404"));
+        server.cancel();
+    }
+}
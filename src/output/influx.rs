@@ -0,0 +1,373 @@
+//! Send metrics to an InfluxDB server or a Telegraf agent, using the line protocol.
+
+use core::attributes::{Buffered, Attributes, WithAttributes, Prefixed};
+use core::name::MetricName;
+use core::metadata::{Unit, time_scale};
+use core::{Flush, MetricValue};
+use core::input::InputKind;
+use core::label::Labels;
+use core::metrics;
+use core::output::{Output, OutputScope, OutputMetric};
+use core::error;
+use queue::queue_out;
+use cache::cache_out;
+use output::socket::{Transport, TransportSocket};
+use output::retry::RetryBuffer;
+use output::http_client::{HttpClient, escape_path_segment};
+
+use std::net::ToSocketAddrs;
+
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::fmt::{Debug, Display};
+
+use std::rc::Rc;
+use std::cell::{RefCell, RefMut};
+
+/// Use a safe maximum size for UDP to prevent fragmentation.
+const MAX_UDP_PAYLOAD: usize = 1432;
+
+/// Its hard to see how a single scope could get more metrics than this.
+const BUFFER_FLUSH_THRESHOLD: usize = 65_536;
+
+/// Where line protocol payloads are sent.
+#[derive(Debug, Clone)]
+enum InfluxSink {
+    Socket { socket: Arc<TransportSocket>, datagram: bool },
+    Http { client: HttpClient, path: Arc<String> },
+}
+
+impl InfluxSink {
+    fn send(&self, payload: &[u8]) -> error::Result<()> {
+        match *self {
+            InfluxSink::Socket { ref socket, .. } => socket.send(payload)?,
+            InfluxSink::Http { ref client, ref path } => {
+                client.send("POST", path, "text/plain; charset=utf-8", payload)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn max_payload(&self) -> usize {
+        match *self {
+            InfluxSink::Socket { datagram: true, .. } => MAX_UDP_PAYLOAD,
+            _ => BUFFER_FLUSH_THRESHOLD,
+        }
+    }
+}
+
+/// Influx output holds a connection to an InfluxDB server or Telegraf agent.
+/// The connection is shared between scopes opened from the output.
+#[derive(Clone, Debug)]
+pub struct Influx {
+    attributes: Attributes,
+    sink: InfluxSink,
+}
+
+impl Output for Influx {
+    type SCOPE = InfluxScope;
+
+    fn output(&self) -> Self::SCOPE {
+        InfluxScope {
+            attributes: self.attributes.clone(),
+            buffer: Rc::new(RefCell::new(String::new())),
+            sink: self.sink.clone(),
+        }
+    }
+}
+
+impl Influx {
+    /// Send metrics over TCP to a line protocol listener (e.g. Telegraf's `socket_listener`).
+    pub fn send_to<A: ToSocketAddrs + Debug + Clone>(address: A) -> error::Result<Influx> {
+        debug!("Connecting to influx {:?}", address);
+        Influx::send_via(Transport::tcp(address)?)
+    }
+
+    /// Send metrics over UDP to a line protocol listener.
+    pub fn send_udp_to<A: ToSocketAddrs>(address: A) -> error::Result<Influx> {
+        Influx::send_via(Transport::udp(address)?)
+    }
+
+    /// Send metrics to a line protocol listener through any transport, e.g. a Unix domain socket.
+    pub fn send_via(transport: Transport) -> error::Result<Influx> {
+        let datagram = transport.is_datagram();
+        let socket = Arc::new(transport.open()?);
+        Ok(Influx::with_sink(InfluxSink::Socket { socket, datagram }))
+    }
+
+    /// Send metrics to the HTTP `/write` endpoint of an InfluxDB server, in the specified database.
+    /// Every flush sends a single request.
+    pub fn write_http_to<A: ToSocketAddrs + Display>(address: A, database: &str) -> error::Result<Influx> {
+        Influx::write_http_with(HttpClient::new(address)?, database)
+    }
+
+    /// Send metrics to the HTTP `/write` endpoint of an InfluxDB server reached through a stream transport.
+    pub fn write_http_via(transport: Transport, database: &str) -> error::Result<Influx> {
+        Influx::write_http_with(HttpClient::via(transport)?, database)
+    }
+
+    fn write_http_with(client: HttpClient, database: &str) -> error::Result<Influx> {
+        let path = Arc::new(format!("/write?db={}", escape_path_segment(database)));
+        Ok(Influx::with_sink(InfluxSink::Http { client, path }))
+    }
+
+    /// Return a clone of the output keeping payloads that could not be sent in the retry buffer,
+    /// replaying them in order once the server can be reached again.
+    /// The buffer is installed on the output's connection, which all its clones share.
+    /// HTTP and datagram transports do not buffer unsent payloads.
+    pub fn retry_buffer(&self, buffer: RetryBuffer) -> Self {
+        match self.sink {
+            InfluxSink::Socket { ref socket, .. } => socket.set_retry_buffer(buffer),
+            InfluxSink::Http { .. } => warn!("Retry buffer ignored by HTTP output"),
+        }
+        self.clone()
+    }
+
+    fn with_sink(sink: InfluxSink) -> Influx {
+        Influx {
+            attributes: Attributes::default(),
+            sink,
+        }
+    }
+}
+
+impl WithAttributes for Influx {
+    fn get_attributes(&self) -> &Attributes { &self.attributes }
+    fn mut_attributes(&mut self) -> &mut Attributes { &mut self.attributes }
+}
+
+impl Buffered for Influx {}
+
+/// Influx Input
+#[derive(Debug, Clone)]
+pub struct InfluxScope {
+    attributes: Attributes,
+    buffer: Rc<RefCell<String>>,
+    sink: InfluxSink,
+}
+
+impl OutputScope for InfluxScope {
+    /// Define a metric of the specified type.
+    fn new_metric(&self, name: MetricName, kind: InputKind) -> OutputMetric {
+        // we give influx times in milliseconds
+        let scale = time_scale(&name, kind, Unit::Milliseconds);
+        let measurement = escape(&self.prefix_prepend(name).join("."), ",");
+
+        let cloned = self.clone();
+        let metric = InfluxMetric { measurement, scale };
+
+        OutputMetric::new_timed(move |value, labels, timestamp| {
+            cloned.print(&metric, value, labels, timestamp);
+        })
+    }
+}
+
+impl Flush for InfluxScope {
+
+    fn flush(&self) -> error::Result<()> {
+        let buf = self.buffer.borrow_mut();
+        self.flush_inner(buf)
+    }
+}
+
+/// Escape spaces and the specified characters with a backslash.
+fn escape(text: &str, special: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if c == ' ' || c == '\\' || special.contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+impl InfluxScope {
+    fn print(&self, metric: &InfluxMetric, value: MetricValue, labels: Labels, timestamp: Option<SystemTime>)  {
+        if !value.is_finite() {
+            debug!("Skipping non-finite value {} of {}", value, metric.measurement);
+            return
+        }
+        let timestamp = match timestamp.unwrap_or_else(SystemTime::now).duration_since(UNIX_EPOCH) {
+            Ok(timestamp) => timestamp,
+            Err(e) => {
+                warn!("Could not compute epoch timestamp. {}", e);
+                return
+            }
+        };
+
+        let mut line = metric.measurement.clone();
+        for (key, value) in labels.into_map() {
+            // empty tag values are invalid line protocol
+            if key.is_empty() || value.is_empty() { continue }
+            line.push(',');
+            line.push_str(&escape(&key, ",="));
+            line.push('=');
+            line.push_str(&escape(&value, ",="));
+        }
+        line.push_str(" value=");
+        // always a float field, a field's type can not change once written
+        line.push_str(&(value / metric.scale).to_string());
+        line.push(' ');
+        let nanos = timestamp.as_secs() * 1_000_000_000 + u64::from(timestamp.subsec_nanos());
+        line.push_str(&nanos.to_string());
+        line.push('\n');
+
+        let mut buffer = self.buffer.borrow_mut();
+        if buffer.len() + line.len() > self.sink.max_payload() {
+            if self.sink.max_payload() == BUFFER_FLUSH_THRESHOLD {
+                metrics::INFLUX_OVERFLOW.mark();
+                warn!("Influx Buffer Size Exceeded: {}", BUFFER_FLUSH_THRESHOLD);
+            }
+            let _ = self.flush_inner(buffer);
+            buffer = self.buffer.borrow_mut();
+        }
+        buffer.push_str(&line);
+
+        if self.get_buffering().is_none() {
+            if let Err(e) = self.flush_inner(buffer) {
+                debug!("Could not send to influx {}", e)
+            }
+        }
+    }
+
+    fn flush_inner(&self, mut buf: RefMut<String>) -> error::Result<()> {
+        if buf.is_empty() { return Ok(()) }
+
+        match self.sink.send(buf.as_bytes()) {
+            Ok(()) => {
+                metrics::INFLUX_SENT_BYTES.count(buf.len());
+                trace!("Sent {} bytes to influx", buf.len());
+                buf.clear();
+                Ok(())
+            }
+            Err(e) => {
+                metrics::INFLUX_SEND_ERR.mark();
+                debug!("Failed to send buffer to influx: {}", e);
+                Err(e)
+            }
+        }
+    }
+}
+
+impl WithAttributes for InfluxScope {
+    fn get_attributes(&self) -> &Attributes { &self.attributes }
+    fn mut_attributes(&mut self) -> &mut Attributes { &mut self.attributes }
+}
+
+impl Buffered for InfluxScope {}
+
+impl queue_out::QueuedOutput for Influx {}
+impl cache_out::CachedOutput for Influx {}
+
+/// Key of an influx metric.
+#[derive(Debug, Clone)]
+pub struct InfluxMetric {
+    measurement: String,
+    scale: MetricValue,
+}
+
+/// Any remaining buffered data is flushed on Drop.
+impl Drop for InfluxScope {
+    fn drop(&mut self) {
+        if let Err(err) = self.flush() {
+            warn!("Could not flush influx metrics upon Drop: {}", err)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::attributes::Buffering;
+    use output::http_client::test::stand_in;
+
+    use std::net::{TcpListener, UdpSocket};
+    use std::io::{BufRead, BufReader};
+    use std::time::Duration;
+
+    #[test]
+    fn escape_special_chars() {
+        assert_eq!("a\\ b\\,c=d", escape("a b,c=d", ","));
+        assert_eq!("a\\ b\\,c\\=d", escape("a b,c=d", ",="));
+    }
+
+    #[test]
+    fn write_http_buffered() {
+        let (address, requests) = stand_in(204);
+        let scope = Influx::write_http_to(address, "my db").unwrap()
+            .buffered(Buffering::Unlimited)
+            .add_prefix("my_app")
+            .output();
+
+        let counter = scope.new_metric("requests".into(), InputKind::Counter);
+        let timer = scope.new_metric("latency".into(), InputKind::Timer);
+        counter.write(3.0, labels!{ "route" => "/home" });
+        timer.write(2000.0, labels![]);
+        scope.flush().unwrap();
+
+        let request = requests.recv().unwrap();
+        assert!(request.starts_with("POST /write?db=my%20db HTTP/1.1\r\n"), "{}", request);
+        let body = request.split("\r\n\r\n").nth(1).unwrap();
+        let lines: Vec<&str> = body.lines().collect();
+        assert_eq!(2, lines.len(), "{}", body);
+        assert!(lines[0].starts_with("my_app.requests,"), "{}", lines[0]);
+        assert!(lines[0].contains(",route=/home"), "{}", lines[0]);
+        assert!(lines[0].contains(" value=3 "), "{}", lines[0]);
+        assert!(lines[1].contains(" value=2 "), "{}", lines[1]);
+    }
+
+    #[test]
+    fn send_tcp_unbuffered() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let scope = Influx::send_to(listener.local_addr().unwrap()).unwrap().output();
+        let depth = scope.new_metric("queue depth".into(), InputKind::Gauge);
+        depth.write(f64::INFINITY, labels![]);
+        depth.write(7.0, labels![]);
+
+        let (stream, _) = listener.accept().unwrap();
+        let mut line = String::new();
+        BufReader::new(stream).read_line(&mut line).unwrap();
+        assert!(line.starts_with("queue\\ depth"), "{}", line);
+        assert!(line.contains(" value=7 "), "{}", line);
+    }
+
+    #[test]
+    fn send_escaped_tags() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let scope = Influx::send_to(listener.local_addr().unwrap()).unwrap().output();
+        let hits = scope.new_metric("hits".into(), InputKind::Counter);
+        hits.write(1.0, labels!{ "host name" => "web,01=a", "region" => "" });
+
+        let (stream, _) = listener.accept().unwrap();
+        let mut line = String::new();
+        BufReader::new(stream).read_line(&mut line).unwrap();
+        assert!(line.starts_with("hits,host\\ name=web\\,01\\=a value=1 "), "{}", line);
+    }
+
+    #[test]
+    fn send_explicit_timestamp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let scope = Influx::send_to(listener.local_addr().unwrap()).unwrap().output();
+        let timestamp = UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+        scope.new_metric("hits".into(), InputKind::Counter).write_timed(3.0, labels![], Some(timestamp));
+
+        let (stream, _) = listener.accept().unwrap();
+        let mut line = String::new();
+        BufReader::new(stream).read_line(&mut line).unwrap();
+        assert!(line.ends_with(" value=3 1500000000000000000\n"), "{}", line);
+    }
+
+    #[test]
+    fn send_udp_unbuffered() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let scope = Influx::send_udp_to(receiver.local_addr().unwrap()).unwrap().output();
+        scope.new_metric("hits".into(), InputKind::Marker).write(1.0, labels![]);
+
+        let mut datagram = [0; MAX_UDP_PAYLOAD];
+        let size = receiver.recv(&mut datagram).unwrap();
+        let line = String::from_utf8_lossy(&datagram[..size]);
+        assert!(line.starts_with("hits"), "{}", line);
+        assert!(line.contains(" value=1 "), "{}", line);
+    }
+}
@@ -1,7 +1,9 @@
 use core::{Flush};
 use core::input::{InputKind, Input, InputScope, InputMetric};
-use core::attributes::{Attributes, WithAttributes, Buffered, Prefixed};
+use core::attributes::{Attributes, WithAttributes, Buffered, Prefixed, Sampled};
+use core::sampler::Sampler;
 use core::name::MetricName;
+use core::label::Labels;
 use core::error;
 use cache::cache_in;
 use queue::queue_in;
@@ -37,6 +39,8 @@ impl WithAttributes for Log {
 
 impl Buffered for Log {}
 
+impl Sampled for Log {}
+
 impl Formatting for Log {
     fn formatting(&self, format: impl LineFormat + 'static) -> Self {
         let mut cloned = self.clone();
@@ -71,6 +75,8 @@ impl WithAttributes for LogScope {
 
 impl Buffered for LogScope {}
 
+impl Sampled for LogScope {}
+
 impl queue_in::QueuedInput for Log {}
 impl cache_in::CachedInput for Log {}
 
@@ -78,12 +84,16 @@ impl InputScope for LogScope {
     fn new_metric(&self, name: MetricName, kind: InputKind) -> InputMetric {
         let name = self.prefix_append(name);
 
-            let template = self.output.format.template(&name, kind);
+        let template = self.output.format.template(&name, kind);
+        let sampler = self.get_sampling().map(|sampling| Sampler::new(&sampling));
 
         let entries = self.entries.clone();
 
         if let Some(_buffering) = self.get_buffering() {
             InputMetric::new(move |value, labels| {
+                if !sampled(&sampler, &labels) {
+                    return
+                }
                 let mut buffer = Vec::with_capacity(32);
                 match template.print(&mut buffer, value, |key| labels.lookup(key)) {
                     Ok(()) => {
@@ -96,6 +106,9 @@ impl InputScope for LogScope {
         } else {
             // unbuffered
             InputMetric::new(move |value, labels| {
+                if !sampled(&sampler, &labels) {
+                    return
+                }
                 let mut buffer = Vec::with_capacity(32);
                 match template.print(&mut buffer, value, |key| labels.lookup(key)) {
                     Ok(()) => log!(log::Level::Debug, "{:?}", &buffer),
@@ -106,6 +119,14 @@ impl InputScope for LogScope {
     }
 }
 
+/// Returns true if the value should be logged.
+fn sampled(sampler: &Option<Sampler>, labels: &Labels) -> bool {
+    match *sampler {
+        Some(ref sampler) => sampler.sample(labels).is_some(),
+        None => true,
+    }
+}
+
 impl Flush for LogScope {
 
     fn flush(&self) -> error::Result<()> {
@@ -137,7 +158,7 @@ mod test {
     fn test_to_log() {
         let c = super::Log::log_to().input();
         let m = c.new_metric("test".into(), InputKind::Marker);
-        m.write(33, labels![]);
+        m.write(33.0, labels![]);
     }
 
 }
@@ -2,15 +2,29 @@ pub mod format;
 
 pub mod map;
 
+pub mod recording;
+
 pub mod stream;
 
 pub mod log;
 
 pub mod socket;
 
+#[cfg(feature="tokio")]
+pub mod async_socket;
+
+pub mod retry;
+
+pub mod http_client;
+
 pub mod graphite;
 
 pub mod statsd;
 
+pub mod influx;
+
 //#[cfg(feature="prometheus")]
 pub mod prometheus;
+
+#[cfg(feature="tiny_http")]
+pub mod http_serve;
@@ -6,52 +6,268 @@ use core::{Flush};
 use core::input::{InputKind};
 use core::attributes::{Attributes, WithAttributes, Buffered, Buffering, Prefixed};
 use core::name::MetricName;
+use core::metadata::{Unit, time_scale};
 use core::output::{Output, OutputMetric, OutputScope};
+use core::label::{AppLabel, Labels};
+use core::metrics;
 use core::error;
-use output::socket::RetrySocket;
+use output::http_client::{HttpClient, escape_path_segment};
+use output::socket::Transport;
 
 use std::net::ToSocketAddrs;
 use std::sync::{Arc, RwLock};
-use std::fmt::Debug;
-use std::io::Write;
+use std::collections::HashMap;
+use std::fmt::{self, Debug, Display};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use prometheus::{Opts, Registry, IntGauge, IntCounter, Encoder, ProtobufEncoder, TextEncoder,};
+use prometheus::{Opts, HistogramOpts, Registry, GaugeVec, CounterVec, HistogramVec,
+                 Encoder, ProtobufEncoder, TextEncoder,};
+use prometheus::proto::MetricFamily;
 
 metrics!{
 }
 
+/// Job name used by pushes that do not specify one.
+const DEFAULT_JOB: &str = "dipstick";
+
 #[derive(Clone, Debug)]
 enum PrometheusEncoding {
-    JSON,
-    PROTOBUF,
+    Text,
+    Protobuf,
 }
 
-/// Prometheus push shared client
-/// Holds a shared socket to a Prometheus host.
+/// Where and how to push metrics to a Prometheus Pushgateway.
 #[derive(Clone, Debug)]
+struct PushGateway {
+    client: HttpClient,
+    job: String,
+    grouping: Vec<String>,
+    replace: bool,
+}
+
+impl PushGateway {
+    /// Build the URL path identifying the group of pushed metrics.
+    /// Grouping keys without a current `AppLabel` value are left out.
+    fn path(&self) -> String {
+        let mut path = "/metrics".to_string();
+        push_grouping(&mut path, "job", &self.job);
+        for key in &self.grouping {
+            match AppLabel::get(key) {
+                Some(value) => push_grouping(&mut path, key, &value),
+                None => debug!("No AppLabel value for Prometheus grouping key '{}'", key),
+            }
+        }
+        path
+    }
+}
+
+/// Append a grouping label to a Pushgateway URL path.
+/// Values that can not be path segments, i.e. empty or containing a `/`, are sent base64-encoded.
+fn push_grouping(path: &mut String, key: &str, value: &str) {
+    path.push('/');
+    path.push_str(&escape_path_segment(key));
+    if value.is_empty() {
+        // the Pushgateway's encoding of an empty value
+        path.push_str("@base64/=");
+    } else if value.contains('/') {
+        path.push_str("@base64/");
+        path.push_str(&base64_url(value.as_bytes()));
+    } else {
+        path.push('/');
+        path.push_str(&escape_path_segment(value));
+    }
+}
+
+/// Encode bytes with the URL and filename safe base64 alphabet, padded.
+fn base64_url(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let triple = chunk.iter().enumerate().fold(0u32, |triple, (idx, byte)| triple | u32::from(*byte) << (16 - 8 * idx));
+        for idx in 0..4 {
+            if idx <= chunk.len() {
+                encoded.push(ALPHABET[(triple >> (18 - 6 * idx) & 0x3f) as usize] as char)
+            } else {
+                encoded.push('=')
+            }
+        }
+    }
+    encoded
+}
+
+/// A collector registered under a metric's name.
+/// Kept so that redefining a metric reuses the already registered collector.
+#[derive(Clone)]
+enum PrometheusMetric {
+    Counter(CounterVec),
+    Gauge(GaugeVec),
+    Histogram(HistogramVec),
+}
+
+/// A registered collector and the label keys its dimensions are valued from.
+type Collector = (PrometheusMetric, Arc<Vec<String>>);
+
+/// Replace any character not allowed in Prometheus names with an underscore.
+/// Metric names match `[a-zA-Z_:][a-zA-Z0-9_:]*`, label names `[a-zA-Z_][a-zA-Z0-9_]*`.
+fn sanitize(name: &str, allow_colon: bool) -> String {
+    let mut sanitized: String = name.chars().map(|c| match c {
+        'a'..='z' | 'A'..='Z' | '0'..='9' | '_' => c,
+        ':' if allow_colon => c,
+        _ => '_',
+    }).collect();
+    match sanitized.chars().next() {
+        Some('0'..='9') | None => sanitized.insert(0, '_'),
+        _ => {}
+    }
+    sanitized
+}
+
+/// Prometheus push shared client
+/// Pushes metrics over HTTP to a Prometheus Pushgateway host.
+/// All scopes opened from the output record their metrics to the same registry.
+#[derive(Clone)]
 pub struct Prometheus {
     attributes: Attributes,
-    socket: Arc<RwLock<RetrySocket>>,
+    registry: Registry,
+    metrics: Arc<RwLock<HashMap<String, Collector>>>,
+    timestamps: Arc<RwLock<HashMap<String, i64>>>,
+    dimensions: Arc<Vec<String>>,
+    push: Option<PushGateway>,
     encoding: PrometheusEncoding,
 }
 
+impl Debug for Prometheus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Prometheus {:?} {:?}", self.encoding, self.push)
+    }
+}
+
 impl Prometheus {
-    /// Send metrics to a prometheus server at the address and port provided.
-    pub fn send_json_to<A: ToSocketAddrs + Debug + Clone>(address: A) -> error::Result<Prometheus> {
-        Ok(Prometheus {
+    fn new(push: Option<PushGateway>, encoding: PrometheusEncoding, registry: Registry) -> Prometheus {
+        Prometheus {
             attributes: Attributes::default(),
-            socket: Arc::new(RwLock::new(RetrySocket::new(address.clone())?)),
-            encoding: PrometheusEncoding::JSON,
-        })
+            registry,
+            metrics: Arc::new(RwLock::new(HashMap::new())),
+            timestamps: Arc::new(RwLock::new(HashMap::new())),
+            dimensions: Arc::new(vec![]),
+            push,
+            encoding,
+        }
+    }
+
+    fn push_gateway(client: HttpClient, job: &str, encoding: PrometheusEncoding) -> error::Result<Prometheus> {
+        let push = PushGateway {
+            client,
+            job: job.to_string(),
+            grouping: vec![],
+            replace: false,
+        };
+        Ok(Prometheus::new(Some(push), encoding, Registry::new()))
+    }
+
+    /// Push metrics in the text format to a Pushgateway at the address and port provided.
+    /// Metrics are pushed to the `/metrics/job/<job>` group upon every flush.
+    pub fn push_to<A: ToSocketAddrs + Display>(address: A, job: &str) -> error::Result<Prometheus> {
+        Prometheus::push_gateway(HttpClient::new(address)?, job, PrometheusEncoding::Text)
+    }
+
+    /// Push metrics in the text format to a Pushgateway reached through a stream transport,
+    /// e.g. a Unix domain socket.
+    pub fn push_via(transport: Transport, job: &str) -> error::Result<Prometheus> {
+        Prometheus::push_gateway(HttpClient::via(transport)?, job, PrometheusEncoding::Text)
+    }
+
+    /// Push metrics in the protobuf format to a Pushgateway at the address and port provided.
+    /// Metrics are pushed to the `/metrics/job/<job>` group upon every flush.
+    pub fn push_protobuf_to<A: ToSocketAddrs + Display>(address: A, job: &str) -> error::Result<Prometheus> {
+        Prometheus::push_gateway(HttpClient::new(address)?, job, PrometheusEncoding::Protobuf)
     }
 
-    /// Send metrics to a prometheus server at the address and port provided.
+    /// Push metrics in the text format to a Pushgateway, under the `dipstick` job.
+    #[deprecated(note = "Use Prometheus::push_to() to name the job")]
+    pub fn send_json_to<A: ToSocketAddrs + Debug + Clone>(address: A) -> error::Result<Prometheus> {
+        Prometheus::push_via(Transport::tcp(address)?, DEFAULT_JOB)
+    }
+
+    /// Push metrics in the protobuf format to a Pushgateway, under the `dipstick` job.
+    #[deprecated(note = "Use Prometheus::push_protobuf_to() to name the job")]
     pub fn send_protobuf_to<A: ToSocketAddrs + Debug + Clone>(address: A) -> error::Result<Prometheus> {
-        Ok(Prometheus {
-            attributes: Attributes::default(),
-            socket: Arc::new(RwLock::new(RetrySocket::new(address.clone())?)),
-            encoding: PrometheusEncoding::PROTOBUF,
-        })
+        Prometheus::push_gateway(HttpClient::via(Transport::tcp(address)?)?, DEFAULT_JOB, PrometheusEncoding::Protobuf)
+    }
+
+    /// Record metrics to the provided registry without sending them anywhere.
+    /// The registry can then be exposed to scrapers, see `PrometheusServer::serve_registry`.
+    pub fn to_registry(registry: Registry) -> Prometheus {
+        Prometheus::new(None, PrometheusEncoding::Text, registry)
+    }
+
+    /// Return a clone of the output adding a grouping key to the URL metrics are pushed to.
+    /// The key's value is the `AppLabel` of the same name, as it is when metrics are pushed.
+    /// Has no effect on registry-only outputs.
+    pub fn grouping_label<S: Into<String>>(&self, key: S) -> Self {
+        let mut cloned = self.clone();
+        if let Some(ref mut push) = cloned.push {
+            push.grouping.push(key.into())
+        }
+        cloned
+    }
+
+    /// Return a clone of the output exporting the values of these label keys as metric dimensions.
+    /// Values are looked up in each written value's labels, then in `ThreadLabel` and `AppLabel`.
+    /// Keys without any value are exported with an empty value.
+    /// Metrics registered before the change keep their previous dimensions.
+    pub fn label_dimensions<S: AsRef<str>>(&self, keys: &[S]) -> Self {
+        let mut cloned = self.clone();
+        cloned.dimensions = Arc::new(keys.iter().map(|key| key.as_ref().to_string()).collect());
+        cloned
+    }
+
+    /// Return a clone of the output that replaces all previously pushed metrics of its group (HTTP PUT)
+    /// instead of only those with the same names (HTTP POST).
+    /// Has no effect on registry-only outputs.
+    pub fn replace_group(&self) -> Self {
+        let mut cloned = self.clone();
+        if let Some(ref mut push) = cloned.push {
+            push.replace = true
+        }
+        cloned
+    }
+}
+
+impl Prometheus {
+    /// Gather the recorded metric families, e.g. to be scraped.
+    /// Families last written with an explicit timestamp are exported with it,
+    /// others are left for the scraper to stamp.
+    /// Pushes never carry timestamps, which the Pushgateway rejects.
+    pub fn gather(&self) -> Vec<MetricFamily> {
+        let mut families = self.registry.gather();
+        let timestamps = self.timestamps.read().expect("Prometheus Timestamps");
+        if !timestamps.is_empty() {
+            for family in &mut families {
+                if let Some(timestamp_ms) = timestamps.get(family.get_name()) {
+                    for metric in family.mut_metric().iter_mut() {
+                        metric.set_timestamp_ms(*timestamp_ms)
+                    }
+                }
+            }
+        }
+        families
+    }
+}
+
+/// Remember the timestamp of the family's latest value, forgetting it if the value was written now.
+/// Only locks the timestamps for writing when the family's timestamp changes, e.g. once per flush of a bucket.
+fn stamp(timestamps: &RwLock<HashMap<String, i64>>, family: &str, timestamp: Option<SystemTime>) {
+    match timestamp.and_then(|timestamp| timestamp.duration_since(UNIX_EPOCH).ok()) {
+        Some(since_epoch) => {
+            let timestamp_ms = since_epoch.as_secs() as i64 * 1000 + i64::from(since_epoch.subsec_millis());
+            if timestamps.read().expect("Prometheus Timestamps").get(family) != Some(&timestamp_ms) {
+                timestamps.write().expect("Prometheus Timestamps").insert(family.to_string(), timestamp_ms);
+            }
+        }
+        None => if timestamps.read().expect("Prometheus Timestamps").contains_key(family) {
+            timestamps.write().expect("Prometheus Timestamps").remove(family);
+        }
     }
 }
 
@@ -61,9 +277,7 @@ impl Output for Prometheus {
     fn output(&self) -> Self::SCOPE {
         PrometheusScope {
             attributes: self.attributes.clone(),
-            registry: Registry::new(),
-            socket: self.socket.clone(),
-            encoding: self.encoding.clone(),
+            output: self.clone(),
         }
     }
 }
@@ -77,48 +291,115 @@ impl WithAttributes for Prometheus {
 #[derive(Clone)]
 pub struct PrometheusScope {
     attributes: Attributes,
-    registry: Registry,
-    socket: Arc<RwLock<RetrySocket>>,
-    encoding: PrometheusEncoding,
+    output: Prometheus,
+}
+
+impl PrometheusScope {
+    /// Lookup the collector registered under that name or register a new one.
+    /// Also returns the label keys the collector's dimensions are valued from.
+    /// Returns None if the collector could not be registered, e.g. if the registry already has one of that name.
+    fn collector(&self, name: String, help: String, kind: InputKind) -> Option<Collector> {
+        let mut metrics = self.output.metrics.write().expect("Prometheus Metrics");
+        if let Some(metric) = metrics.get(&name) {
+            return Some(metric.clone())
+        }
+        let (keys, label_names) = dimensions(&self.output.dimensions);
+        let label_names: Vec<&str> = label_names.iter().map(|label| label.as_str()).collect();
+        let registry = &self.output.registry;
+        let registered = match kind {
+            InputKind::Marker | InputKind::Counter => CounterVec::new(Opts::new(name.clone(), help), &label_names)
+                .and_then(|counter| registry.register(Box::new(counter.clone())).map(|_| PrometheusMetric::Counter(counter))),
+            InputKind::Gauge => GaugeVec::new(Opts::new(name.clone(), help), &label_names)
+                .and_then(|gauge| registry.register(Box::new(gauge.clone())).map(|_| PrometheusMetric::Gauge(gauge))),
+            InputKind::Timer => HistogramVec::new(HistogramOpts::new(name.clone(), help), &label_names)
+                .and_then(|timer| registry.register(Box::new(timer.clone())).map(|_| PrometheusMetric::Histogram(timer))),
+        };
+        match registered {
+            Ok(metric) => {
+                let keys = Arc::new(keys);
+                metrics.insert(name, (metric.clone(), keys.clone()));
+                Some((metric, keys))
+            }
+            Err(e) => {
+                warn!("Could not register Prometheus metric '{}', its values are dropped. {}", name, e);
+                None
+            }
+        }
+    }
+}
+
+/// The label keys exported as dimensions, with their sanitized label names.
+/// Keys sanitized to the same name as a previous key are left out.
+fn dimensions(keys: &[String]) -> (Vec<String>, Vec<String>) {
+    let mut kept = Vec::with_capacity(keys.len());
+    let mut label_names: Vec<String> = Vec::with_capacity(keys.len());
+    for key in keys {
+        let label_name = sanitize(key, false);
+        if label_names.contains(&label_name) {
+            warn!("Prometheus dimension '{}' would be exported as '{}' like another, ignoring it", key, label_name);
+            continue
+        }
+        kept.push(key.clone());
+        label_names.push(label_name);
+    }
+    (kept, label_names)
+}
+
+/// Lookup the value of each dimension's label, in order.
+fn label_values(keys: &[String], labels: &Labels) -> Vec<Arc<String>> {
+    keys.iter().map(|key| labels.lookup(key).unwrap_or_else(|| Arc::new(String::new()))).collect()
 }
 
 impl OutputScope for PrometheusScope {
 
     /// Define a metric of the specified type.
     fn new_metric(&self, name: MetricName, kind: InputKind) -> OutputMetric {
-        let name = self.prefix_prepend(name).join(".");
-        match kind {
-            InputKind::Counter => {
-                let opts = Opts::new(name, "".to_string());
-                let counter = IntCounter::with_opts(opts).expect("Prometheus Counter");
-                self.registry.register(Box::new(counter.clone())).expect("Registered Prometheus Counter");
-                OutputMetric::new(move |value, _labels|
-                    counter.inc_by(value as i64)
-                )
+        // prometheus wants times in seconds
+        let scale = time_scale(&name, kind, Unit::Seconds);
+        let help = name.metadata().description.as_ref().map(|description| description.to_string());
+        let name = sanitize(&self.prefix_prepend(name).join("_"), true);
+        let help = help.unwrap_or_else(|| name.clone());
+        let (collector, keys) = match self.collector(name.clone(), help, kind) {
+            Some(collector) => collector,
+            None => return OutputMetric::new(|_value, _labels| {}),
+        };
+        let timestamps = self.output.timestamps.clone();
+        match (kind, collector) {
+            (InputKind::Marker, PrometheusMetric::Counter(marker)) => {
+                OutputMetric::new_timed(move |_value, labels, timestamp| {
+                    stamp(&timestamps, &name, timestamp);
+                    let values = label_values(&keys, &labels);
+                    let values: Vec<&str> = values.iter().map(|v| v.as_str()).collect();
+                    marker.with_label_values(&values).inc()
+                })
             },
-            InputKind::Marker => {
-                let opts = Opts::new(name, "".to_string());
-                let marker = IntCounter::with_opts(opts).expect("Prometheus Counter");
-                self.registry.register(Box::new(marker.clone())).expect("Registered Prometheus Marker");
-                OutputMetric::new(move |_value, _labels|
-                    marker.inc()
-                )
+            (_, PrometheusMetric::Counter(counter)) => {
+                OutputMetric::new_timed(move |value, labels, timestamp| {
+                    if value.is_nan() || value < 0.0 {
+                        debug!("Prometheus counters can not be decremented or NaN, ignoring {}", value);
+                        return
+                    }
+                    stamp(&timestamps, &name, timestamp);
+                    let values = label_values(&keys, &labels);
+                    let values: Vec<&str> = values.iter().map(|v| v.as_str()).collect();
+                    counter.with_label_values(&values).inc_by(value / scale)
+                })
             },
-            InputKind::Timer => {
-                let opts = Opts::new(name, "".to_string());
-                let timer = IntCounter::with_opts(opts).expect("Prometheus Histogram");
-                self.registry.register(Box::new(timer.clone())).expect("Registered Prometheus Timer");
-                OutputMetric::new(move |value, _labels|
-                    timer.inc_by(value as i64)
-                )
+            (_, PrometheusMetric::Gauge(gauge)) => {
+                OutputMetric::new_timed(move |value, labels, timestamp| {
+                    stamp(&timestamps, &name, timestamp);
+                    let values = label_values(&keys, &labels);
+                    let values: Vec<&str> = values.iter().map(|v| v.as_str()).collect();
+                    gauge.with_label_values(&values).set(value / scale)
+                })
             },
-            InputKind::Gauge => {
-                let opts = Opts::new(name, "".to_string());
-                let gauge = IntGauge::with_opts(opts).expect("Prometheus Gauge");
-                self.registry.register(Box::new(gauge.clone())).expect("Registered Prometheus Gauge");;
-                OutputMetric::new(move |value, _labels|
-                    gauge.add(value as i64)
-                )
+            (_, PrometheusMetric::Histogram(timer)) => {
+                OutputMetric::new_timed(move |value, labels, timestamp| {
+                    stamp(&timestamps, &name, timestamp);
+                    let values = label_values(&keys, &labels);
+                    let values: Vec<&str> = values.iter().map(|v| v.as_str()).collect();
+                    timer.with_label_values(&values).observe(value / scale)
+                })
             },
         }
     }
@@ -127,22 +408,42 @@ impl OutputScope for PrometheusScope {
 impl Flush for PrometheusScope {
 
     fn flush(&self) -> error::Result<()> {
-        let metric_families = self.registry.gather();
+        let push = match self.output.push {
+            Some(ref push) => push,
+            // registry only, nothing to send
+            None => return Ok(())
+        };
+
+        // the Pushgateway rejects timestamped samples
+        let metric_families = self.output.registry.gather();
         let mut buffer = vec![];
 
-        match self.encoding {
-            PrometheusEncoding::JSON => {
+        let content_type = match self.output.encoding {
+            PrometheusEncoding::Text => {
                 let encoder = TextEncoder::new();
-                encoder.encode(&metric_families, &mut buffer)?
+                encoder.encode(&metric_families, &mut buffer)?;
+                encoder.format_type().to_string()
             },
-            PrometheusEncoding::PROTOBUF => {
+            PrometheusEncoding::Protobuf => {
                 let encoder = ProtobufEncoder::new();
-                encoder.encode(&metric_families, &mut buffer)?
+                encoder.encode(&metric_families, &mut buffer)?;
+                encoder.format_type().to_string()
             },
-        }
+        };
 
-        let mut socket = self.socket.write().expect("Lock Prometheus Socket");
-        Ok(socket.write_all(&mut buffer)?)
+        let method = if push.replace { "PUT" } else { "POST" };
+        match push.client.send(method, &push.path(), &content_type, &buffer) {
+            Ok(()) => {
+                metrics::PROMETHEUS_SENT_BYTES.count(buffer.len());
+                trace!("Pushed {} bytes to prometheus", buffer.len());
+                Ok(())
+            }
+            Err(e) => {
+                metrics::PROMETHEUS_SEND_ERR.mark();
+                debug!("Failed to push metrics to prometheus: {}", e);
+                Err(e)
+            }
+        }
     }
 }
 
@@ -150,3 +451,140 @@ impl WithAttributes for PrometheusScope {
     fn get_attributes(&self) -> &Attributes { &self.attributes }
     fn mut_attributes(&mut self) -> &mut Attributes { &mut self.attributes }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use output::http_client::test::stand_in;
+
+    #[test]
+    fn encode_grouping_labels() {
+        assert_eq!("YQ==", base64_url(b"a"));
+        assert_eq!("YWI=", base64_url(b"ab"));
+        assert_eq!("-_8=", base64_url(&[0xfb, 0xff]));
+
+        let mut path = String::new();
+        push_grouping(&mut path, "job", "batch");
+        push_grouping(&mut path, "path", "/var/tmp");
+        push_grouping(&mut path, "empty", "");
+        assert_eq!("/job/batch/path@base64/L3Zhci90bXA=/empty@base64/=", path);
+    }
+
+    #[test]
+    fn push_grouped_by_app_label() {
+        let (address, requests) = stand_in(202);
+        AppLabel::set("prometheus_test_instance", "host/1");
+        let scope = Prometheus::push_to(address, "test_job").unwrap()
+            .grouping_label("prometheus_test_instance")
+            .replace_group()
+            .output();
+        let pushed = scope.new_metric("pushed_count".into(), InputKind::Counter);
+        // pushed without its timestamp
+        pushed.write_timed(4.0, labels![], Some(SystemTime::now()));
+        pushed.write(f64::NAN, labels![]);
+        scope.flush().unwrap();
+        AppLabel::unset("prometheus_test_instance");
+
+        let request = requests.recv().unwrap();
+        assert!(request.starts_with("PUT /metrics/job/test_job/prometheus_test_instance@base64/aG9zdC8x HTTP/1.1\r\n"), "{}", request);
+        assert!(request.contains("Content-Type: text/plain; version=0.0.4\r\n"), "{}", request);
+        assert!(request.contains("\npushed_count 4\n"), "{}", request);
+    }
+
+    #[test]
+    fn metric_types_and_dimensions() {
+        let (address, requests) = stand_in(200);
+        let scope = Prometheus::push_to(address, "test_job").unwrap()
+            .label_dimensions(&["route", "http.status"])
+            .add_prefix("my.app")
+            .output();
+
+        let gauge = scope.new_metric("queue-depth".into(), InputKind::Gauge);
+        gauge.write(7.0, labels![]);
+        gauge.write(3.0, labels![]);
+        scope.new_metric("latency".into(), InputKind::Timer)
+            .write(250_000.0, labels!["route" => "/home", "http.status" => "200"]);
+        scope.new_metric("9lives".into(), InputKind::Marker).write(1.0, labels![]);
+        scope.flush().unwrap();
+
+        let request = requests.recv().unwrap();
+        assert!(request.contains("\nmy_app_queue_depth{http_status=\"\",route=\"\"} 3\n"), "{}", request);
+        assert!(request.contains("# TYPE my_app_latency histogram\n"), "{}", request);
+        assert!(request.contains("\nmy_app_latency_sum{http_status=\"200\",route=\"/home\"} 0.25\n"), "{}", request);
+        assert!(request.contains("\nmy_app_latency_count{http_status=\"200\",route=\"/home\"} 1\n"), "{}", request);
+        assert!(request.contains("\nmy_app_9lives{http_status=\"\",route=\"\"} 1\n"), "{}", request);
+    }
+
+    #[test]
+    fn conflicting_registrations() {
+        let registry = Registry::new();
+        let first = Prometheus::to_registry(registry.clone()).output();
+        first.new_metric("shared".into(), InputKind::Counter).write(1.0, labels![]);
+        let second = Prometheus::to_registry(registry.clone()).output();
+        second.new_metric("shared".into(), InputKind::Counter).write(2.0, labels![]);
+
+        let dimensioned = Prometheus::to_registry(registry.clone())
+            .label_dimensions(&["http.status", "http_status"])
+            .output();
+        dimensioned.new_metric("requests".into(), InputKind::Counter).write(1.0, labels!{ "http.status" => "200" });
+
+        let mut buffer = vec![];
+        TextEncoder::new().encode(&registry.gather(), &mut buffer).unwrap();
+        let text = String::from_utf8(buffer).unwrap();
+        assert!(text.contains("\nshared 1\n"), "{}", text);
+        assert!(text.contains("\nrequests{http_status=\"200\"} 1\n"), "{}", text);
+    }
+
+    #[test]
+    fn explicit_timestamps() {
+        let prometheus = Prometheus::to_registry(Registry::new());
+        let scope = prometheus.output();
+        let timestamp = UNIX_EPOCH + ::std::time::Duration::from_millis(1_500_000_000_250);
+        let counter = scope.new_metric("stamped".into(), InputKind::Counter);
+        counter.write_timed(2.0, labels![], Some(timestamp));
+        scope.new_metric("unstamped".into(), InputKind::Gauge).write(3.0, labels![]);
+
+        let mut buffer = vec![];
+        TextEncoder::new().encode(&prometheus.gather(), &mut buffer).unwrap();
+        let text = String::from_utf8(buffer).unwrap();
+        assert!(text.contains("\nstamped 2 1500000000250\n"), "{}", text);
+        assert!(text.contains("\nunstamped 3\n"), "{}", text);
+
+        // values written now are left for the scraper to stamp
+        counter.write(1.0, labels![]);
+        let mut buffer = vec![];
+        TextEncoder::new().encode(&prometheus.gather(), &mut buffer).unwrap();
+        assert!(String::from_utf8(buffer).unwrap().contains("\nstamped 3\n"));
+    }
+
+    #[test]
+    fn help_and_units() {
+        let prometheus = Prometheus::to_registry(Registry::new());
+        let scope = prometheus.output();
+        scope.new_metric(MetricName::from("uptime").unit(Unit::Milliseconds).description("Time since start"), InputKind::Gauge)
+            .write(1500.0, labels![]);
+        scope.new_metric("undescribed".into(), InputKind::Counter).write(1.0, labels![]);
+
+        let mut buffer = vec![];
+        TextEncoder::new().encode(&prometheus.gather(), &mut buffer).unwrap();
+        let text = String::from_utf8(buffer).unwrap();
+        assert!(text.contains("# HELP uptime Time since start\n"), "{}", text);
+        assert!(text.contains("\nuptime 1.5\n"), "{}", text);
+        assert!(text.contains("# HELP undescribed undescribed\n"), "{}", text);
+    }
+
+    #[test]
+    fn sanitized_names() {
+        assert_eq!("a_b:c", sanitize("a.b:c", true));
+        assert_eq!("a_b_c", sanitize("a.b:c", false));
+        assert_eq!("_9lives", sanitize("9lives", true));
+    }
+
+    #[test]
+    fn push_error_status() {
+        let (address, _requests) = stand_in(500);
+        let scope = Prometheus::push_protobuf_to(address, "test_job").unwrap().output();
+        scope.new_metric("pushed_count".into(), InputKind::Counter).write(4.0, labels![]);
+        assert!(scope.flush().is_err());
+    }
+}
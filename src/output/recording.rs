@@ -0,0 +1,275 @@
+//! Record every metric value written, to test an application's instrumentation without a backend.
+
+use core::{Flush, MetricValue};
+use core::input::{Input, InputKind, InputMetric, InputScope, ToPrimitive};
+use core::name::MetricName;
+use core::output::{Output, OutputMetric, OutputScope};
+use core::label::Labels;
+use core::attributes::{Attributes, WithAttributes, Prefixed};
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// A metric value as it was written, with its name, kind and labels.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    /// The full name of the metric, its parts joined with '.'.
+    pub name: String,
+    /// The kind of the metric.
+    pub kind: InputKind,
+    /// The value written.
+    pub value: MetricValue,
+    /// The labels visible when the value was written, including thread and app labels.
+    pub labels: BTreeMap<String, String>,
+    /// The time the value was observed at if it was specified, otherwise the time it was written at.
+    pub timestamp: SystemTime,
+    /// The rate at which the value was sampled, 1.0 if it was not.
+    pub rate: f64,
+}
+
+/// Query and assert the values captured by a recording, in the order they were written.
+/// Assertions panic with the recorded values of the metric if they fail.
+pub trait Recorded {
+    /// A copy of all values written so far.
+    fn records(&self) -> Vec<Record>;
+
+    /// Forget all values written so far.
+    fn clear(&self);
+
+    /// The values written to the metric of this name and kind.
+    fn values(&self, name: &str, kind: InputKind) -> Vec<MetricValue> {
+        self.records().into_iter()
+            .filter(|record| record.name == name && record.kind == kind)
+            .map(|record| record.value)
+            .collect()
+    }
+
+    /// The last value written to the metric of this name and kind, if any.
+    fn last_value(&self, name: &str, kind: InputKind) -> Option<MetricValue> {
+        self.values(name, kind).pop()
+    }
+
+    /// The values written to the metric of this name and kind with this label value.
+    fn values_labeled(&self, name: &str, kind: InputKind, key: &str, value: &str) -> Vec<MetricValue> {
+        self.records().into_iter()
+            .filter(|record| record.name == name && record.kind == kind)
+            .filter(|record| record.labels.get(key).map(String::as_str) == Some(value))
+            .map(|record| record.value)
+            .collect()
+    }
+
+    /// Assert that the values written to the counter add up to the expected sum.
+    fn assert_counter_sum<V: ToPrimitive>(&self, name: &str, expected: V) {
+        assert_sum(name, InputKind::Counter, self.values(name, InputKind::Counter), expected)
+    }
+
+    /// Assert that the marker was marked this many times.
+    fn assert_marker_count(&self, name: &str, expected: usize) {
+        assert_count(name, InputKind::Marker, self.values(name, InputKind::Marker), expected)
+    }
+
+    /// Assert that the timer recorded this many intervals.
+    fn assert_timer_count(&self, name: &str, expected: usize) {
+        assert_count(name, InputKind::Timer, self.values(name, InputKind::Timer), expected)
+    }
+
+    /// Assert that the last value written to the gauge is the expected value.
+    fn assert_gauge_value<V: ToPrimitive>(&self, name: &str, expected: V) {
+        let values = self.values(name, InputKind::Gauge);
+        let expected = expected.to_f64().unwrap_or(f64::NAN);
+        assert_eq!(values.last(), Some(&expected), "{} {:?} values: {:?}", name, InputKind::Gauge, values)
+    }
+
+    /// Assert that some value was written to a metric of this name, of any kind.
+    fn assert_written(&self, name: &str) {
+        let names: Vec<String> = self.records().into_iter().map(|record| record.name).collect();
+        assert!(names.iter().any(|written| written == name), "{} not written, written: {:?}", name, names)
+    }
+
+    /// Assert that no value was written to a metric of this name, of any kind.
+    fn assert_not_written(&self, name: &str) {
+        let values: Vec<Record> = self.records().into_iter().filter(|record| record.name == name).collect();
+        assert!(values.is_empty(), "{} written: {:?}", name, values)
+    }
+}
+
+fn assert_sum<V: ToPrimitive>(name: &str, kind: InputKind, values: Vec<MetricValue>, expected: V) {
+    let sum: MetricValue = values.iter().sum();
+    let expected = expected.to_f64().unwrap_or(f64::NAN);
+    assert!((sum - expected).abs() <= MetricValue::EPSILON * expected.abs().max(1.0),
+        "{} {:?} sum {} != {}, values: {:?}", name, kind, sum, expected, values)
+}
+
+fn assert_count(name: &str, kind: InputKind, values: Vec<MetricValue>, expected: usize) {
+    assert_eq!(values.len(), expected, "{} {:?} values: {:?}", name, kind, values)
+}
+
+fn record(records: &Mutex<Vec<Record>>, name: &str, kind: InputKind, value: MetricValue, labels: Labels,
+          timestamp: Option<SystemTime>, rate: f64)
+{
+    let labels = labels.into_map().into_iter()
+        .map(|(key, value)| (key, value.as_ref().clone()))
+        .collect();
+    let timestamp = timestamp.unwrap_or_else(SystemTime::now);
+    records.lock().expect("Recording").push(Record { name: name.to_string(), kind, value, labels, timestamp, rate })
+}
+
+/// An output keeping every value written to it, e.g. by a bucket's flush or a proxy.
+/// Clones share the same records, including prefixed clones.
+#[derive(Debug, Clone, Default)]
+pub struct RecordingOutput {
+    attributes: Attributes,
+    records: Arc<Mutex<Vec<Record>>>,
+}
+
+impl RecordingOutput {
+    /// A new, empty recording output.
+    pub fn new() -> Self {
+        RecordingOutput::default()
+    }
+}
+
+impl Output for RecordingOutput {
+    type SCOPE = RecordingOutput;
+
+    fn output(&self) -> Self::SCOPE {
+        self.clone()
+    }
+}
+
+impl OutputScope for RecordingOutput {
+    fn new_metric(&self, name: MetricName, kind: InputKind) -> OutputMetric {
+        let records = self.records.clone();
+        let name = self.prefix_prepend(name).join(".");
+        OutputMetric::new_forwarding(move |value, labels, timestamp, rate| {
+            record(&records, &name, kind, value, labels, timestamp, rate)
+        })
+    }
+}
+
+impl Flush for RecordingOutput {}
+
+impl WithAttributes for RecordingOutput {
+    fn get_attributes(&self) -> &Attributes { &self.attributes }
+    fn mut_attributes(&mut self) -> &mut Attributes { &mut self.attributes }
+}
+
+impl Recorded for RecordingOutput {
+    fn records(&self) -> Vec<Record> {
+        self.records.lock().expect("Recording").clone()
+    }
+
+    fn clear(&self) {
+        self.records.lock().expect("Recording").clear()
+    }
+}
+
+/// An input keeping every value written to it, e.g. as the target of an application's proxy.
+/// Clones share the same records, including prefixed clones.
+#[derive(Debug, Clone, Default)]
+pub struct RecordingInput {
+    attributes: Attributes,
+    records: Arc<Mutex<Vec<Record>>>,
+}
+
+impl RecordingInput {
+    /// A new, empty recording input.
+    pub fn new() -> Self {
+        RecordingInput::default()
+    }
+}
+
+impl Input for RecordingInput {
+    type SCOPE = RecordingInput;
+
+    fn input(&self) -> Self::SCOPE {
+        self.clone()
+    }
+}
+
+impl InputScope for RecordingInput {
+    fn new_metric(&self, name: MetricName, kind: InputKind) -> InputMetric {
+        let records = self.records.clone();
+        let name = self.prefix_prepend(name).join(".");
+        InputMetric::new_sampled(move |value, labels, rate| {
+            record(&records, &name, kind, value, labels, None, rate)
+        })
+    }
+}
+
+impl Flush for RecordingInput {}
+
+impl WithAttributes for RecordingInput {
+    fn get_attributes(&self) -> &Attributes { &self.attributes }
+    fn mut_attributes(&mut self) -> &mut Attributes { &mut self.attributes }
+}
+
+impl Recorded for RecordingInput {
+    fn records(&self) -> Vec<Record> {
+        self.records.lock().expect("Recording").clone()
+    }
+
+    fn clear(&self) {
+        self.records.lock().expect("Recording").clear()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::proxy::Proxy;
+    use bucket::atomic::AtomicBucket;
+    use bucket::stats_all;
+
+    #[test]
+    fn record_input() {
+        let recording = RecordingInput::new();
+        let proxy = Proxy::new();
+        proxy.set_target(recording.add_prefix("a"));
+
+        proxy.counter("b").count(10);
+        proxy.counter("b").count_with(20, labels!{ "route" => "/home" });
+        proxy.gauge("g").value(3);
+        proxy.gauge("g").value(4);
+        proxy.marker("m").mark();
+        proxy.timer("t").interval_us(1000);
+        recording.new_metric("s".into(), InputKind::Counter).write_sampled(5.0, labels![], 0.5);
+
+        recording.assert_counter_sum("a.b", 30);
+        recording.assert_gauge_value("a.g", 4);
+        recording.assert_marker_count("a.m", 1);
+        recording.assert_timer_count("a.t", 1);
+        recording.assert_not_written("a.x");
+        assert_eq!(vec![20.0], recording.values_labeled("a.b", InputKind::Counter, "route", "/home"));
+        assert_eq!(None, recording.last_value("a.b", InputKind::Gauge));
+        let rates: Vec<f64> = recording.records().iter().map(|record| record.rate).collect();
+        assert_eq!(vec![1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 0.5], rates);
+        assert!(recording.records().iter().all(|record| record.timestamp <= SystemTime::now()));
+
+        recording.clear();
+        assert!(recording.records().is_empty());
+    }
+
+    #[test]
+    fn record_output() {
+        let recording = RecordingOutput::new();
+        let bucket = AtomicBucket::new();
+        let counter = bucket.counter("b");
+        counter.count(10);
+        counter.count(20);
+        bucket.flush_now_to(&recording, &stats_all).unwrap();
+
+        recording.assert_written("b.sum");
+        assert_eq!(Some(30.0), recording.last_value("b.sum", InputKind::Counter));
+        assert!(recording.records().iter().all(|record| record.timestamp <= SystemTime::now()));
+    }
+
+    #[test]
+    #[should_panic(expected = "a.b Counter sum 10 != 30")]
+    fn failed_assertion() {
+        let recording = RecordingInput::new();
+        recording.counter("a.b").count(10);
+        recording.assert_counter_sum("a.b", 30)
+    }
+}
@@ -0,0 +1,241 @@
+//! Keep payloads that could not be sent to a metrics server, to replay them once it is reachable again.
+
+use core::metrics;
+
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::fmt;
+
+/// A bounded queue of unsent payloads, replayed in order once the connection comes back.
+/// When the byte cap is reached, the oldest payloads are dropped to make room for new ones.
+/// Payloads can also be written to a spool file, so they survive a restart of the application.
+pub struct RetryBuffer {
+    max_bytes: usize,
+    queued: VecDeque<Vec<u8>>,
+    queued_bytes: usize,
+    spool: Option<(PathBuf, File)>,
+}
+
+impl fmt::Debug for RetryBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "RetryBuffer {} payloads, {}/{} bytes", self.queued.len(), self.queued_bytes, self.max_bytes)?;
+        if let Some((ref path, _)) = self.spool {
+            write!(f, ", spooled to {:?}", path)?;
+        }
+        Ok(())
+    }
+}
+
+impl RetryBuffer {
+    /// Keep up to `max_bytes` of unsent payloads in memory.
+    pub fn new(max_bytes: usize) -> RetryBuffer {
+        RetryBuffer {
+            max_bytes,
+            queued: VecDeque::new(),
+            queued_bytes: 0,
+            spool: None,
+        }
+    }
+
+    /// Return a buffer that also writes unsent payloads to the spool file.
+    /// Payloads left in the file by a previous run are queued first, to be replayed before any new ones.
+    pub fn spool_to<P: AsRef<Path>>(self, path: P) -> io::Result<RetryBuffer> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path)?;
+        let mut contents = vec![];
+        file.read_to_end(&mut contents)?;
+
+        let mut buffer = self;
+        for payload in read_records(&contents) {
+            buffer.enqueue(payload.to_vec());
+        }
+        buffer.spool = Some((path, file));
+        buffer.rewrite_spool();
+        Ok(buffer)
+    }
+
+    /// Returns true if no payload is waiting to be sent.
+    pub fn is_empty(&self) -> bool {
+        self.queued.is_empty()
+    }
+
+    /// The total size of the payloads waiting to be sent.
+    pub fn queued_bytes(&self) -> usize {
+        self.queued_bytes
+    }
+
+    /// Keep a payload that could not be sent, dropping the oldest ones if over the byte cap.
+    pub fn push(&mut self, payload: Vec<u8>) {
+        metrics::RETRY_QUEUED_BYTES.count(payload.len());
+        let record = record(&payload);
+        if self.enqueue(payload) {
+            // some payloads were dropped, the whole queue must be spooled again
+            self.rewrite_spool();
+        } else if let Some((ref path, ref mut file)) = self.spool {
+            if let Err(e) = file.write_all(&record).and_then(|_| file.flush()) {
+                metrics::RETRY_SPOOL_ERR.mark();
+                warn!("Could not append to retry spool {:?}: {}", path, e);
+            }
+        }
+    }
+
+    /// Send the queued payloads in order, stopping at the first failure.
+    /// Payloads that were not sent are kept for the next replay.
+    pub fn replay<F>(&mut self, mut send: F) -> io::Result<()>
+    where
+        F: FnMut(&[u8]) -> io::Result<()>,
+    {
+        if self.queued.is_empty() {
+            return Ok(())
+        }
+        let mut result = Ok(());
+        let mut replayed = false;
+        while let Some(payload) = self.queued.pop_front() {
+            if let Err(e) = send(&payload) {
+                self.queued.push_front(payload);
+                result = Err(e);
+                break
+            }
+            metrics::RETRY_REPLAYED_BYTES.count(payload.len());
+            self.queued_bytes -= payload.len();
+            replayed = true;
+        }
+        if replayed {
+            self.rewrite_spool();
+        }
+        result
+    }
+
+    /// Queue the payload, dropping the oldest ones to stay under the byte cap.
+    /// Returns true if any payload was dropped.
+    fn enqueue(&mut self, payload: Vec<u8>) -> bool {
+        if payload.len() > self.max_bytes {
+            metrics::RETRY_DROPPED_BYTES.count(payload.len());
+            warn!("Dropped {} bytes payload larger than the retry buffer", payload.len());
+            return true
+        }
+        let mut dropped = false;
+        while self.queued_bytes + payload.len() > self.max_bytes {
+            if let Some(oldest) = self.queued.pop_front() {
+                metrics::RETRY_DROPPED_BYTES.count(oldest.len());
+                debug!("Retry buffer full, dropped {} bytes", oldest.len());
+                self.queued_bytes -= oldest.len();
+                dropped = true;
+            }
+        }
+        self.queued_bytes += payload.len();
+        self.queued.push_back(payload);
+        dropped
+    }
+
+    /// Replace the spool file's contents with the queued payloads.
+    fn rewrite_spool(&mut self) {
+        if let Some((ref path, ref mut file)) = self.spool {
+            let mut contents = Vec::with_capacity(self.queued_bytes + 4 * self.queued.len());
+            for payload in &self.queued {
+                contents.extend_from_slice(&record(payload));
+            }
+            let written = file.set_len(0)
+                .and_then(|_| file.seek(SeekFrom::Start(0)))
+                .and_then(|_| file.write_all(&contents))
+                .and_then(|_| file.flush());
+            if let Err(e) = written {
+                metrics::RETRY_SPOOL_ERR.mark();
+                warn!("Could not rewrite retry spool {:?}: {}", path, e);
+            }
+        }
+    }
+}
+
+/// A spooled payload, prefixed by its 4 bytes big-endian length.
+fn record(payload: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(payload.len() + 4);
+    record.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    record.extend_from_slice(payload);
+    record
+}
+
+/// Split the spool file's contents into payloads.
+/// A truncated last record, e.g. from a crash while spooling, is ignored.
+fn read_records(mut contents: &[u8]) -> Vec<&[u8]> {
+    let mut payloads = vec![];
+    while contents.len() >= 4 {
+        let len = u32::from_be_bytes([contents[0], contents[1], contents[2], contents[3]]) as usize;
+        if contents.len() < 4 + len {
+            warn!("Ignoring truncated retry spool record");
+            break
+        }
+        payloads.push(&contents[4..4 + len]);
+        contents = &contents[4 + len..];
+    }
+    payloads
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::env;
+    use std::fs;
+
+    fn replayed(buffer: &mut RetryBuffer) -> Vec<String> {
+        let mut sent = vec![];
+        buffer.replay(|payload| {
+            sent.push(String::from_utf8(payload.to_vec()).unwrap());
+            Ok(())
+        }).unwrap();
+        sent
+    }
+
+    #[test]
+    fn drop_oldest_over_cap() {
+        let mut buffer = RetryBuffer::new(10);
+        buffer.push(b"aaaa".to_vec());
+        buffer.push(b"bbbb".to_vec());
+        buffer.push(b"cccc".to_vec());
+        buffer.push(b"too large payload".to_vec());
+        assert_eq!(8, buffer.queued_bytes());
+        assert_eq!(vec!["bbbb", "cccc"], replayed(&mut buffer));
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn replay_in_order_until_failure() {
+        let mut buffer = RetryBuffer::new(100);
+        buffer.push(b"a".to_vec());
+        buffer.push(b"b".to_vec());
+        buffer.push(b"c".to_vec());
+
+        let mut sent = vec![];
+        let result = buffer.replay(|payload| {
+            if payload == b"b" {
+                return Err(io::Error::from(io::ErrorKind::NotConnected))
+            }
+            sent.push(payload.to_vec());
+            Ok(())
+        });
+        assert!(result.is_err());
+        assert_eq!(vec![b"a".to_vec()], sent);
+        assert_eq!(vec!["b", "c"], replayed(&mut buffer));
+    }
+
+    #[test]
+    fn spool_survives_restart() {
+        let path = env::temp_dir().join(format!("dipstick_retry_{}.spool", ::std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        {
+            let mut buffer = RetryBuffer::new(10).spool_to(&path).unwrap();
+            buffer.push(b"aaaa".to_vec());
+            buffer.push(b"bbbb".to_vec());
+            buffer.push(b"cccc".to_vec());
+        }
+
+        let mut buffer = RetryBuffer::new(10).spool_to(&path).unwrap();
+        assert_eq!(vec!["bbbb", "cccc"], replayed(&mut buffer));
+        assert_eq!(0, fs::metadata(&path).unwrap().len());
+        fs::remove_file(&path).unwrap();
+    }
+}
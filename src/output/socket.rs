@@ -1,19 +1,273 @@
-use std::net::TcpStream;
+//! Transports reaching metrics servers over TCP, UDP or Unix domain sockets.
+
+use std::net::{TcpStream, UdpSocket};
 use std::net::{SocketAddr, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::{UnixStream, UnixDatagram};
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
 use std::io;
 use std::time::{Duration, Instant};
 use std::fmt;
 use std::io::Write;
+use std::mem;
+use std::sync::Mutex;
+
+use output::retry::RetryBuffer;
+use core::metrics;
+#[cfg(feature="tokio")]
+use output::async_socket::AsyncSocket;
+
+#[cfg(feature="tokio")]
+use tokio::runtime::Handle;
+
+/// The initial delay before reconnecting a stream socket.
+pub const MIN_RECONNECT_DELAY_MS: u64 = 50;
+/// The longest delay before reconnecting a stream socket.
+pub const MAX_RECONNECT_DELAY_MS: u64 = 10_000;
+
+/// The address and kind of socket used to reach a metrics server.
+/// Stream transports carry a continuous flow of bytes, datagram transports send each payload separately.
+#[derive(Debug, Clone)]
+pub enum Transport {
+    /// A TCP stream.
+    Tcp(Vec<SocketAddr>),
+    /// UDP datagrams.
+    Udp(Vec<SocketAddr>),
+    /// A Unix domain stream socket.
+    #[cfg(unix)]
+    UnixStream(PathBuf),
+    /// A Unix domain datagram socket.
+    #[cfg(unix)]
+    UnixDatagram(PathBuf),
+    /// Any other transport, written to by a task on a tokio runtime through non-blocking sockets.
+    #[cfg(feature="tokio")]
+    Async {
+        /// The transport reaching the server.
+        transport: Box<Transport>,
+        /// The runtime running the writer task.
+        runtime: Handle,
+        /// How many payloads can be queued for the writer task.
+        queue_length: usize,
+    },
+}
+
+impl Transport {
+    /// Reach the server over TCP.
+    pub fn tcp<A: ToSocketAddrs>(address: A) -> io::Result<Transport> {
+        Ok(Transport::Tcp(address.to_socket_addrs()?.collect()))
+    }
+
+    /// Reach the server over UDP.
+    pub fn udp<A: ToSocketAddrs>(address: A) -> io::Result<Transport> {
+        Ok(Transport::Udp(address.to_socket_addrs()?.collect()))
+    }
+
+    /// Reach the server through a Unix domain stream socket.
+    #[cfg(unix)]
+    pub fn unix_stream<P: AsRef<Path>>(path: P) -> Transport {
+        Transport::UnixStream(path.as_ref().to_path_buf())
+    }
+
+    /// Reach the server through a Unix domain datagram socket.
+    #[cfg(unix)]
+    pub fn unix_datagram<P: AsRef<Path>>(path: P) -> Transport {
+        Transport::UnixDatagram(path.as_ref().to_path_buf())
+    }
+
+    /// Send payloads from a task spawned on the tokio runtime, through non-blocking async sockets.
+    /// Sending only queues payloads, failing without blocking if this many payloads are already queued.
+    #[cfg(feature="tokio")]
+    pub fn on_runtime(self, runtime: Handle, queue_length: usize) -> Transport {
+        Transport::Async { transport: Box::new(self), runtime, queue_length }
+    }
+
+    /// The transport reaching the server, unwrapped from any async transport.
+    pub fn underlying(&self) -> &Transport {
+        match *self {
+            #[cfg(feature="tokio")]
+            Transport::Async { ref transport, .. } => transport.underlying(),
+            _ => self,
+        }
+    }
+
+    /// Returns true if payloads are sent as separate datagrams.
+    pub fn is_datagram(&self) -> bool {
+        match *self.underlying() {
+            Transport::Udp(_) => true,
+            #[cfg(unix)]
+            Transport::UnixDatagram(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Open a socket to the server.
+    /// Stream sockets reconnect automatically, with an exponential backoff.
+    pub fn open(&self) -> io::Result<TransportSocket> {
+        match *self {
+            Transport::Udp(ref addresses) => {
+                let socket = UdpSocket::bind("0.0.0.0:0")?;
+                socket.set_nonblocking(true)?;
+                socket.connect(&addresses[..])?;
+                Ok(TransportSocket::Datagram(DatagramSocket::Udp(socket)))
+            }
+            #[cfg(unix)]
+            Transport::UnixDatagram(ref path) => {
+                let socket = UnixDatagram::unbound()?;
+                socket.set_nonblocking(true)?;
+                socket.connect(path)?;
+                Ok(TransportSocket::Datagram(DatagramSocket::Unix(socket)))
+            }
+            #[cfg(feature="tokio")]
+            Transport::Async { ref transport, ref runtime, queue_length } => {
+                let transport = transport.underlying().clone();
+                Ok(TransportSocket::Async(AsyncSocket::spawn(transport, runtime, queue_length)))
+            }
+            _ => Ok(TransportSocket::Stream(Mutex::new(RetrySocket::with_transport(self.clone())))),
+        }
+    }
+
+    /// Connect a new blocking stream to the server.
+    pub fn connect_stream(&self) -> io::Result<Stream> {
+        match *self {
+            Transport::Tcp(ref addresses) => Ok(Stream::Tcp(TcpStream::connect(&addresses[..])?)),
+            #[cfg(unix)]
+            Transport::UnixStream(ref path) => Ok(Stream::Unix(UnixStream::connect(path)?)),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{:?} is not a stream transport", self))),
+        }
+    }
+}
+
+/// A connected TCP or Unix domain stream.
+#[derive(Debug)]
+pub enum Stream {
+    /// A TCP stream.
+    Tcp(TcpStream),
+    /// A Unix domain stream.
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Stream {
+    /// Set the stream's non-blocking mode.
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref stream) => stream.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Stream::Unix(ref stream) => stream.set_nonblocking(nonblocking),
+        }
+    }
+
+    /// Set the stream's read and write timeouts.
+    pub fn set_timeouts(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref stream) => {
+                stream.set_read_timeout(timeout)?;
+                stream.set_write_timeout(timeout)
+            }
+            #[cfg(unix)]
+            Stream::Unix(ref stream) => {
+                stream.set_read_timeout(timeout)?;
+                stream.set_write_timeout(timeout)
+            }
+        }
+    }
+}
+
+impl io::Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Tcp(ref mut stream) => stream.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(ref mut stream) => stream.read(buf),
+        }
+    }
+}
 
-const MIN_RECONNECT_DELAY_MS: u64 = 50;
-const MAX_RECONNECT_DELAY_MS: u64 = 10_000;
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Tcp(ref mut stream) => stream.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(ref mut stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref mut stream) => stream.flush(),
+            #[cfg(unix)]
+            Stream::Unix(ref mut stream) => stream.flush(),
+        }
+    }
+}
+
+/// A connected UDP or Unix domain datagram socket.
+#[derive(Debug)]
+pub enum DatagramSocket {
+    /// A UDP socket.
+    Udp(UdpSocket),
+    /// A Unix domain datagram socket.
+    #[cfg(unix)]
+    Unix(UnixDatagram),
+}
+
+impl DatagramSocket {
+    /// Send the payload as a single datagram.
+    pub fn send(&self, payload: &[u8]) -> io::Result<usize> {
+        match *self {
+            DatagramSocket::Udp(ref socket) => socket.send(payload),
+            #[cfg(unix)]
+            DatagramSocket::Unix(ref socket) => socket.send(payload),
+        }
+    }
+}
+
+/// An open socket of any transport, which can be shared between threads.
+/// Only stream sockets are locked, to keep concurrent payloads whole. Datagrams are sent without locking.
+#[derive(Debug)]
+pub enum TransportSocket {
+    /// A reconnecting stream socket.
+    Stream(Mutex<RetrySocket>),
+    /// A datagram socket.
+    Datagram(DatagramSocket),
+    /// The queue of a task sending through an async socket.
+    #[cfg(feature="tokio")]
+    Async(AsyncSocket),
+}
+
+impl TransportSocket {
+    /// Send the whole payload, as a single datagram if using a datagram transport.
+    pub fn send(&self, payload: &[u8]) -> io::Result<()> {
+        match *self {
+            TransportSocket::Stream(ref socket) => socket.lock().expect("Lock Stream Socket").send(payload),
+            TransportSocket::Datagram(ref socket) => socket.send(payload).map(|_| ()),
+            #[cfg(feature="tokio")]
+            TransportSocket::Async(ref socket) => socket.send(payload),
+        }
+    }
+
+    /// Keep the payloads that could not be sent in the buffer, to replay them once reconnected.
+    /// Datagram transports do not buffer unsent payloads.
+    pub fn set_retry_buffer(&self, buffer: RetryBuffer) {
+        match *self {
+            TransportSocket::Stream(ref socket) => socket.lock().expect("Lock Stream Socket").retry = Some(buffer),
+            TransportSocket::Datagram(_) => warn!("Retry buffer ignored by datagram transport"),
+            #[cfg(feature="tokio")]
+            TransportSocket::Async(_) => warn!("Retry buffer ignored by async transport, which queues unsent payloads"),
+        }
+    }
+}
 
 /// A socket that retries
 pub struct RetrySocket {
     retries: usize,
     next_try: Instant,
-    addresses: Vec<SocketAddr>,
-    socket: Option<TcpStream>,
+    transport: Transport,
+    socket: Option<Stream>,
+    retry: Option<RetryBuffer>,
+    /// The end of a payload the socket could only partly accept, to be written before anything else.
+    partial: Vec<u8>,
 }
 
 impl fmt::Debug for RetrySocket {
@@ -24,34 +278,102 @@ impl fmt::Debug for RetrySocket {
 }
 
 impl RetrySocket {
-    /// Create a new socket that will retry
+    /// Create a new TCP socket that will retry
     pub fn new<A: ToSocketAddrs>(addresses: A) -> io::Result<Self> {
-        // FIXME instead of collecting addresses early, store ToSocketAddrs as trait object
-        // FIXME apparently this can not be one because of Associated Types clusterfuck (?!)
-        let addresses = addresses.to_socket_addrs()?.collect();
+        Ok(RetrySocket::with_transport(Transport::tcp(addresses)?))
+    }
+
+    /// Create a new socket over a stream transport that will retry
+    fn with_transport(transport: Transport) -> Self {
         let mut socket = RetrySocket {
             retries: 0,
             next_try: Instant::now() - Duration::from_millis(MIN_RECONNECT_DELAY_MS),
-            addresses,
+            transport,
             socket: None,
+            retry: None,
+            partial: vec![],
         };
 
         // try early connect
         let _ = socket.flush().ok();
-        Ok(socket)
+        socket
     }
 }
 
 impl RetrySocket {
+    /// Send the whole payload, after any payloads kept from previous failures.
+    /// With a retry buffer, payloads that can not be sent are kept for later instead of failing,
+    /// but the failure is still marked.
+    pub fn send(&mut self, payload: &[u8]) -> io::Result<()> {
+        let mut retry = match self.retry.take() {
+            Some(retry) => retry,
+            None => return self.write_payload(payload),
+        };
+        let sent = retry.replay(|queued| self.write_payload(queued))
+            .and_then(|_| self.write_payload(payload));
+        if let Err(e) = sent {
+            metrics::RETRY_SEND_ERR.mark();
+            debug!("Keeping {} bytes for retry: {}", payload.len(), e);
+            retry.push(payload.to_vec());
+        }
+        self.retry = Some(retry);
+        Ok(())
+    }
+
+    /// Write the whole payload, after the end of any partly written one.
+    /// If the socket would block after accepting part of the payload, the rest is kept
+    /// to be written first on the same connection, and the payload counts as sent.
+    fn write_payload(&mut self, payload: &[u8]) -> io::Result<()> {
+        self.write_partial()?;
+        match self.write_some(payload) {
+            (written, Err(ref e)) if written > 0 && e.kind() == io::ErrorKind::WouldBlock => {
+                self.partial = payload[written..].to_vec();
+                Ok(())
+            }
+            (_, result) => result,
+        }
+    }
+
+    /// Write the end of a partly written payload, if any.
+    /// It is dropped if the connection was lost, since its start went to the previous connection.
+    fn write_partial(&mut self) -> io::Result<()> {
+        if self.partial.is_empty() {
+            return Ok(())
+        }
+        let partial = mem::take(&mut self.partial);
+        let (written, result) = self.write_some(&partial);
+        if let Err(ref e) = result {
+            if e.kind() == io::ErrorKind::WouldBlock {
+                self.partial = partial[written..].to_vec();
+            } else {
+                warn!("Dropped the last {} bytes of a payload interrupted by disconnection", partial.len() - written);
+            }
+        }
+        result
+    }
+
+    /// Write as much of the bytes as the socket accepts, returning how many were written.
+    fn write_some(&mut self, bytes: &[u8]) -> (usize, io::Result<()>) {
+        let mut written = 0;
+        while written < bytes.len() {
+            match self.write(&bytes[written..]) {
+                Ok(0) => return (written, Err(io::ErrorKind::WriteZero.into())),
+                Ok(size) => written += size,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return (written, Err(e)),
+            }
+        }
+        (written, Ok(()))
+    }
+
     fn try_connect(&mut self) -> io::Result<()> {
         if self.socket.is_none() {
             let now = Instant::now();
             if now > self.next_try {
-                let addresses: &[SocketAddr] = self.addresses.as_ref();
-                let socket = TcpStream::connect(addresses)?;
+                let socket = self.transport.connect_stream()?;
                 socket.set_nonblocking(true)?;
                 self.retries = 0;
-                info!("Connected to {:?}", addresses);
+                info!("Connected to {:?}", self.transport);
                 self.socket = Some(socket);
             }
         }
@@ -60,11 +382,15 @@ impl RetrySocket {
 
     fn backoff(&mut self, e: io::Error) -> io::Error {
         self.socket = None;
+        if !self.partial.is_empty() {
+            warn!("Dropped the last {} bytes of a payload interrupted by disconnection", self.partial.len());
+            self.partial.clear();
+        }
         self.retries += 1;
         let delay = MAX_RECONNECT_DELAY_MS.min(MIN_RECONNECT_DELAY_MS << self.retries);
         warn!(
             "Could not connect to {:?} after {} trie(s). Backing off reconnection by {}ms. {}",
-            self.addresses, self.retries, delay, e
+            self.transport, self.retries, delay, e
         );
         self.next_try = Instant::now() + Duration::from_millis(delay);
         e
@@ -72,7 +398,7 @@ impl RetrySocket {
 
     fn with_socket<F, T>(&mut self, operation: F) -> io::Result<T>
     where
-        F: FnOnce(&mut TcpStream) -> io::Result<T>,
+        F: FnOnce(&mut Stream) -> io::Result<T>,
     {
         if let Err(e) = self.try_connect() {
             return Err(self.backoff(e));
@@ -87,6 +413,8 @@ impl RetrySocket {
 
         match opres {
             Ok(r) => Ok(r),
+            // the connection is still good, only full for now
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Err(e),
             Err(e) => Err(self.backoff(e)),
         }
     }
@@ -101,3 +429,98 @@ impl Write for RetrySocket {
         self.with_socket(|sock| sock.flush())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::net::TcpListener;
+    use std::io::Read;
+
+    #[test]
+    fn tcp_and_udp_transports() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let socket = Transport::tcp(listener.local_addr().unwrap()).unwrap().open().unwrap();
+        socket.send(b"over tcp").unwrap();
+        let mut received = String::new();
+        listener.accept().unwrap().0.take(8).read_to_string(&mut received).unwrap();
+        assert_eq!("over tcp", received);
+
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let transport = Transport::udp(receiver.local_addr().unwrap()).unwrap();
+        assert!(transport.is_datagram());
+        transport.open().unwrap().send(b"over udp").unwrap();
+        let mut datagram = [0; 16];
+        let size = receiver.recv(&mut datagram).unwrap();
+        assert_eq!(b"over udp", &datagram[..size]);
+    }
+
+    #[test]
+    fn replay_after_outage() {
+        // find a free port, then leave it closed
+        let address = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let socket = Transport::tcp(address).unwrap().open().unwrap();
+        socket.set_retry_buffer(RetryBuffer::new(1024));
+        socket.send(b"lost ").unwrap();
+
+        let listener = TcpListener::bind(address).unwrap();
+        ::std::thread::sleep(Duration::from_millis(MIN_RECONNECT_DELAY_MS << 3));
+        socket.send(b"found").unwrap();
+        let mut received = String::new();
+        listener.accept().unwrap().0.take(10).read_to_string(&mut received).unwrap();
+        assert_eq!("lost found", received);
+    }
+
+    #[test]
+    fn partial_writes_sent_once() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut socket = RetrySocket::new(listener.local_addr().unwrap()).unwrap();
+        socket.retry = Some(RetryBuffer::new(64 << 20));
+        let reader = ::std::thread::spawn(move || {
+            let mut received = vec![];
+            listener.accept().unwrap().0.read_to_end(&mut received).unwrap();
+            received
+        });
+
+        // more than the socket buffers hold, so some payloads are only partly written
+        let mut expected = vec![];
+        for i in 0..256 {
+            let payload = format!("{:03}{}\n", i, "x".repeat(65_536));
+            socket.send(payload.as_bytes()).unwrap();
+            expected.extend_from_slice(payload.as_bytes());
+        }
+        while !(socket.partial.is_empty() && socket.retry.as_ref().unwrap().is_empty()) {
+            ::std::thread::sleep(Duration::from_millis(1));
+            socket.send(b"").unwrap();
+        }
+        drop(socket);
+        assert!(reader.join().unwrap() == expected);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_transports() {
+        use std::os::unix::net::UnixListener;
+        use std::env;
+        use std::fs;
+
+        let stream_path = env::temp_dir().join(format!("dipstick_stream_{}.sock", ::std::process::id()));
+        let _ = fs::remove_file(&stream_path);
+        let listener = UnixListener::bind(&stream_path).unwrap();
+        let socket = Transport::unix_stream(&stream_path).open().unwrap();
+        socket.send(b"over unix stream").unwrap();
+        let mut received = String::new();
+        listener.accept().unwrap().0.take(16).read_to_string(&mut received).unwrap();
+        assert_eq!("over unix stream", received);
+        fs::remove_file(&stream_path).unwrap();
+
+        let datagram_path = env::temp_dir().join(format!("dipstick_datagram_{}.sock", ::std::process::id()));
+        let _ = fs::remove_file(&datagram_path);
+        let receiver = UnixDatagram::bind(&datagram_path).unwrap();
+        Transport::unix_datagram(&datagram_path).open().unwrap().send(b"over unix datagram").unwrap();
+        let mut datagram = [0; 32];
+        let size = receiver.recv(&mut datagram).unwrap();
+        assert_eq!(b"over unix datagram", &datagram[..size]);
+        fs::remove_file(&datagram_path).unwrap();
+    }
+}
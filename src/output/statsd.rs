@@ -1,46 +1,292 @@
 //! Send metrics to a statsd server.
 
-use core::attributes::{Buffered, Attributes, Sampled, Sampling, WithAttributes, Prefixed};
+use core::attributes::{Buffered, Buffering, Attributes, Sampled, WithAttributes, Prefixed};
 use core::name::MetricName;
-use core::pcg32;
+use core::metadata::{Unit, time_scale};
+use core::sampler::Sampler;
 use core::{Flush, MetricValue};
 use core::input::InputKind;
+use core::label::Labels;
 use core::metrics;
 use core::output::{Output, OutputScope, OutputMetric};
+use core::clock::TimeHandle;
 use core::error;
 use cache::cache_out;
 use queue::queue_out;
 
+use output::socket::{Transport, TransportSocket};
+use output::retry::RetryBuffer;
+
 use std::net::ToSocketAddrs;
 use std::sync::Arc;
-use std::net::UdpSocket;
+#[cfg(unix)]
+use std::path::Path;
+use std::time::Duration;
 use std::rc::Rc;
-use std::cell::{RefCell, RefMut};
+use std::cell::{Cell, RefCell, RefMut};
+use std::io;
 
 /// Use a safe maximum size for UDP to prevent fragmentation.
-// TODO make configurable?
+/// Can be raised with `max_payload()`, e.g. for loopback or jumbo frames networks.
 const MAX_UDP_PAYLOAD: usize = 576;
 
-/// Statsd output holds a datagram (UDP) socket to a statsd server.
+/// Default maximum payload size over transports that do not fragment datagrams,
+/// i.e. Unix domain and stream sockets.
+const MAX_LOCAL_PAYLOAD: usize = 8192;
+
+/// The socket used to reach the statsd server.
+/// Over stream transports, every payload is terminated by a newline.
+#[derive(Debug, Clone)]
+struct StatsdSocket {
+    socket: Arc<TransportSocket>,
+    stream: bool,
+}
+
+impl StatsdSocket {
+    fn send(&self, payload: &str) -> io::Result<usize> {
+        if self.stream {
+            self.socket.send(format!("{}\n", payload).as_bytes())?;
+            Ok(payload.len() + 1)
+        } else {
+            self.socket.send(payload.as_bytes())?;
+            Ok(payload.len())
+        }
+    }
+}
+
+/// The statsd protocol variant spoken to the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatsdDialect {
+    /// Plain statsd. Labels are not sent.
+    Statsd,
+    /// Datadog's DogStatsD. Labels are sent as `|#key:value` tags.
+    DogStatsd,
+}
+
+/// The statsd type a metric's values are sent as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatsdType {
+    /// Counter, `|c`.
+    Count,
+    /// Gauge, `|g`.
+    Gauge,
+    /// Timer, `|ms`.
+    Timing,
+    /// DogStatsD histogram, aggregated by the agent, `|h`.
+    Histogram,
+    /// DogStatsD distribution, aggregated server-side, `|d`.
+    Distribution,
+    /// DogStatsD set, counting unique values, `|s`.
+    Set,
+}
+
+impl StatsdType {
+    fn suffix(self) -> &'static str {
+        match self {
+            StatsdType::Count => "c",
+            StatsdType::Gauge => "g",
+            StatsdType::Timing => "ms",
+            StatsdType::Histogram => "h",
+            StatsdType::Distribution => "d",
+            StatsdType::Set => "s",
+        }
+    }
+}
+
+/// Status of a DogStatsD service check.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServiceCheckStatus {
+    /// The service is running normally.
+    Ok = 0,
+    /// The service is degraded.
+    Warning = 1,
+    /// The service is down.
+    Critical = 2,
+    /// The service status could not be determined.
+    Unknown = 3,
+}
+
+/// Statsd types of each kind of metric.
+#[derive(Debug, Clone, Copy)]
+struct StatsdTypes {
+    marker: StatsdType,
+    counter: StatsdType,
+    gauge: StatsdType,
+    timer: StatsdType,
+}
+
+impl Default for StatsdTypes {
+    fn default() -> Self {
+        StatsdTypes {
+            marker: StatsdType::Count,
+            counter: StatsdType::Count,
+            gauge: StatsdType::Gauge,
+            timer: StatsdType::Timing,
+        }
+    }
+}
+
+impl StatsdTypes {
+    fn of(&self, kind: InputKind) -> StatsdType {
+        match kind {
+            InputKind::Marker => self.marker,
+            InputKind::Counter => self.counter,
+            InputKind::Gauge => self.gauge,
+            InputKind::Timer => self.timer,
+        }
+    }
+}
+
+/// Statsd output holds a socket to a statsd server, usually a datagram (UDP) socket.
 /// The socket is shared between scopes opened from the output.
 #[derive(Clone, Debug)]
 pub struct Statsd {
     attributes: Attributes,
-    socket: Arc<UdpSocket>,
+    socket: StatsdSocket,
+    dialect: StatsdDialect,
+    types: StatsdTypes,
+    max_payload: usize,
+    flush_interval: Option<Duration>,
 }
 
 impl Statsd {
     /// Send metrics to a statsd server at the address and port provided.
     pub fn send_to<ADDR: ToSocketAddrs>(address: ADDR) -> error::Result<Statsd> {
-        let socket = Arc::new(UdpSocket::bind("0.0.0.0:0")?);
-        socket.set_nonblocking(true)?;
-        socket.connect(address)?;
+        Statsd::send_via(Transport::udp(address)?)
+    }
+
+    /// Send metrics to a statsd server listening on a Unix domain datagram socket,
+    /// e.g. `/var/run/statsd.sock`.
+    #[cfg(unix)]
+    pub fn send_unix_to<P: AsRef<Path>>(path: P) -> error::Result<Statsd> {
+        Statsd::send_via(Transport::unix_datagram(path))
+    }
+
+    /// Send metrics to a statsd server through any transport.
+    /// Over stream transports, each flush is terminated by a newline.
+    pub fn send_via(transport: Transport) -> error::Result<Statsd> {
+        let max_payload = match *transport.underlying() {
+            Transport::Udp(_) => MAX_UDP_PAYLOAD,
+            _ => MAX_LOCAL_PAYLOAD,
+        };
+        let socket = StatsdSocket {
+            socket: Arc::new(transport.open()?),
+            stream: !transport.is_datagram(),
+        };
 
         Ok(Statsd {
             attributes: Attributes::default(),
             socket,
+            dialect: StatsdDialect::Statsd,
+            types: StatsdTypes::default(),
+            max_payload,
+            flush_interval: None,
         })
     }
+
+    /// Return a clone of the output sending datagrams of at most this many bytes.
+    /// Defaults to 576 bytes for UDP, which is safe over any network,
+    /// and 8192 bytes for Unix domain and stream sockets.
+    /// Single metric values larger than this are dropped.
+    pub fn max_payload(&self, max_payload: usize) -> Self {
+        let mut cloned = self.clone();
+        cloned.max_payload = max_payload;
+        cloned
+    }
+
+    /// Return a clone of the output that also flushes buffered scopes
+    /// upon writing a value, if the previous flush is older than the interval.
+    /// Buffered scopes are otherwise flushed when the buffer size is reached, or explicitly.
+    /// The interval is only checked on writes: values buffered by a scope that goes idle
+    /// stay there until it is flushed, e.g. on a schedule with `flush_every()`.
+    pub fn flush_interval(&self, interval: Duration) -> Self {
+        let mut cloned = self.clone();
+        cloned.flush_interval = Some(interval);
+        cloned
+    }
+
+    /// Return a clone of the output keeping payloads that could not be sent in the retry buffer,
+    /// replaying them in order once the server can be reached again.
+    /// The buffer is installed on the output's connection, which all its clones share.
+    /// Datagram transports do not buffer unsent payloads.
+    pub fn retry_buffer(&self, buffer: RetryBuffer) -> Self {
+        self.socket.socket.set_retry_buffer(buffer);
+        self.clone()
+    }
+
+    /// Return a clone of the output speaking the specified statsd dialect.
+    pub fn dialect(&self, dialect: StatsdDialect) -> Self {
+        let mut cloned = self.clone();
+        cloned.dialect = dialect;
+        cloned
+    }
+
+    /// Return a clone of the output sending values of this kind of metric as the specified type,
+    /// e.g. timers as DogStatsD histograms or distributions.
+    /// Timer values are sent in milliseconds whatever their type.
+    pub fn metric_type(&self, kind: InputKind, statsd_type: StatsdType) -> Self {
+        let mut cloned = self.clone();
+        match kind {
+            InputKind::Marker => cloned.types.marker = statsd_type,
+            InputKind::Counter => cloned.types.counter = statsd_type,
+            InputKind::Gauge => cloned.types.gauge = statsd_type,
+            InputKind::Timer => cloned.types.timer = statsd_type,
+        }
+        cloned
+    }
+
+    /// Immediately send a DogStatsD service check, tagged with the labels.
+    /// The check's name is prefixed like metric names.
+    pub fn service_check(&self, name: &str, status: ServiceCheckStatus, message: Option<&str>, labels: Labels)
+        -> error::Result<()>
+    {
+        let mut datagram = format!("_sc|{}|{}", self.prefix_prepend(name).join("."), status as u8);
+        datagram.push_str(&dogstatsd_tags(labels));
+        if let Some(message) = message {
+            datagram.push_str("|m:");
+            datagram.push_str(&message.replace('\n', "\\n"));
+        }
+        self.send_datagram(&datagram)
+    }
+
+    /// Immediately send a DogStatsD event, tagged with the labels.
+    pub fn event(&self, title: &str, text: &str, labels: Labels) -> error::Result<()> {
+        let title = title.replace('\n', "\\n");
+        let text = text.replace('\n', "\\n");
+        let mut datagram = format!("_e{{{},{}}}:{}|{}", title.len(), text.len(), title, text);
+        datagram.push_str(&dogstatsd_tags(labels));
+        self.send_datagram(&datagram)
+    }
+
+    fn send_datagram(&self, datagram: &str) -> error::Result<()> {
+        match self.socket.send(datagram) {
+            Ok(size) => {
+                metrics::STATSD_SENT_BYTES.count(size);
+                Ok(())
+            }
+            Err(e) => {
+                metrics::STATSD_SEND_ERR.mark();
+                Err(e.into())
+            }
+        }
+    }
+}
+
+/// Format all labels as DogStatsD tags, e.g. `|#route:/home,status:200`.
+/// Characters used as separators by the protocol are replaced by underscores.
+fn dogstatsd_tags(labels: Labels) -> String {
+    fn clean(text: &str) -> String {
+        text.replace(&['|', ',', '#', '\n'][..], "_")
+    }
+
+    let mut tags = String::new();
+    for (key, value) in labels.into_map() {
+        tags.push_str(if tags.is_empty() { "|#" } else { "," });
+        tags.push_str(&clean(&key));
+        tags.push(':');
+        tags.push_str(&clean(&value));
+    }
+    tags
 }
 
 impl Buffered for Statsd {}
@@ -55,8 +301,13 @@ impl Output for Statsd {
     fn output(&self) -> Self::SCOPE {
         StatsdScope {
             attributes: self.attributes.clone(),
-            buffer: Rc::new(RefCell::new(String::with_capacity(MAX_UDP_PAYLOAD))),
+            buffer: Rc::new(RefCell::new(String::with_capacity(self.max_payload))),
             socket: self.socket.clone(),
+            dialect: self.dialect,
+            types: self.types,
+            max_payload: self.max_payload,
+            flush_interval: self.flush_interval,
+            last_flush: Rc::new(Cell::new(TimeHandle::now())),
         }
     }
 }
@@ -71,7 +322,12 @@ impl WithAttributes for Statsd {
 pub struct StatsdScope {
     attributes: Attributes,
     buffer: Rc<RefCell<String>>,
-    socket: Arc<UdpSocket>,
+    socket: StatsdSocket,
+    dialect: StatsdDialect,
+    types: StatsdTypes,
+    max_payload: usize,
+    flush_interval: Option<Duration>,
+    last_flush: Rc<Cell<TimeHandle>>,
 }
 
 impl Sampled for StatsdScope {}
@@ -79,40 +335,31 @@ impl Sampled for StatsdScope {}
 impl OutputScope for StatsdScope {
     /// Define a metric of the specified type.
     fn new_metric(&self, name: MetricName, kind: InputKind) -> OutputMetric {
+        // statsd wants times in ms
+        let scale = time_scale(&name, kind, Unit::Milliseconds);
         let mut prefix = self.prefix_prepend(name).join(".");
         prefix.push(':');
 
         let mut suffix = String::with_capacity(16);
         suffix.push('|');
-        suffix.push_str(match kind {
-            InputKind::Marker | InputKind::Counter => "c",
-            InputKind::Gauge => "g",
-            InputKind::Timer => "ms",
-        });
-
-        let scale = match kind {
-            // timers are in µs, statsd wants ms
-            InputKind::Timer => 1000,
-            _ => 1,
-        };
+        suffix.push_str(self.types.of(kind).suffix());
 
         let cloned = self.clone();
-
-        if let Some(Sampling::Random(float_rate)) = self.get_sampling() {
-            suffix.push_str(&format!{"|@{}\n", float_rate});
-            let int_sampling_rate = pcg32::to_int_rate(float_rate);
-            let metric = StatsdMetric { prefix, suffix, scale };
-
-            OutputMetric::new(move |value, _labels| {
-                if pcg32::accept_sample(int_sampling_rate) {
-                    cloned.print(&metric, value)
+        let tagged = self.dialect == StatsdDialect::DogStatsd;
+
+        let metric = StatsdMetric { prefix, suffix, scale };
+        if let Some(sampling) = self.get_sampling() {
+            let sampler = Sampler::new(&sampling);
+            OutputMetric::new_sampled(move |value, labels, rate| {
+                if let Some(sampled) = sampler.sample(&labels) {
+                    let tags = if tagged { dogstatsd_tags(labels) } else { String::new() };
+                    cloned.print(&metric, value, rate * sampled, &tags)
                 }
             })
         } else {
-            suffix.push_str("\n");
-            let metric = StatsdMetric { prefix, suffix, scale };
-            OutputMetric::new(move |value, _labels| {
-                cloned.print(&metric, value)
+            OutputMetric::new_sampled(move |value, labels, rate| {
+                let tags = if tagged { dogstatsd_tags(labels) } else { String::new() };
+                cloned.print(&metric, value, rate, &tags)
             })
         }
     }
@@ -127,34 +374,50 @@ impl Flush for StatsdScope {
 }
 
 impl StatsdScope {
-    fn print(&self, metric: &StatsdMetric, value: MetricValue)  {
+    fn print(&self, metric: &StatsdMetric, value: MetricValue, rate: f64, tags: &str)  {
+        if !value.is_finite() {
+            debug!("Skipping non-finite value {} of {}", value, metric.prefix);
+            return
+        }
         let scaled_value = value / metric.scale;
         let value_str = scaled_value.to_string();
-        let entry_len = metric.prefix.len() + value_str.len() + metric.suffix.len();
+        // the effective sampling rate of this value, may vary between values
+        let rate_str = if rate < 1.0 { format!("|@{}", rate) } else { String::new() };
+        let entry_len = metric.prefix.len() + value_str.len() + metric.suffix.len() + rate_str.len() + tags.len();
 
         let mut buffer = self.buffer.borrow_mut();
-        if entry_len > buffer.capacity() {
+        if entry_len > self.max_payload {
             // TODO report entry too big to fit in buffer (!?)
             return;
         }
 
-        let remaining = buffer.capacity() - buffer.len();
+        let remaining = self.max_payload - buffer.len();
         if entry_len + 1 > remaining {
             // buffer is nearly full, make room
             let _ = self.flush_inner(buffer);
             buffer = self.buffer.borrow_mut();
+        }
 
-        } else {
-            if !buffer.is_empty() {
-                // separate from previous entry
-                buffer.push('\n')
-            }
-            buffer.push_str(&metric.prefix);
-            buffer.push_str(&value_str);
-            buffer.push_str(&metric.suffix);
+        if !buffer.is_empty() {
+            // separate from previous entry
+            buffer.push('\n')
         }
+        buffer.push_str(&metric.prefix);
+        buffer.push_str(&value_str);
+        buffer.push_str(&metric.suffix);
+        buffer.push_str(&rate_str);
+        buffer.push_str(tags);
+
+        let flush = match self.get_buffering() {
+            None => true,
+            Some(Buffering::BufferSize(size)) => buffer.len() >= size,
+            Some(Buffering::Unlimited) => false,
+        } || match self.flush_interval {
+            Some(interval) => self.last_flush.get().elapsed_us() >= interval_us(interval),
+            None => false,
+        };
 
-        if self.get_buffering().is_none() {
+        if flush {
             if let Err(e) = self.flush_inner(buffer) {
                 debug!("Could not send to statsd {}", e)
             }
@@ -162,8 +425,9 @@ impl StatsdScope {
     }
 
     fn flush_inner(&self, mut buffer: RefMut<String>) -> error::Result<()> {
+        self.last_flush.set(TimeHandle::now());
         if !buffer.is_empty() {
-            match self.socket.send(buffer.as_bytes()) {
+            match self.socket.send(&buffer) {
                 Ok(size) => {
                     metrics::STATSD_SENT_BYTES.count(size);
                     trace!("Sent {} bytes to statsd", buffer.len());
@@ -179,6 +443,10 @@ impl StatsdScope {
     }
 }
 
+fn interval_us(interval: Duration) -> MetricValue {
+    (interval.as_secs() * 1_000_000) as MetricValue + interval.subsec_micros() as MetricValue
+}
+
 impl WithAttributes for StatsdScope {
     fn get_attributes(&self) -> &Attributes { &self.attributes }
     fn mut_attributes(&mut self) -> &mut Attributes { &mut self.attributes }
@@ -191,7 +459,7 @@ impl Buffered for StatsdScope {}
 pub struct StatsdMetric {
     prefix: String,
     suffix: String,
-    scale: isize,
+    scale: MetricValue,
 }
 
 /// Any remaining buffered data is flushed on Drop.
@@ -247,6 +515,211 @@ impl Drop for StatsdScope {
 //    }
 //}
 
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::attributes::Sampling;
+    use core::proxy::Proxy;
+    use core::input::{Input, InputScope};
+    use core::clock::{mock_clock_advance, mock_clock_reset};
+    use core::scheduler::ScheduleFlush;
+
+    use std::net::{UdpSocket, TcpListener};
+    #[cfg(unix)]
+    use std::os::unix::net::UnixDatagram;
+    use std::io::{BufRead, BufReader};
+    use std::env;
+    use std::fs;
+
+    fn receive(receiver: &UdpSocket) -> String {
+        receiver.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut datagram = [0; MAX_UDP_PAYLOAD];
+        let size = receiver.recv(&mut datagram).unwrap();
+        String::from_utf8_lossy(&datagram[..size]).into_owned()
+    }
+
+    #[test]
+    fn plain_statsd_drops_labels() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let scope = Statsd::send_to(receiver.local_addr().unwrap()).unwrap().output();
+        let counter = scope.new_metric("requests".into(), InputKind::Counter);
+        counter.write(f64::NAN, labels![]);
+        counter.write(3.0, labels!{ "route" => "/home" });
+        assert_eq!("requests:3|c", receive(&receiver));
+
+        let gauge = scope.new_metric("ratio".into(), InputKind::Gauge);
+        gauge.write(0.25, labels![]);
+        assert_eq!("ratio:0.25|g", receive(&receiver));
+    }
+
+    #[test]
+    fn dogstatsd_tags_and_types() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let scope = Statsd::send_to(receiver.local_addr().unwrap()).unwrap()
+            .dialect(StatsdDialect::DogStatsd)
+            .metric_type(InputKind::Timer, StatsdType::Distribution)
+            .metric_type(InputKind::Gauge, StatsdType::Set)
+            .buffered(Buffering::Unlimited)
+            .output();
+
+        let timer = scope.new_metric("latency".into(), InputKind::Timer);
+        let gauge = scope.new_metric("users".into(), InputKind::Gauge);
+        timer.write(2000.0, labels!{ "route" => "/home", "role" => "web|front" });
+        gauge.write(42.0, labels![]);
+        scope.flush().unwrap();
+
+        let datagram = receive(&receiver);
+        let lines: Vec<&str> = datagram.lines().collect();
+        assert_eq!(2, lines.len(), "{}", datagram);
+        assert!(lines[0].starts_with("latency:2|d|#"), "{}", lines[0]);
+        assert!(lines[0].contains("role:web_front"), "{}", lines[0]);
+        assert!(lines[0].contains("route:/home"), "{}", lines[0]);
+        assert!(lines[1].starts_with("users:42|s"), "{}", lines[1]);
+    }
+
+    #[test]
+    fn batch_up_to_max_payload() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let scope = Statsd::send_to(receiver.local_addr().unwrap()).unwrap()
+            .max_payload(16)
+            .buffered(Buffering::Unlimited)
+            .output();
+
+        let counter = scope.new_metric("a".into(), InputKind::Counter);
+        counter.write(1.0, labels![]);
+        counter.write(2.0, labels![]);
+        counter.write(3.0, labels![]);
+        scope.flush().unwrap();
+
+        assert_eq!("a:1|c\na:2|c", receive(&receiver));
+        assert_eq!("a:3|c", receive(&receiver));
+    }
+
+    #[test]
+    fn flush_on_size_or_interval() {
+        mock_clock_reset();
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let scope = Statsd::send_to(receiver.local_addr().unwrap()).unwrap()
+            .flush_interval(Duration::from_secs(1))
+            .buffered(Buffering::BufferSize(16))
+            .output();
+
+        let counter = scope.new_metric("a".into(), InputKind::Counter);
+        counter.write(1.0, labels![]);
+        counter.write(2.0, labels![]);
+        counter.write(3.0, labels![]);
+        assert_eq!("a:1|c\na:2|c\na:3|c", receive(&receiver));
+
+        counter.write(4.0, labels![]);
+        mock_clock_advance(Duration::from_secs(2));
+        counter.write(5.0, labels![]);
+        assert_eq!("a:4|c\na:5|c", receive(&receiver));
+    }
+
+    #[test]
+    fn flush_idle_scope_on_schedule() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let metrics = Statsd::send_to(receiver.local_addr().unwrap()).unwrap()
+            .flush_interval(Duration::from_secs(3600))
+            .buffered(Buffering::Unlimited)
+            .input();
+        let counter = metrics.counter("a");
+        counter.count(1);
+
+        // the interval is only checked on writes, the idle scope keeps its buffer
+        receiver.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        assert!(receiver.recv(&mut [0; MAX_UDP_PAYLOAD]).is_err());
+
+        let handle = metrics.flush_every(Duration::from_millis(10));
+        assert_eq!("a:1|c", receive(&receiver));
+        handle.join();
+    }
+
+    #[test]
+    fn report_effective_sampling_rate() {
+        mock_clock_reset();
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let statsd = Statsd::send_to(receiver.local_addr().unwrap()).unwrap()
+            .buffered(Buffering::Unlimited);
+
+        let every_other = statsd.sampled(Sampling::EveryNth(2)).output();
+        let counter = every_other.new_metric("a".into(), InputKind::Counter);
+        for i in 1..5 {
+            counter.write(f64::from(i), labels![]);
+        }
+        every_other.flush().unwrap();
+        assert_eq!("a:1|c|@0.5\na:3|c|@0.5", receive(&receiver));
+
+        let capped = statsd.sampled(Sampling::MaxRate(1)).output();
+        let counter = capped.new_metric("b".into(), InputKind::Counter);
+        counter.write(1.0, labels![]);
+        counter.write(2.0, labels![]);
+        mock_clock_advance(Duration::from_secs(1));
+        counter.write(3.0, labels![]);
+        capped.flush().unwrap();
+        assert_eq!("b:1|c\nb:3|c|@0.5", receive(&receiver));
+    }
+
+    #[test]
+    fn publish_proxy_sampling_rate() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let statsd = Statsd::send_to(receiver.local_addr().unwrap()).unwrap();
+        let proxy = Proxy::new().sampled(Sampling::EveryNth(4));
+        proxy.set_target(statsd.input());
+
+        let counter = proxy.counter("a");
+        for i in 1..5 {
+            counter.count(i);
+        }
+        assert_eq!("a:1|c|@0.25", receive(&receiver));
+    }
+
+    #[test]
+    fn terminate_stream_payloads() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let scope = Statsd::send_via(Transport::tcp(listener.local_addr().unwrap()).unwrap()).unwrap().output();
+        scope.new_metric("hits".into(), InputKind::Marker).write(1.0, labels![]);
+
+        let mut received = String::new();
+        let (stream, _) = listener.accept().unwrap();
+        BufReader::new(stream).read_line(&mut received).unwrap();
+        assert_eq!("hits:1|c\n", received);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn send_unix_datagrams() {
+        let path = env::temp_dir().join(format!("dipstick_statsd_{}.sock", ::std::process::id()));
+        let _ = fs::remove_file(&path);
+        let receiver = UnixDatagram::bind(&path).unwrap();
+        let scope = Statsd::send_unix_to(&path).unwrap().output();
+
+        scope.new_metric("hits".into(), InputKind::Marker).write(1.0, labels![]);
+        let mut datagram = [0; MAX_LOCAL_PAYLOAD];
+        let size = receiver.recv(&mut datagram).unwrap();
+        assert_eq!("hits:1|c", String::from_utf8_lossy(&datagram[..size]));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn service_checks_and_events() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let statsd = Statsd::send_to(receiver.local_addr().unwrap()).unwrap()
+            .dialect(StatsdDialect::DogStatsd)
+            .add_prefix("my_app");
+
+        statsd.service_check("db", ServiceCheckStatus::Critical, Some("timed out"), labels!{ "shard" => "2" }).unwrap();
+        let check = receive(&receiver);
+        assert!(check.starts_with("_sc|my_app.db|2|#"), "{}", check);
+        assert!(check.contains("shard:2"), "{}", check);
+        assert!(check.ends_with("|m:timed out"), "{}", check);
+
+        statsd.event("Deployed", "version 2\nall good", labels![]).unwrap();
+        let event = receive(&receiver);
+        assert!(event.starts_with("_e{8,19}:Deployed|version 2\\nall good"), "{}", event);
+    }
+}
+
 #[cfg(feature = "bench")]
 mod bench {
 
@@ -260,7 +733,7 @@ mod bench {
         let sd = Statsd::send_to("localhost:2003").unwrap().input();
         let timer = sd.new_metric("timer".into(), InputKind::Timer);
 
-        b.iter(|| test::black_box(timer.write(2000, labels![])));
+        b.iter(|| test::black_box(timer.write(2000.0, labels![])));
     }
 
     #[bench]
@@ -269,7 +742,7 @@ mod bench {
             .buffered(Buffering::BufferSize(65465)).input();
         let timer = sd.new_metric("timer".into(), InputKind::Timer);
 
-        b.iter(|| test::black_box(timer.write(2000, labels![])));
+        b.iter(|| test::black_box(timer.write(2000.0, labels![])));
     }
 
 }
@@ -134,9 +134,9 @@ impl<W: Write + Send + Sync + 'static> OutputScope for TextScope<W> {
         let entries = self.entries.clone();
 
         if let Some(_buffering) = self.get_buffering() {
-            OutputMetric::new(move |value, labels| {
+            OutputMetric::new_timed(move |value, labels, timestamp| {
                 let mut buffer = Vec::with_capacity(32);
-                match template.print(&mut buffer, value, |key| labels.lookup(key)) {
+                match template.print_timed(&mut buffer, value, timestamp, |key| labels.lookup(key)) {
                     Ok(()) => {
                         let mut entries = entries.borrow_mut();
                         entries.push(buffer)
@@ -147,9 +147,9 @@ impl<W: Write + Send + Sync + 'static> OutputScope for TextScope<W> {
         } else {
             // unbuffered
             let output = self.output.clone();
-            OutputMetric::new(move |value, labels| {
+            OutputMetric::new_timed(move |value, labels, timestamp| {
                 let mut buffer = Vec::with_capacity(32);
-                match template.print(&mut buffer, value, |key| labels.lookup(key)) {
+                match template.print_timed(&mut buffer, value, timestamp, |key| labels.lookup(key)) {
                     Ok(()) => {
                         let mut output = output.inner.write().expect("Metrics Text Output");
                         if let Err(e) = output.write_all(&buffer).and_then(|_| output.flush()) {
@@ -196,6 +196,6 @@ mod test {
     fn sink_print() {
         let c = super::Stream::write_to(io::stdout()).output();
         let m = c.new_metric("test".into(), InputKind::Marker);
-        m.write(33, labels![]);
+        m.write(33.0, labels![]);
     }
 }
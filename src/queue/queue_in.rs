@@ -1,6 +1,7 @@
-//! Queue metrics for write on a separate thread,
+//! Queue metrics for write on a separate thread, or on a tokio runtime,
 //! Metrics definitions are still synchronous.
 //! If queue size is exceeded, calling code reverts to blocking.
+//! Queues running on a tokio runtime never block, values are dropped instead.
 
 use core::attributes::{Attributes, WithAttributes, Prefixed};
 use core::name::MetricName;
@@ -15,6 +16,17 @@ use std::sync::Arc;
 use std::sync::mpsc;
 use std::thread;
 
+#[cfg(feature="tokio")]
+use tokio::runtime::Handle;
+#[cfg(feature="tokio")]
+use tokio::sync::mpsc as tokio_mpsc;
+#[cfg(feature="tokio")]
+use std::future::Future;
+#[cfg(feature="tokio")]
+use std::pin::Pin;
+#[cfg(feature="tokio")]
+use std::task::{Context, Poll};
+
 /// Wrap this output behind an asynchronous metrics dispatch queue.
 /// This is not strictly required for multi threading since the provided scopes
 /// are already Send + Sync but might be desired to lower the latency
@@ -25,16 +37,22 @@ pub trait QueuedInput: Input + Send + Sync + 'static + Sized {
     }
 }
 
-fn new_async_channel(length: usize) -> Arc<mpsc::SyncSender<InputQueueCmd>> {
+fn execute(cmd: InputQueueCmd) {
+    match cmd {
+        InputQueueCmd::Write(metric, value, labels, rate) => metric.write_sampled(value, labels, rate),
+        InputQueueCmd::Flush(scope) => if let Err(e) = scope.flush() {
+            debug!("Could not asynchronously flush metrics: {}", e);
+        },
+    }
+}
+
+fn new_async_channel(length: usize) -> QueueSender {
     let (sender, receiver) = mpsc::sync_channel::<InputQueueCmd>(length);
     thread::spawn(move || {
         let mut done = false;
         while !done {
             match receiver.recv() {
-                Ok(InputQueueCmd::Write(metric, value, labels)) => metric.write(value, labels),
-                Ok(InputQueueCmd::Flush(scope)) => if let Err(e) = scope.flush() {
-                    debug!("Could not asynchronously flush metrics: {}", e);
-                },
+                Ok(cmd) => execute(cmd),
                 Err(e) => {
                     debug!("Async metrics receive loop terminated: {}", e);
                     // cannot break from within match, use safety pin instead
@@ -43,7 +61,57 @@ fn new_async_channel(length: usize) -> Arc<mpsc::SyncSender<InputQueueCmd>> {
             }
         }
     });
-    Arc::new(sender)
+    QueueSender::Thread(Arc::new(sender))
+}
+
+/// Receives queued commands on a tokio runtime, until all senders are dropped.
+#[cfg(feature="tokio")]
+struct RuntimeReceiver(tokio_mpsc::Receiver<InputQueueCmd>);
+
+#[cfg(feature="tokio")]
+impl Future for RuntimeReceiver {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let receiver = &mut self.get_mut().0;
+        loop {
+            match receiver.poll_recv(cx) {
+                Poll::Ready(Some(cmd)) => execute(cmd),
+                Poll::Ready(None) => {
+                    debug!("Async metrics receive task terminated");
+                    return Poll::Ready(())
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+#[cfg(feature="tokio")]
+fn new_runtime_channel(length: usize, runtime: &Handle) -> QueueSender {
+    let (sender, receiver) = tokio_mpsc::channel::<InputQueueCmd>(length);
+    runtime.spawn(RuntimeReceiver(receiver));
+    QueueSender::Runtime(sender)
+}
+
+/// The sending end of a queue, received by a thread or by a tokio task.
+#[derive(Clone)]
+enum QueueSender {
+    Thread(Arc<mpsc::SyncSender<InputQueueCmd>>),
+    #[cfg(feature="tokio")]
+    Runtime(tokio_mpsc::Sender<InputQueueCmd>),
+}
+
+impl QueueSender {
+    /// Send the command, blocking while a thread's queue is full.
+    /// Commands sent while a tokio queue is full are dropped.
+    fn send(&self, cmd: InputQueueCmd) -> error::Result<()> {
+        match *self {
+            QueueSender::Thread(ref sender) => sender.send(cmd).map_err(|e| e.into()),
+            #[cfg(feature="tokio")]
+            QueueSender::Runtime(ref sender) => sender.try_send(cmd).map_err(|e| e.to_string().into()),
+        }
+    }
 }
 
 /// Wrap new scopes with an asynchronous metric write & flush dispatcher.
@@ -51,7 +119,7 @@ fn new_async_channel(length: usize) -> Arc<mpsc::SyncSender<InputQueueCmd>> {
 pub struct InputQueue {
     attributes: Attributes,
     target: Arc<InputDyn + Send + Sync + 'static>,
-    sender: Arc<mpsc::SyncSender<InputQueueCmd>>,
+    sender: QueueSender,
 }
 
 impl InputQueue {
@@ -63,6 +131,17 @@ impl InputQueue {
             sender: new_async_channel(queue_length),
         }
     }
+
+    /// Wrap new scopes with a metric write & flush dispatcher running as a task on the tokio runtime.
+    /// Writes and flushes are dropped without blocking if the queue is full.
+    #[cfg(feature="tokio")]
+    pub fn on_runtime<OUT: Input + Send + Sync + 'static>(target: OUT, queue_length: usize, runtime: &Handle) -> Self {
+        InputQueue {
+            attributes: Attributes::default(),
+            target: Arc::new(target),
+            sender: new_runtime_channel(queue_length, runtime),
+        }
+    }
 }
 
 impl CachedInput for InputQueue {}
@@ -89,8 +168,8 @@ impl Input for InputQueue {
 /// This is only `pub` because `error` module needs to know about it.
 /// Async commands should be of no concerns to applications.
 pub enum InputQueueCmd {
-    /// Send metric write
-    Write(InputMetric, MetricValue, Labels),
+    /// Send metric write, with the rate at which the value was sampled
+    Write(InputMetric, MetricValue, Labels, f64),
     /// Send metric flush
     Flush(Arc<InputScope + Send + Sync + 'static>),
 }
@@ -100,7 +179,7 @@ pub enum InputQueueCmd {
 #[derive(Clone)]
 pub struct InputQueueScope {
     attributes: Attributes,
-    sender: Arc<mpsc::SyncSender<InputQueueCmd>>,
+    sender: QueueSender,
     target: Arc<InputScope + Send + Sync + 'static>,
 }
 
@@ -125,9 +204,9 @@ impl InputScope for InputQueueScope {
         let name = self.prefix_append(name);
         let target_metric = self.target.new_metric(name, kind);
         let sender = self.sender.clone();
-        InputMetric::new(move |value, mut labels| {
+        InputMetric::new_sampled(move |value, mut labels, rate| {
             labels.save_context();
-            if let Err(e) = sender.send(InputQueueCmd::Write(target_metric.clone(), value, labels)) {
+            if let Err(e) = sender.send(InputQueueCmd::Write(target_metric.clone(), value, labels, rate)) {
                 metrics::SEND_FAILED.mark();
                 debug!("Failed to send async metrics: {}", e);
             }
@@ -141,10 +220,38 @@ impl Flush for InputQueueScope {
         if let Err(e) = self.sender.send(InputQueueCmd::Flush(self.target.clone())) {
             metrics::SEND_FAILED.mark();
             debug!("Failed to flush async metrics: {}", e);
-            Err(e.into())
+            Err(e)
         } else {
             Ok(())
         }
     }
 }
 
+
+#[cfg(all(test, feature="tokio"))]
+mod test {
+    use super::*;
+    use output::recording::{RecordingInput, Recorded};
+    use tokio::runtime::Builder;
+
+    use std::time::Duration;
+
+    #[test]
+    fn queue_on_runtime() {
+        let runtime = Builder::new_multi_thread().worker_threads(1).enable_all().build().unwrap();
+        let recording = RecordingInput::new();
+        let queue = InputQueue::on_runtime(recording.clone(), 10, runtime.handle()).input();
+
+        queue.counter("requests").count(3);
+        queue.counter("requests").count(4);
+        queue.flush().unwrap();
+
+        for _ in 0..100 {
+            if recording.records().len() == 2 {
+                break
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        recording.assert_counter_sum("requests", 7);
+    }
+}
@@ -34,7 +34,7 @@ fn new_async_channel(length: usize) -> Arc<mpsc::SyncSender<OutputQueueCmd>> {
         let mut done = false;
         while !done {
             match receiver.recv() {
-                Ok(OutputQueueCmd::Write(metric, value, labels)) => metric.write(value, labels),
+                Ok(OutputQueueCmd::Write(metric, value, labels, rate)) => metric.write_sampled(value, labels, rate),
                 Ok(OutputQueueCmd::Flush(scope)) => if let Err(e) = scope.flush() {
                     debug!("Could not asynchronously flush metrics: {}", e);
                 },
@@ -94,8 +94,8 @@ impl Input for OutputQueue {
 /// This is only `pub` because `error` module needs to know about it.
 /// Async commands should be of no concerns to applications.
 pub enum OutputQueueCmd {
-    /// Send metric write
-    Write(Arc<OutputMetric>, MetricValue, Labels),
+    /// Send metric write, with the rate at which the value was sampled
+    Write(Arc<OutputMetric>, MetricValue, Labels, f64),
     /// Send metric flush
     Flush(Arc<UnsafeScope>),
 }
@@ -119,9 +119,9 @@ impl InputScope for OutputQueueScope {
         let name = self.prefix_append(name);
         let target_metric = Arc::new(self.target.new_metric(name, kind));
         let sender = self.sender.clone();
-        InputMetric::new(move |value, mut labels| {
+        InputMetric::new_sampled(move |value, mut labels, rate| {
             labels.save_context();
-            if let Err(e) = sender.send(OutputQueueCmd::Write(target_metric.clone(), value, labels)) {
+            if let Err(e) = sender.send(OutputQueueCmd::Write(target_metric.clone(), value, labels, rate)) {
                 metrics::SEND_FAILED.mark();
                 debug!("Failed to send async metrics: {}", e);
             }